@@ -0,0 +1,40 @@
+use std::{env, fs, path::Path};
+
+/// 每次编译时把头文件生成到 OUT_DIR，设置了 SQLR_UPDATE_HEADER 时同时更新仓库里的 include/sqlr.h。
+/// 测试会检查两者是否一致，并且用这个头文件编译运行 tests/sqlr_test.c
+fn main() {
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=SQLR_UPDATE_HEADER");
+    // tests/c_api.rs 用 cc 编译 C 测试程序时需要知道目标平台
+    for var in ["TARGET", "HOST"] {
+        println!("cargo:rustc-env={}={}", var, env::var(var).unwrap());
+    }
+
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("sqlr.h");
+    // 生成失败不应该让整个编译失败，header_is_up_to_date 测试会报告出来
+    let bindings =
+        cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir)).and_then(|config| {
+            cbindgen::Builder::new()
+                .with_crate(&dir)
+                .with_config(config)
+                .generate()
+                .map_err(|e| e.to_string())
+        });
+    let bindings = match bindings {
+        Ok(bindings) => bindings,
+        Err(e) => {
+            println!("cargo:warning=Unable to generate C header: {}", e);
+            let _ = fs::remove_file(&out);
+            return;
+        }
+    };
+    bindings.write_to_file(&out);
+
+    if env::var_os("SQLR_UPDATE_HEADER").is_some() {
+        if let Err(e) = fs::copy(&out, format!("{}/include/sqlr.h", dir)) {
+            println!("cargo:warning=Unable to update include/sqlr.h: {}", e);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+#ifndef SQLR_H
+#define SQLR_H
+
+/* 由 cbindgen 生成，不要手工修改 */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+#ifndef ARROW_C_DATA_INTERFACE
+#define ARROW_C_DATA_INTERFACE
+
+#define ARROW_FLAG_DICTIONARY_ORDERED 1
+#define ARROW_FLAG_NULLABLE 2
+#define ARROW_FLAG_MAP_KEYS_SORTED 4
+
+struct ArrowSchema {
+  const char* format;
+  const char* name;
+  const char* metadata;
+  int64_t flags;
+  int64_t n_children;
+  struct ArrowSchema** children;
+  struct ArrowSchema* dictionary;
+  void (*release)(struct ArrowSchema*);
+  void* private_data;
+};
+
+struct ArrowArray {
+  int64_t length;
+  int64_t null_count;
+  int64_t offset;
+  int64_t n_buffers;
+  int64_t n_children;
+  const void** buffers;
+  struct ArrowArray** children;
+  struct ArrowArray* dictionary;
+  void (*release)(struct ArrowArray*);
+  void* private_data;
+};
+
+#endif  /* ARROW_C_DATA_INTERFACE */
+
+/**
+ * 查询结果
+ */
+typedef struct SqlrResult SqlrResult;
+
+/**
+ * 查询的上下文，对应 sqlr::Session，同一时间只能在一个线程里使用
+ */
+typedef struct SqlrSession SqlrSession;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * 当前线程上最近一次出错的信息，没有出错时返回 NULL。
+ * 返回的字符串归 sqlr 所有，在这个线程下一次调用 sqlr 之前有效
+ */
+const char *sqlr_last_error(void);
+
+/**
+ * 创建一个 session，失败时返回 NULL
+ */
+SqlrSession *sqlr_session_new(void);
+
+/**
+ * 释放 session，session 可以是 NULL
+ *
+ * # Safety
+ *
+ * session 必须是 sqlr_session_new 返回的，并且只能释放一次
+ */
+void sqlr_session_free(SqlrSession *session);
+
+/**
+ * 把数据源注册成表，成功时返回 0，失败时返回 -1
+ *
+ * # Safety
+ *
+ * session 必须是有效的 session，name 和 source 是以 \0 结尾的 UTF-8 字符串
+ */
+int sqlr_session_register(SqlrSession *session, const char *name, const char *source);
+
+/**
+ * 执行一条 SQL，失败时返回 NULL，错误信息里带着出错的位置
+ *
+ * # Safety
+ *
+ * session 必须是有效的 session，sql 是以 \0 结尾的 UTF-8 字符串
+ */
+SqlrResult *sqlr_query(SqlrSession *session, const char *sql);
+
+/**
+ * 释放查询结果，result 可以是 NULL
+ *
+ * # Safety
+ *
+ * result 必须是 sqlr_query 返回的，并且只能释放一次
+ */
+void sqlr_result_free(SqlrResult *result);
+
+/**
+ * 结果的行数
+ *
+ * # Safety
+ *
+ * result 必须是有效的查询结果
+ */
+uintptr_t sqlr_result_rows(const SqlrResult *result);
+
+/**
+ * 结果的列数
+ *
+ * # Safety
+ *
+ * result 必须是有效的查询结果
+ */
+uintptr_t sqlr_result_columns(const SqlrResult *result);
+
+/**
+ * 把结果转换成 csv，返回的字符串用 sqlr_string_free 释放，失败时返回 NULL
+ *
+ * # Safety
+ *
+ * result 必须是有效的查询结果
+ */
+char *sqlr_result_to_csv(const SqlrResult *result);
+
+/**
+ * 把结果转换成 JSON 数组，返回的字符串用 sqlr_string_free 释放，失败时返回 NULL
+ *
+ * # Safety
+ *
+ * result 必须是有效的查询结果
+ */
+char *sqlr_result_to_json(const SqlrResult *result);
+
+/**
+ * 释放 sqlr 返回的字符串，s 可以是 NULL
+ *
+ * # Safety
+ *
+ * s 必须是 sqlr_result_to_csv 或者 sqlr_result_to_json 返回的，并且只能释放一次
+ */
+void sqlr_string_free(char *s);
+
+/**
+ * 按 Arrow C Data Interface 导出结果：一个 struct 类型的数组，每一列是它的一个子数组。
+ * 成功时返回 0，调用者用完之后调用 array 和 schema 的 release 释放；失败时返回 -1
+ *
+ * # Safety
+ *
+ * result 必须是有效的查询结果，array 和 schema 指向调用者分配的结构体
+ */
+int sqlr_result_to_arrow(const SqlrResult *result,
+                         struct ArrowArray *array,
+                         struct ArrowSchema *schema);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* SQLR_H */
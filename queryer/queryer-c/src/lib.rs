@@ -0,0 +1,327 @@
+//! sqlr 的 C 接口，头文件是用 cbindgen 生成的 `include/sqlr.h`
+//!
+//! 出错的函数返回 NULL 或者负数，用 [`sqlr_last_error`] 取得错误信息，
+//! 每次调用（除了 sqlr_last_error 自己）开始时都会清除上一次的错误。
+//! 所有返回的指针都要用对应的 free 函数释放
+
+use polars::export::arrow::{
+    array::{Array, StructArray},
+    datatypes::{DataType as ArrowDataType, Field as ArrowField},
+    ffi::{self, Ffi_ArrowArray, Ffi_ArrowSchema},
+};
+use polars::prelude::*;
+use sqlr::{DataSet, Format, Session};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use tokio::runtime::{Builder, Runtime};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// 查询的上下文，对应 sqlr::Session，同一时间只能在一个线程里使用
+pub struct SqlrSession {
+    session: Session,
+    runtime: Runtime,
+}
+
+/// 查询结果
+pub struct SqlrResult(DataSet);
+
+fn set_error(msg: impl Into<Vec<u8>>) {
+    // 错误信息里不应该有 \0，万一有就截断
+    let mut msg = msg.into();
+    if let Some(i) = msg.iter().position(|b| *b == 0) {
+        msg.truncate(i);
+    }
+    let msg = CString::new(msg).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+fn clear_error() {
+    LAST_ERROR.with(|e| *e.borrow_mut() = None);
+}
+
+/// 执行 f，出错或者 panic 时记录错误信息并返回 default，panic 不能跨过 FFI 边界
+fn guard<T>(default: T, f: impl FnOnce() -> Result<T, String>) -> T {
+    clear_error();
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(v)) => v,
+        Ok(Err(msg)) => {
+            set_error(msg);
+            default
+        }
+        Err(_) => {
+            set_error("sqlr panicked");
+            default
+        }
+    }
+}
+
+/// 把 C 字符串转换成 &str，NULL 或者不是 UTF-8 时返回错误
+unsafe fn to_str<'a>(s: *const c_char, name: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{} is NULL", name));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| format!("{} is not valid UTF-8", name))
+}
+
+fn to_c_string(s: String) -> Result<*mut c_char, String> {
+    CString::new(s)
+        .map(CString::into_raw)
+        .map_err(|_| "Result contains a NUL byte".to_owned())
+}
+
+/// 当前线程上最近一次出错的信息，没有出错时返回 NULL。
+/// 返回的字符串归 sqlr 所有，在这个线程下一次调用 sqlr 之前有效
+#[no_mangle]
+pub extern "C" fn sqlr_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// 创建一个 session，失败时返回 NULL
+#[no_mangle]
+pub extern "C" fn sqlr_session_new() -> *mut SqlrSession {
+    guard(ptr::null_mut(), || {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let session = Session::new();
+        Ok(Box::into_raw(Box::new(SqlrSession { session, runtime })))
+    })
+}
+
+/// 释放 session，session 可以是 NULL
+///
+/// # Safety
+///
+/// session 必须是 sqlr_session_new 返回的，并且只能释放一次
+#[no_mangle]
+pub unsafe extern "C" fn sqlr_session_free(session: *mut SqlrSession) {
+    clear_error();
+    if !session.is_null() {
+        drop(Box::from_raw(session));
+    }
+}
+
+/// 把数据源注册成表，成功时返回 0，失败时返回 -1
+///
+/// # Safety
+///
+/// session 必须是有效的 session，name 和 source 是以 \0 结尾的 UTF-8 字符串
+#[no_mangle]
+pub unsafe extern "C" fn sqlr_session_register(
+    session: *mut SqlrSession,
+    name: *const c_char,
+    source: *const c_char,
+) -> c_int {
+    guard(-1, || {
+        let session = session.as_mut().ok_or("session is NULL")?;
+        let name = to_str(name, "name")?;
+        let source = to_str(source, "source")?;
+        session.session.register(name, source);
+        Ok(0)
+    })
+}
+
+/// 执行一条 SQL，失败时返回 NULL，错误信息里带着出错的位置
+///
+/// # Safety
+///
+/// session 必须是有效的 session，sql 是以 \0 结尾的 UTF-8 字符串
+#[no_mangle]
+pub unsafe extern "C" fn sqlr_query(
+    session: *mut SqlrSession,
+    sql: *const c_char,
+) -> *mut SqlrResult {
+    guard(ptr::null_mut(), || {
+        let session = session.as_mut().ok_or("session is NULL")?;
+        let sql = to_str(sql, "sql")?;
+        let SqlrSession { session, runtime } = session;
+        let ds = runtime
+            .block_on(session.query(sql))
+            .map_err(|e| e.render(sql))?;
+        Ok(Box::into_raw(Box::new(SqlrResult(ds))))
+    })
+}
+
+/// 释放查询结果，result 可以是 NULL
+///
+/// # Safety
+///
+/// result 必须是 sqlr_query 返回的，并且只能释放一次
+#[no_mangle]
+pub unsafe extern "C" fn sqlr_result_free(result: *mut SqlrResult) {
+    clear_error();
+    if !result.is_null() {
+        drop(Box::from_raw(result));
+    }
+}
+
+/// 结果的行数
+///
+/// # Safety
+///
+/// result 必须是有效的查询结果
+#[no_mangle]
+pub unsafe extern "C" fn sqlr_result_rows(result: *const SqlrResult) -> usize {
+    clear_error();
+    result.as_ref().map_or(0, |r| r.0.height())
+}
+
+/// 结果的列数
+///
+/// # Safety
+///
+/// result 必须是有效的查询结果
+#[no_mangle]
+pub unsafe extern "C" fn sqlr_result_columns(result: *const SqlrResult) -> usize {
+    clear_error();
+    result.as_ref().map_or(0, |r| r.0.width())
+}
+
+/// 把结果转换成 csv，返回的字符串用 sqlr_string_free 释放，失败时返回 NULL
+///
+/// # Safety
+///
+/// result 必须是有效的查询结果
+#[no_mangle]
+pub unsafe extern "C" fn sqlr_result_to_csv(result: *const SqlrResult) -> *mut c_char {
+    to_text(result, Format::Csv)
+}
+
+/// 把结果转换成 JSON 数组，返回的字符串用 sqlr_string_free 释放，失败时返回 NULL
+///
+/// # Safety
+///
+/// result 必须是有效的查询结果
+#[no_mangle]
+pub unsafe extern "C" fn sqlr_result_to_json(result: *const SqlrResult) -> *mut c_char {
+    to_text(result, Format::Json)
+}
+
+unsafe fn to_text(result: *const SqlrResult, format: Format) -> *mut c_char {
+    guard(ptr::null_mut(), || {
+        let result = result.as_ref().ok_or("result is NULL")?;
+        let data = result.0.to_bytes(format).map_err(|e| e.to_string())?;
+        let data = String::from_utf8(data).map_err(|e| e.to_string())?;
+        to_c_string(data)
+    })
+}
+
+/// 释放 sqlr 返回的字符串，s 可以是 NULL
+///
+/// # Safety
+///
+/// s 必须是 sqlr_result_to_csv 或者 sqlr_result_to_json 返回的，并且只能释放一次
+#[no_mangle]
+pub unsafe extern "C" fn sqlr_string_free(s: *mut c_char) {
+    clear_error();
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// 按 Arrow C Data Interface 导出结果：一个 struct 类型的数组，每一列是它的一个子数组。
+/// 成功时返回 0，调用者用完之后调用 array 和 schema 的 release 释放；失败时返回 -1
+///
+/// # Safety
+///
+/// result 必须是有效的查询结果，array 和 schema 指向调用者分配的结构体
+#[no_mangle]
+pub unsafe extern "C" fn sqlr_result_to_arrow(
+    result: *const SqlrResult,
+    array: *mut Ffi_ArrowArray,
+    schema: *mut Ffi_ArrowSchema,
+) -> c_int {
+    guard(-1, || {
+        let result = result.as_ref().ok_or("result is NULL")?;
+        if array.is_null() || schema.is_null() {
+            return Err("array or schema is NULL".to_owned());
+        }
+
+        // 每一列合并成一块，才能作为 struct 的一个子数组
+        let mut df: DataFrame = result.0.clone();
+        df.rechunk();
+        let mut fields = Vec::with_capacity(df.width());
+        let mut values: Vec<Arc<dyn Array>> = Vec::with_capacity(df.width());
+        for s in df.get_columns() {
+            let chunk = s
+                .chunks()
+                .first()
+                .ok_or_else(|| format!("Column {} has no data", s.name()))?;
+            fields.push(s.field().to_arrow());
+            values.push(chunk.clone());
+        }
+
+        let data_type = ArrowDataType::Struct(fields);
+        let batch = StructArray::from_data(data_type.clone(), values, None);
+        ffi::export_field_to_c(&ArrowField::new("", data_type, false), schema);
+        ffi::export_array_to_c(Arc::new(batch), array);
+        Ok(0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn c_api_works() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.csv");
+        std::fs::write(&path, "id,name\n1,a\n2,b\n").unwrap();
+
+        unsafe {
+            let session = sqlr_session_new();
+            let name = CString::new("t").unwrap();
+            let source = CString::new(format!("file://{}", path.display())).unwrap();
+            assert_eq!(
+                sqlr_session_register(session, name.as_ptr(), source.as_ptr()),
+                0
+            );
+
+            let sql = CString::new("SELECT name FROM t WHERE id > 1").unwrap();
+            let result = sqlr_query(session, sql.as_ptr());
+            assert_eq!(
+                (sqlr_result_rows(result), sqlr_result_columns(result)),
+                (1, 1)
+            );
+            let csv = sqlr_result_to_csv(result);
+            assert_eq!(CStr::from_ptr(csv).to_str().unwrap(), "name\nb\n");
+            sqlr_string_free(csv);
+            sqlr_result_free(result);
+
+            let sql = CString::new("SELECT * FROM nowhere").unwrap();
+            assert!(sqlr_query(session, sql.as_ptr()).is_null());
+            let error = CStr::from_ptr(sqlr_last_error()).to_str().unwrap();
+            assert!(error.starts_with("error: "));
+
+            // 成功的调用会清除上一次的错误
+            let sql = CString::new("SELECT id FROM t").unwrap();
+            let result = sqlr_query(session, sql.as_ptr());
+            assert!(!result.is_null());
+            assert!(sqlr_last_error().is_null());
+            sqlr_result_free(result);
+
+            sqlr_session_free(session);
+        }
+    }
+
+    #[test]
+    fn header_is_up_to_date() {
+        // build.rs 把头文件生成到 OUT_DIR，和仓库里的 include/sqlr.h 比较
+        let generated = std::fs::read_to_string(concat!(env!("OUT_DIR"), "/sqlr.h"))
+            .expect("build.rs failed to generate the header");
+        assert!(
+            generated == include_str!("../include/sqlr.h"),
+            "include/sqlr.h is out of date, run `SQLR_UPDATE_HEADER=1 cargo build -p queryer-c`"
+        );
+    }
+}
@@ -0,0 +1,43 @@
+//! 用 C 编译器编译 tests/sqlr_test.c，链接 libsqlr_c 之后运行，从 C 调用者的一侧检查头文件和 ABI
+#![cfg(unix)]
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn c_program_works() {
+    // 测试程序在 target/<profile>/deps 下，cargo 把动态库放在 deps 和上一层目录
+    let exe = std::env::current_exe().unwrap();
+    let deps = exe.parent().unwrap();
+    let lib_dirs = [deps, deps.parent().unwrap()];
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let dir = tempfile::tempdir().unwrap();
+    let program = dir.path().join("sqlr_test");
+
+    let compiler = cc::Build::new()
+        .cargo_metadata(false)
+        .out_dir(dir.path())
+        .target(env!("TARGET"))
+        .host(env!("HOST"))
+        .opt_level(0)
+        .debug(false)
+        .get_compiler();
+    let mut cmd = compiler.to_command();
+    cmd.arg(manifest.join("tests/sqlr_test.c"))
+        .arg(format!("-I{}", manifest.join("include").display()));
+    for dir in lib_dirs {
+        cmd.arg(format!("-L{}", dir.display()))
+            .arg(format!("-Wl,-rpath,{}", dir.display()));
+    }
+    cmd.arg("-lsqlr_c").arg("-o").arg(&program);
+    let status = cmd.status().expect("failed to run the C compiler");
+    assert!(status.success(), "failed to compile tests/sqlr_test.c");
+
+    let output = Command::new(&program).output().unwrap();
+    assert!(
+        output.status.success(),
+        "sqlr_test failed: {}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
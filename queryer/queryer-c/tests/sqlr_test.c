@@ -0,0 +1,67 @@
+/*
+ * C 接口的测试程序，cargo test 时由 tests/c_api.rs 编译运行，手工编译的方法见 README.md
+ */
+#include <assert.h>
+#include <stdlib.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "sqlr.h"
+
+static const char *DATA = "id,name\n1,apple\n2,banana\n3,cherry\n";
+
+int main(void) {
+    char path[] = "/tmp/sqlr_test_XXXXXX";
+    int fd = mkstemp(path);
+    assert(fd >= 0);
+    FILE *file = fdopen(fd, "w");
+    fputs(DATA, file);
+    fclose(file);
+
+    char source[256];
+    snprintf(source, sizeof(source), "file://%s", path);
+
+    SqlrSession *session = sqlr_session_new();
+    assert(session != NULL);
+    assert(sqlr_session_register(session, "fruits", source) == 0);
+
+    SqlrResult *result = sqlr_query(session, "SELECT id, name FROM fruits WHERE id > 1 ORDER BY id");
+    if (result == NULL) {
+        fprintf(stderr, "%s\n", sqlr_last_error());
+        return 1;
+    }
+    /* 成功的调用会清除上一次的错误 */
+    assert(sqlr_last_error() == NULL);
+    assert(sqlr_result_rows(result) == 2);
+    assert(sqlr_result_columns(result) == 2);
+
+    char *csv = sqlr_result_to_csv(result);
+    assert(strcmp(csv, "id,name\n2,banana\n3,cherry\n") == 0);
+    sqlr_string_free(csv);
+
+    char *json = sqlr_result_to_json(result);
+    assert(strstr(json, "\"banana\"") != NULL);
+    sqlr_string_free(json);
+
+    /* 结果是一个 struct 数组，每一列是一个子数组 */
+    struct ArrowArray array;
+    struct ArrowSchema schema;
+    assert(sqlr_result_to_arrow(result, &array, &schema) == 0);
+    assert(strcmp(schema.format, "+s") == 0);
+    assert(schema.n_children == 2);
+    assert(strcmp(schema.children[1]->name, "name") == 0);
+    assert(array.length == 2);
+    assert(array.n_children == 2);
+    array.release(&array);
+    schema.release(&schema);
+    sqlr_result_free(result);
+
+    /* 出错时返回 NULL，错误信息里有出错的位置 */
+    assert(sqlr_query(session, "SELECT * FROM fruits WHERE") == NULL);
+    assert(strstr(sqlr_last_error(), "error: ") == sqlr_last_error());
+
+    sqlr_session_free(session);
+    remove(path);
+    printf("all tests passed\n");
+    return 0;
+}
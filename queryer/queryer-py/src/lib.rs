@@ -1,16 +1,92 @@
-use pyo3::{exceptions, prelude::*};
+use pyo3::{exceptions, prelude::*, types::PyDict};
+use std::time::Duration;
 
 #[pyfunction]
 pub fn example_sql() -> PyResult<String> {
     Ok(sqlr::example_sql())
 }
 
+/// 把 sqlr 的错误转换成 Python 异常，消息里带上出错的 SQL 和 `^` 标记
+fn to_py_err(e: sqlr::Error, sql: &str) -> PyErr {
+    let msg = e.render(sql);
+    match e {
+        sqlr::Error::Parse { .. } | sqlr::Error::Unsupported { .. } => {
+            exceptions::PyValueError::new_err(msg)
+        }
+        sqlr::Error::Fetch(_) => exceptions::PyIOError::new_err(msg),
+        sqlr::Error::Timeout(_) => exceptions::PyTimeoutError::new_err(msg),
+        _ => exceptions::PyRuntimeError::new_err(msg),
+    }
+}
+
+/// 执行查询，timeout 以秒为单位。查询过程中按 Ctrl-C 会取消查询并抛出 KeyboardInterrupt
+#[pyfunction]
+pub fn query(
+    py: Python,
+    sql: &str,
+    output: Option<&str>,
+    timeout: Option<f64>,
+) -> PyResult<String> {
+    let (data, _) = run(py, sql, timeout)?;
+    to_output(&data, sql, output)
+}
+
+/// 和 query 一样，同时返回各个阶段的统计：用时以秒为单位，另外还有读取的字节数和行数
 #[pyfunction]
-pub fn query(sql: &str, output: Option<&str>) -> PyResult<String> {
+pub fn query_with_stats(
+    py: Python,
+    sql: &str,
+    output: Option<&str>,
+    timeout: Option<f64>,
+) -> PyResult<(String, PyObject)> {
+    let (data, stats) = run(py, sql, timeout)?;
+    let dict = PyDict::new(py);
+    dict.set_item("parse", stats.parse.as_secs_f64())?;
+    dict.set_item("convert", stats.convert.as_secs_f64())?;
+    dict.set_item("fetch", stats.fetch.as_secs_f64())?;
+    dict.set_item("load", stats.load.as_secs_f64())?;
+    dict.set_item("execute", stats.execute.as_secs_f64())?;
+    dict.set_item("total", stats.total.as_secs_f64())?;
+    dict.set_item("bytes_fetched", stats.bytes_fetched)?;
+    dict.set_item("rows_loaded", stats.rows_loaded)?;
+    dict.set_item("rows", stats.rows)?;
+    dict.set_item("cached", stats.cached)?;
+    Ok((to_output(&data, sql, output)?, dict.into()))
+}
+
+/// 执行查询，返回结果和这次查询的统计
+fn run(py: Python, sql: &str, timeout: Option<f64>) -> PyResult<(sqlr::DataSet, sqlr::QueryStats)> {
     let rt = tokio::runtime::Runtime::new().unwrap();
-    let data = rt.block_on(async { sqlr::query(sql).await.unwrap() });
+    let cancel = sqlr::CancelHandle::new();
+    let options = sqlr::QueryOptions {
+        timeout: timeout.map(Duration::from_secs_f64),
+        cancel: Some(cancel.clone()),
+        ..Default::default()
+    };
+
+    let mut session = sqlr::Session::new();
+    let data = rt.block_on(async {
+        let query = session.query_with(sql, options);
+        tokio::pin!(query);
+        loop {
+            tokio::select! {
+                result = &mut query => break result.map_err(|e| to_py_err(e, sql)),
+                // 查询时我们持有 GIL，需要自己定期检查 Python 的信号
+                _ = tokio::time::sleep(Duration::from_millis(100)) => {
+                    if let Err(e) = py.check_signals() {
+                        cancel.cancel();
+                        break Err(e);
+                    }
+                }
+            }
+        }
+    })?;
+    Ok((data, session.last_stats().clone()))
+}
+
+fn to_output(data: &sqlr::DataSet, sql: &str, output: Option<&str>) -> PyResult<String> {
     match output {
-        Some("csv") | None => Ok(data.to_csv().unwrap()),
+        Some("csv") | None => data.to_csv().map_err(|e| to_py_err(e, sql)),
         Some(v) => Err(exceptions::PyTypeError::new_err(format!(
             "Output type {} not supported",
             v
@@ -21,6 +97,7 @@ pub fn query(sql: &str, output: Option<&str>) -> PyResult<String> {
 #[pymodule]
 fn queryer_py(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(query, m)?)?;
+    m.add_function(wrap_pyfunction!(query_with_stats, m)?)?;
     m.add_function(wrap_pyfunction!(example_sql, m)?)?;
     Ok(())
 }
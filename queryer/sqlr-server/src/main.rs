@@ -0,0 +1,308 @@
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use clap::Parser;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlr::{Error, Format, Param, Params, QueryOptions, ResultCacheConfig, Session, SessionConfig};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+mod pgwire;
+
+/// 通过 HTTP 提供 sqlr 查询
+#[derive(Parser, Debug)]
+struct Opts {
+    /// 监听的地址
+    #[arg(long, default_value = "0.0.0.0:3000")]
+    addr: SocketAddr,
+    /// 注册的表，形如 name=url，可以指定多次
+    #[arg(long = "table", value_parser = parse_table)]
+    tables: Vec<(String, String)>,
+    /// 允许在 SQL 里直接查询的数据源前缀或者主机名，可以指定多次。不指定时只能查询注册的表
+    #[arg(long = "allow")]
+    allowed: Vec<String>,
+    /// 允许 COPY ... TO 写入的地址前缀，可以指定多次。不指定时不能写入任何文件
+    #[arg(long = "allow-write")]
+    allowed_targets: Vec<String>,
+    /// PostgreSQL 协议监听的地址，比如 127.0.0.1:5432，不指定时不启用
+    #[arg(long)]
+    pg_addr: Option<SocketAddr>,
+    /// 每个查询的超时时间，单位是秒
+    #[arg(long, default_value_t = 30)]
+    timeout: u64,
+    /// 单个数据源最多下载的字节数
+    #[arg(long)]
+    max_bytes: Option<u64>,
+    /// 数据源和结果最多的行数
+    #[arg(long)]
+    max_rows: Option<usize>,
+    /// 查询结果缓存的时间，单位是秒，不指定时不缓存结果
+    #[arg(long)]
+    cache_ttl: Option<u64>,
+    /// 结果缓存最多占用的内存，单位是 MB
+    #[arg(long, default_value_t = 256)]
+    cache_mb: usize,
+}
+
+/// JSON 格式的请求，params 是数组时按位置绑定，是对象时按名字绑定
+#[derive(Deserialize, Debug)]
+struct QueryRequest {
+    sql: String,
+    #[serde(default)]
+    params: Value,
+}
+
+fn parse_table(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, url)) if !name.is_empty() && !url.is_empty() => {
+            Ok((name.to_owned(), url.to_owned()))
+        }
+        _ => Err(format!("Failed to parse table {}, expect name=url", s)),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    let opts = Opts::parse();
+
+    let config = SessionConfig {
+        allowed_sources: Some(opts.allowed),
+        allowed_targets: opts.allowed_targets,
+        query_options: QueryOptions {
+            timeout: Some(Duration::from_secs(opts.timeout)),
+            max_bytes: opts.max_bytes,
+            max_rows: opts.max_rows,
+            cancel: None,
+        },
+        result_cache: opts.cache_ttl.map(|ttl| ResultCacheConfig {
+            ttl: Duration::from_secs(ttl),
+            max_bytes: opts.cache_mb * 1024 * 1024,
+        }),
+        ..Default::default()
+    };
+    let mut session = Session::with_config(config);
+    for (name, url) in opts.tables {
+        session.register(name, url);
+    }
+
+    if let Some(addr) = opts.pg_addr {
+        let session = session.clone();
+        tokio::spawn(async move {
+            if let Err(e) = pgwire::serve(addr, session).await {
+                tracing::error!("postgres protocol server failed: {}", e);
+            }
+        });
+    }
+
+    let app = Router::new()
+        .route("/query", post(query))
+        .route("/tables", get(tables))
+        .route("/cache", get(cache))
+        .layer(Extension(Arc::new(session)));
+
+    tracing::info!("listening on {}", opts.addr);
+    axum::Server::bind(&opts.addr)
+        .serve(app.into_make_service())
+        .await
+        .unwrap();
+}
+
+/// 执行查询，body 是 SQL 文本，或者 Content-Type 为 application/json 时带参数的请求
+async fn query(
+    Extension(session): Extension<Arc<Session>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let (sql, params) = match parse_request(&headers, &body) {
+        Ok(request) => request,
+        Err(msg) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+    };
+    let format = match accepted_format(&headers) {
+        Some(format) => format,
+        None => {
+            return (
+                StatusCode::NOT_ACCEPTABLE,
+                "Supported types: text/csv, application/json, application/x-ndjson, \
+                 application/vnd.apache.arrow.file, application/vnd.apache.parquet",
+            )
+                .into_response()
+        }
+    };
+
+    // 每个请求用一个独立的 session，CREATE VIEW 之类的语句不会影响别的请求
+    let mut session = (*session).clone();
+    let result = match session.prepare(&sql) {
+        Ok(prepared) => session.execute_prepared(&prepared, &params).await,
+        Err(e) => Err(e),
+    };
+
+    match result.and_then(|ds| ds.to_bytes(format)) {
+        Ok(data) => ([(header::CONTENT_TYPE, content_type(format))], data).into_response(),
+        Err(e) => {
+            tracing::warn!("query failed: {}", e);
+            (status_of(&e), e.render(&sql)).into_response()
+        }
+    }
+}
+
+/// 注册的表
+async fn tables(Extension(session): Extension<Arc<Session>>) -> Json<Vec<String>> {
+    Json(session.table_names())
+}
+
+/// 结果缓存的统计，所有请求共享同一个缓存
+async fn cache(Extension(session): Extension<Arc<Session>>) -> Json<Value> {
+    let stats = session.cache_stats();
+    Json(json!({
+        "enabled": session.config().result_cache.is_some(),
+        "hits": stats.hits,
+        "misses": stats.misses,
+        "evictions": stats.evictions,
+        "entries": stats.entries,
+        "bytes": stats.bytes,
+    }))
+}
+
+fn parse_request(headers: &HeaderMap, body: &[u8]) -> Result<(String, Params), String> {
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("application/json"))
+        .unwrap_or(false);
+
+    if !is_json {
+        let sql = std::str::from_utf8(body).map_err(|e| e.to_string())?;
+        return Ok((sql.to_owned(), Params::new()));
+    }
+
+    let request: QueryRequest = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    let params = match request.params {
+        Value::Null => Params::new(),
+        Value::Array(values) => {
+            let values = values
+                .into_iter()
+                .map(to_param)
+                .collect::<Result<Vec<_>, _>>()?;
+            Params::positional(values)
+        }
+        Value::Object(map) => map
+            .into_iter()
+            .try_fold(Params::new(), |params, (name, v)| {
+                Ok::<_, String>(params.bind_named(&name, to_param(v)?))
+            })?,
+        v => return Err(format!("params must be an array or an object, got {}", v)),
+    };
+    Ok((request.sql, params))
+}
+
+fn to_param(v: Value) -> Result<Param, String> {
+    match v {
+        Value::Null => Ok(Param::Null),
+        Value::Bool(v) => Ok(v.into()),
+        Value::Number(n) => match n.as_i64() {
+            Some(v) => Ok(v.into()),
+            None => n
+                .as_f64()
+                .map(Param::from)
+                .ok_or_else(|| format!("Unsupported number {}", n)),
+        },
+        Value::String(v) => Ok(v.into()),
+        v => Err(format!("Unsupported parameter {}", v)),
+    }
+}
+
+/// 按 Accept 里出现的顺序选第一个支持的格式，没有 Accept 时返回 csv
+fn accepted_format(headers: &HeaderMap) -> Option<Format> {
+    let accept = match headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) {
+        Some(accept) => accept,
+        None => return Some(Format::Csv),
+    };
+    accept.split(',').find_map(|item| {
+        let mime = item.split(';').next().unwrap_or_default().trim();
+        match mime {
+            "text/csv" | "text/*" | "*/*" => Some(Format::Csv),
+            "application/json" => Some(Format::Json),
+            "application/x-ndjson" | "application/jsonl" => Some(Format::NdJson),
+            "application/vnd.apache.arrow.file" => Some(Format::Arrow),
+            "application/vnd.apache.parquet" => Some(Format::Parquet),
+            _ => None,
+        }
+    })
+}
+
+fn content_type(format: Format) -> &'static str {
+    match format {
+        Format::Csv => "text/csv",
+        Format::Json => "application/json",
+        Format::NdJson => "application/x-ndjson",
+        Format::Arrow => "application/vnd.apache.arrow.file",
+        Format::Parquet => "application/vnd.apache.parquet",
+    }
+}
+
+fn status_of(e: &Error) -> StatusCode {
+    match e {
+        Error::Parse { .. } | Error::Unsupported { .. } => StatusCode::BAD_REQUEST,
+        Error::NotAllowed(_) => StatusCode::FORBIDDEN,
+        Error::LimitExceeded { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        Error::Fetch(_) => StatusCode::BAD_GATEWAY,
+        Error::Statement { source, .. } => status_of(source),
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn accepted_format_works() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(accepted_format(&headers), Some(Format::Csv));
+
+        headers.insert(
+            header::ACCEPT,
+            HeaderValue::from_static("text/html, application/json;q=0.9, */*;q=0.1"),
+        );
+        assert_eq!(accepted_format(&headers), Some(Format::Json));
+
+        headers.insert(header::ACCEPT, HeaderValue::from_static("image/png"));
+        assert_eq!(accepted_format(&headers), None);
+    }
+
+    #[test]
+    fn parse_request_works() {
+        let mut headers = HeaderMap::new();
+        let (sql, _) = parse_request(&headers, b"SELECT 1").unwrap();
+        assert_eq!(sql, "SELECT 1");
+
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        let body = br#"{"sql": "SELECT a FROM t WHERE a > ?", "params": [1.5]}"#;
+        assert!(parse_request(&headers, body).is_ok());
+        let body = br#"{"sql": "SELECT a FROM t WHERE a = :a", "params": {"a": "x"}}"#;
+        assert!(parse_request(&headers, body).is_ok());
+        let body = br#"{"sql": "SELECT 1", "params": 1}"#;
+        assert!(parse_request(&headers, body).is_err());
+        let body = br#"{"sql": "SELECT 1", "params": [[1]]}"#;
+        assert!(parse_request(&headers, body).is_err());
+    }
+
+    #[test]
+    fn parse_table_works() {
+        assert_eq!(
+            parse_table("covid=https://a.com/c.csv?x=1").unwrap(),
+            ("covid".into(), "https://a.com/c.csv?x=1".into())
+        );
+        assert!(parse_table("covid").is_err());
+    }
+}
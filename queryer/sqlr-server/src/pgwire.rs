@@ -0,0 +1,444 @@
+//! PostgreSQL 前后端协议（v3）的一个子集：启动、简单查询、行描述、数据行和错误，
+//! 足够让 psql 和大部分 BI 工具连上来执行查询。不支持认证、SSL 和扩展查询协议
+
+use chrono::NaiveDateTime;
+use polars::prelude::{AnyValue, DataType};
+use sqlr::{DataSet, Error, Session};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const PROTOCOL_VERSION: i32 = 196608;
+const SSL_REQUEST: i32 = 80877103;
+const CANCEL_REQUEST: i32 = 80877102;
+
+/// 消息最大的长度，长度字段来自还没有认证的客户端，不能直接用来分配内存
+const MAX_MESSAGE_LEN: i32 = 1 << 20;
+/// 启动消息最大的长度，和 postgres 一样
+const MAX_STARTUP_LEN: i32 = 10_000;
+
+/// 监听 addr，每个连接使用 session 的一个副本，连接里创建的视图和表只在这个连接里可见
+pub async fn serve(addr: SocketAddr, session: Session) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("postgres protocol listening on {}", addr);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let session = session.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, session).await {
+                tracing::warn!("postgres connection {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// 处理一个连接，直到客户端发送 Terminate 或者断开
+pub async fn handle<S>(mut stream: S, mut session: Session) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if !startup(&mut stream).await? {
+        return Ok(());
+    }
+
+    // 扩展查询协议出错之后，要丢弃消息直到 Sync
+    let mut skip_until_sync = false;
+    loop {
+        let tag = match stream.read_u8().await {
+            Ok(tag) => tag,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let len = stream.read_i32().await?;
+        let mut body = match body_len(len, 4, MAX_MESSAGE_LEN) {
+            Some(n) => vec![0; n],
+            None => return invalid_length(&mut stream, len).await,
+        };
+        stream.read_exact(&mut body).await?;
+
+        let mut out = Vec::new();
+        match tag {
+            b'Q' => {
+                let sql = String::from_utf8_lossy(cstr(&body)).into_owned();
+                simple_query(&mut session, &sql, &mut out).await;
+                out.extend(ready_for_query());
+            }
+            b'X' => return Ok(()),
+            b'S' => {
+                skip_until_sync = false;
+                out.extend(ready_for_query());
+            }
+            _ if skip_until_sync => {}
+            b'P' | b'B' | b'D' | b'E' | b'H' | b'C' | b'F' => {
+                skip_until_sync = true;
+                out.extend(error_response(
+                    "0A000",
+                    "extended query protocol is not supported, use simple query",
+                    None,
+                ));
+            }
+            tag => {
+                out.extend(error_response(
+                    "08P01",
+                    &format!("unexpected message {}", tag as char),
+                    None,
+                ));
+                out.extend(ready_for_query());
+            }
+        }
+        stream.write_all(&out).await?;
+        stream.flush().await?;
+    }
+}
+
+/// 完成启动握手，拒绝 SSL，不做认证。客户端只是发送取消请求的时候返回 false
+async fn startup<S>(stream: &mut S) -> std::io::Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let len = stream.read_i32().await?;
+        let mut body = match body_len(len, 8, MAX_STARTUP_LEN) {
+            Some(n) => vec![0; n],
+            None => {
+                invalid_length(stream, len).await?;
+                return Ok(false);
+            }
+        };
+        let code = stream.read_i32().await?;
+        stream.read_exact(&mut body).await?;
+
+        match code {
+            SSL_REQUEST => stream.write_all(b"N").await?,
+            CANCEL_REQUEST => return Ok(false),
+            PROTOCOL_VERSION => break,
+            code => {
+                let msg = format!("unsupported protocol version {}", code);
+                stream
+                    .write_all(&error_response("0A000", &msg, None))
+                    .await?;
+                return Ok(false);
+            }
+        }
+    }
+
+    let mut out = message(b'R', &0i32.to_be_bytes());
+    for (name, value) in [
+        ("server_version", "13.0 (sqlr)"),
+        ("server_encoding", "UTF8"),
+        ("client_encoding", "UTF8"),
+        ("DateStyle", "ISO, MDY"),
+        ("integer_datetimes", "on"),
+    ] {
+        let mut body = Vec::new();
+        put_cstr(&mut body, name);
+        put_cstr(&mut body, value);
+        out.extend(message(b'S', &body));
+    }
+    out.extend(ready_for_query());
+    stream.write_all(&out).await?;
+    stream.flush().await?;
+    Ok(true)
+}
+
+/// 长度字段包括它自己和 header 里的其它字段，返回剩下的消息体的长度，不合法时返回 None
+fn body_len(len: i32, header: i32, max: i32) -> Option<usize> {
+    (header..=max)
+        .contains(&len)
+        .then(|| (len - header) as usize)
+}
+
+/// 回复长度不合法的错误，之后关闭连接，因为已经没法找到下一条消息的开头
+async fn invalid_length<S>(stream: &mut S, len: i32) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let msg = format!("invalid message length {}", len);
+    stream
+        .write_all(&error_response("08P01", &msg, None))
+        .await?;
+    stream.flush().await
+}
+
+/// 执行简单查询里的所有语句，每条语句返回结果和 CommandComplete
+async fn simple_query(session: &mut Session, sql: &str, out: &mut Vec<u8>) {
+    if sql.trim().trim_matches(';').trim().is_empty() {
+        out.extend(message(b'I', &[]));
+        return;
+    }
+
+    // 和 execute_script 一样，任何一条语句出错都只返回错误
+    match session.execute_script(sql).await {
+        Ok(results) => {
+            for ds in results {
+                write_result(&ds, out);
+            }
+        }
+        Err(e) => {
+            let position = e.location().map(|loc| position(sql, loc.line, loc.column));
+            out.extend(error_response(sqlstate(&e), &e.to_string(), position));
+        }
+    }
+}
+
+fn write_result(ds: &DataSet, out: &mut Vec<u8>) {
+    let columns = ds.get_columns();
+    if columns.is_empty() {
+        out.extend(command_complete("OK"));
+        return;
+    }
+
+    let mut body = Vec::new();
+    body.extend((columns.len() as i16).to_be_bytes());
+    for s in columns {
+        let (oid, size) = pg_type(s.dtype());
+        put_cstr(&mut body, s.name());
+        body.extend(0i32.to_be_bytes()); // 表的 oid
+        body.extend(0i16.to_be_bytes()); // 列的序号
+        body.extend(oid.to_be_bytes());
+        body.extend(size.to_be_bytes());
+        body.extend((-1i32).to_be_bytes()); // 类型修饰
+        body.extend(0i16.to_be_bytes()); // 文本格式
+    }
+    out.extend(message(b'T', &body));
+
+    for row in 0..ds.height() {
+        let mut body = Vec::new();
+        body.extend((columns.len() as i16).to_be_bytes());
+        for s in columns {
+            match text_value(s.get(row)) {
+                Some(v) => {
+                    body.extend((v.len() as i32).to_be_bytes());
+                    body.extend(v.as_bytes());
+                }
+                None => body.extend((-1i32).to_be_bytes()),
+            }
+        }
+        out.extend(message(b'D', &body));
+    }
+    out.extend(command_complete(&format!("SELECT {}", ds.height())));
+}
+
+/// polars 类型对应的 postgres 类型 oid 和长度，不认识的类型按 text 返回
+fn pg_type(dtype: &DataType) -> (i32, i16) {
+    match dtype {
+        DataType::Boolean => (16, 1),
+        DataType::Int8 | DataType::Int16 | DataType::UInt8 => (21, 2),
+        DataType::Int32 | DataType::UInt16 => (23, 4),
+        DataType::Int64 | DataType::UInt32 | DataType::UInt64 => (20, 8),
+        DataType::Float32 => (700, 4),
+        DataType::Float64 => (701, 8),
+        DataType::Date32 => (1082, 4),
+        DataType::Date64 => (1114, 8),
+        _ => (25, -1),
+    }
+}
+
+/// 值的文本格式，null 返回 None；超出 chrono 范围的日期返回原始的整数
+fn text_value(v: AnyValue) -> Option<String> {
+    let text = match v {
+        AnyValue::Null => return None,
+        AnyValue::Boolean(v) => (if v { "t" } else { "f" }).to_owned(),
+        AnyValue::Utf8(v) => v.to_owned(),
+        AnyValue::Date32(days) => NaiveDateTime::from_timestamp_opt(days as i64 * 86400, 0)
+            .map_or_else(|| days.to_string(), |t| t.format("%Y-%m-%d").to_string()),
+        AnyValue::Date64(ms) => NaiveDateTime::from_timestamp_opt(
+            ms.div_euclid(1000),
+            (ms.rem_euclid(1000) * 1_000_000) as u32,
+        )
+        .map_or_else(
+            || ms.to_string(),
+            |t| t.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+        ),
+        v => v.to_string(),
+    };
+    Some(text)
+}
+
+fn sqlstate(e: &Error) -> &'static str {
+    match e {
+        Error::Parse { .. } => "42601",
+        Error::Unsupported { .. } => "0A000",
+        Error::NotAllowed(_) => "42501",
+        Error::Timeout(_) | Error::Cancelled => "57014",
+        Error::LimitExceeded { .. } => "54000",
+        Error::Fetch(_) => "58030",
+        Error::Statement { source, .. } => sqlstate(source),
+        _ => "XX000",
+    }
+}
+
+/// postgres 的错误位置是从 1 开始的字符偏移
+fn position(sql: &str, line: usize, column: usize) -> usize {
+    let before: usize = sql
+        .split('\n')
+        .take(line - 1)
+        .map(|l| l.chars().count() + 1)
+        .sum();
+    before + column
+}
+
+fn message(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(body.len() + 5);
+    buf.push(tag);
+    buf.extend(((body.len() + 4) as i32).to_be_bytes());
+    buf.extend(body);
+    buf
+}
+
+fn put_cstr(buf: &mut Vec<u8>, s: &str) {
+    buf.extend(s.as_bytes());
+    buf.push(0);
+}
+
+/// 去掉结尾的 `\0`
+fn cstr(body: &[u8]) -> &[u8] {
+    let end = body.iter().position(|b| *b == 0).unwrap_or(body.len());
+    &body[..end]
+}
+
+fn ready_for_query() -> Vec<u8> {
+    message(b'Z', b"I")
+}
+
+fn command_complete(tag: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_cstr(&mut body, tag);
+    message(b'C', &body)
+}
+
+fn error_response(code: &str, msg: &str, position: Option<usize>) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (field, value) in [(b'S', "ERROR"), (b'V', "ERROR"), (b'C', code), (b'M', msg)] {
+        body.push(field);
+        put_cstr(&mut body, value);
+    }
+    if let Some(position) = position {
+        body.push(b'P');
+        put_cstr(&mut body, &position.to_string());
+    }
+    body.push(0);
+    message(b'E', &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polars::prelude::*;
+
+    fn startup_message() -> Vec<u8> {
+        let mut body = PROTOCOL_VERSION.to_be_bytes().to_vec();
+        put_cstr(&mut body, "user");
+        put_cstr(&mut body, "postgres");
+        body.push(0);
+        let mut buf = ((body.len() + 4) as i32).to_be_bytes().to_vec();
+        buf.extend(body);
+        buf
+    }
+
+    /// 把服务器返回的数据拆成 (tag, body)
+    fn messages(mut data: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut result = Vec::new();
+        while !data.is_empty() {
+            let len = i32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+            result.push((data[0], data[5..len + 1].to_vec()));
+            data = &data[len + 1..];
+        }
+        result
+    }
+
+    #[tokio::test]
+    async fn simple_query_works() {
+        let mut session = Session::new();
+        let df = df!("name" => &["a", "b"], "value" => &[Some(1i64), None]).unwrap();
+        session.register_dataset("sample", DataSet::from(df));
+
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let task = tokio::spawn(handle(server, session));
+
+        let mut request = startup_message();
+        let mut query = Vec::new();
+        put_cstr(&mut query, "SELECT name, value FROM sample");
+        request.extend(message(b'Q', &query));
+        let mut query = Vec::new();
+        put_cstr(&mut query, "SELECT name FROM\nsample WHERE = 1");
+        request.extend(message(b'Q', &query));
+        request.extend(message(b'X', &[]));
+        client.write_all(&request).await.unwrap();
+
+        task.await.unwrap().unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let tags: Vec<_> = messages(&response).iter().map(|(tag, _)| *tag).collect();
+        assert_eq!(
+            tags,
+            b"RSSSSSZTDDCZEZ".to_vec(),
+            "startup, one result set and an error"
+        );
+
+        let msgs = messages(&response);
+        // 第二行的 value 是 null
+        assert_eq!(
+            msgs[9].1,
+            b"\x00\x02\x00\x00\x00\x01b\xff\xff\xff\xff".to_vec()
+        );
+        assert_eq!(msgs[10].1, b"SELECT 2\x00".to_vec());
+        let error = String::from_utf8_lossy(&msgs[12].1).into_owned();
+        assert!(error.contains("C42601\0"));
+        assert!(error.contains("P31\0"));
+    }
+
+    #[tokio::test]
+    async fn invalid_length_closes_connection() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let task = tokio::spawn(handle(server, Session::new()));
+        let mut request = startup_message();
+        request.push(b'Q');
+        request.extend((-1i32).to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        task.await.unwrap().unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let msgs = messages(&response);
+        let (tag, body) = msgs.last().unwrap();
+        assert_eq!(*tag, b'E');
+        assert!(String::from_utf8_lossy(body).contains("C08P01\0"));
+
+        // 启动消息的长度也有上限
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let task = tokio::spawn(handle(server, Session::new()));
+        client.write_all(&i32::MAX.to_be_bytes()).await.unwrap();
+        task.await.unwrap().unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(messages(&response)[0].0, b'E');
+    }
+
+    #[test]
+    fn text_value_works() {
+        assert_eq!(text_value(AnyValue::Null), None);
+        assert_eq!(
+            text_value(AnyValue::Date32(1)).as_deref(),
+            Some("1970-01-02")
+        );
+        assert_eq!(
+            text_value(AnyValue::Date64(1_500)).as_deref(),
+            Some("1970-01-01 00:00:01.500")
+        );
+        // 超出范围的日期不能让连接崩溃
+        assert_eq!(
+            text_value(AnyValue::Date64(i64::MAX)),
+            Some(i64::MAX.to_string())
+        );
+        assert_eq!(
+            text_value(AnyValue::Date32(i32::MIN)),
+            Some(i32::MIN.to_string())
+        );
+    }
+
+    #[test]
+    fn position_works() {
+        assert_eq!(position("SELECT a\nFROM t", 2, 3), 12);
+    }
+}
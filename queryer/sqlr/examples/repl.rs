@@ -0,0 +1,70 @@
+use anyhow::Result;
+use sqlr::Session;
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+
+/// 简单的交互式命令行，以 `;` 结尾的输入会被当成一个脚本执行
+///
+/// `\watch SELECT ...;` 监视查询用到的数据源，变化时打印新增和删除的行，按 Ctrl-C 停止
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut session = Session::new();
+    let mut buf = String::new();
+
+    prompt("sqlr> ")?;
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        buf.push_str(&line);
+        buf.push('\n');
+        if !line.trim_end().ends_with(';') {
+            prompt("   -> ")?;
+            continue;
+        }
+
+        let sql = std::mem::take(&mut buf);
+        if let Some(sql) = sql.trim_start().strip_prefix("\\watch") {
+            watch(&session, sql.trim().trim_end_matches(';')).await;
+            prompt("sqlr> ")?;
+            continue;
+        }
+        match session.execute_script(&sql).await {
+            Ok(results) => {
+                for ds in results.iter().filter(|ds| ds.width() > 0) {
+                    println!("{:?}", ds);
+                }
+            }
+            Err(e) => eprintln!("{}", e.render(&sql)),
+        }
+        prompt("sqlr> ")?;
+    }
+
+    Ok(())
+}
+
+async fn watch(session: &Session, sql: &str) {
+    let mut watch = match session.watch(sql, Duration::from_secs(1)) {
+        Ok(watch) => watch,
+        Err(e) => return eprintln!("{}", e.render(sql)),
+    };
+    println!("watching {}", watch.sources().join(", "));
+    loop {
+        tokio::select! {
+            update = watch.next() => match update {
+                Ok(update) => match update.diff {
+                    Some(diff) => println!("+ {:?}\n- {:?}", diff.added, diff.removed),
+                    None => println!("{:?}", update.result),
+                },
+                Err(e) => eprintln!("{}", e.render(sql)),
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+}
+
+fn prompt(s: &str) -> Result<()> {
+    print!("{}", s);
+    io::stdout().flush()?;
+    Ok(())
+}
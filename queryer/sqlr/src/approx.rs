@@ -0,0 +1,397 @@
+use crate::error::{Error, Result};
+use crate::sample::Rng;
+use polars::prelude::*;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+/// HyperLogLog 用哈希值的前 14 位选择寄存器，共 16384 个寄存器，占用 16KB，
+/// 标准误差约为 1.04 / sqrt(16384) ≈ 0.8%
+const PRECISION: u32 = 14;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// 近似分位数最多在这么多个值的样本上计算，分位数对应的排名误差通常在 0.5% 以内。
+/// 值的个数不超过样本大小时结果是精确的
+const SAMPLE_SIZE: usize = 10_000;
+
+/// 需要给出默认列名的聚合和分桶函数，否则输出的列名和参数列同名，容易冲突
+pub(crate) const FUNCTIONS: [&str; 7] = [
+    "approx_count_distinct",
+    "percentile_cont",
+    "quantile",
+    "approx_median",
+    "approx_quantile",
+    "width_bucket",
+    "bucket",
+];
+
+/// 估算不同值的个数，不计 null。不超过寄存器个数的值直接精确计算
+pub(crate) fn approx_count_distinct(expr: Expr) -> Expr {
+    expr.apply(
+        |s: Series| {
+            let count = if s.len() <= REGISTERS {
+                let mut seen = HashSet::new();
+                for_each_hash(&s, |h| {
+                    seen.insert(h);
+                })?;
+                seen.len() as u64
+            } else {
+                let mut hll = HyperLogLog::new();
+                for_each_hash(&s, |h| hll.insert(h))?;
+                hll.estimate().round() as u64
+            };
+            Ok(Series::new(s.name(), &[count]))
+        },
+        Some(DataType::UInt64),
+    )
+}
+
+/// 精确的连续分位数，在相邻的两个值之间线性插值，和 PostgreSQL 的 percentile_cont 一致
+pub(crate) fn percentile_cont(expr: Expr, q: f64) -> Result<Expr> {
+    check_fraction(q)?;
+    Ok(expr.apply(
+        move |s: Series| {
+            let value = percentile(values(&s)?, q);
+            Ok(Series::new(s.name(), &[value]))
+        },
+        Some(DataType::Float64),
+    ))
+}
+
+/// 在固定大小的样本上计算分位数，内存占用和数据量无关
+pub(crate) fn approx_quantile(expr: Expr, q: f64) -> Result<Expr> {
+    check_fraction(q)?;
+    Ok(expr.apply(
+        move |s: Series| {
+            let value = percentile(sample(&s)?, q);
+            Ok(Series::new(s.name(), &[value]))
+        },
+        Some(DataType::Float64),
+    ))
+}
+
+/// 和 PostgreSQL 的 width_bucket 一样：把 [low, high) 等分成 count 个桶，返回值所在的桶，
+/// 从 1 开始编号。小于 low 的值在 0 号桶，大于等于 high 的值在 count + 1 号桶
+pub(crate) fn width_bucket(expr: Expr, low: f64, high: f64, count: f64) -> Result<Expr> {
+    if low >= high || !low.is_finite() || !high.is_finite() || count < 1.0 || count.fract() != 0.0 {
+        return Err(Error::unsupported(
+            "width_bucket expects low < high and a positive integer count",
+            format!("width_bucket({}, {}, {})", low, high, count),
+        ));
+    }
+    Ok(expr.map(
+        move |s: Series| {
+            let ca = s.cast::<Float64Type>()?;
+            let out: Int64Chunked = ca
+                .f64()?
+                .into_iter()
+                .map(|v| v.map(|v| bucket_of(v, low, high, count)))
+                .collect();
+            let mut out = out.into_series();
+            out.rename(s.name());
+            Ok(out)
+        },
+        Some(DataType::Int64),
+    ))
+}
+
+/// 按固定的宽度分桶，返回桶的下界，比如 `bucket(age, 10)` 把 37 放到 30 这个桶里。
+/// 整数列的整数宽度结果还是整数，桶的下界超出 i64 的范围时是 null
+pub(crate) fn bucket(expr: Expr, width: f64) -> Result<Expr> {
+    if width <= 0.0 || !width.is_finite() {
+        return Err(Error::unsupported(
+            "Width of bucket must be positive",
+            format!("bucket({})", width),
+        ));
+    }
+    Ok(expr.map(
+        move |s: Series| {
+            let is_int = matches!(
+                s.dtype(),
+                DataType::Int8
+                    | DataType::Int16
+                    | DataType::Int32
+                    | DataType::Int64
+                    | DataType::UInt8
+                    | DataType::UInt16
+                    | DataType::UInt32
+                    | DataType::UInt64
+            );
+            let mut out = if is_int && width.fract() == 0.0 {
+                let width = width as i64;
+                let ca = s.cast::<Int64Type>()?;
+                let out: Int64Chunked = ca
+                    .i64()?
+                    .into_iter()
+                    .map(|v| v.and_then(|v| v.div_euclid(width).checked_mul(width)))
+                    .collect();
+                out.into_series()
+            } else {
+                let ca = s.cast::<Float64Type>()?;
+                let out: Float64Chunked = ca
+                    .f64()?
+                    .into_iter()
+                    .map(|v| v.map(|v| (v / width).floor() * width))
+                    .collect();
+                out.into_series()
+            };
+            out.rename(s.name());
+            Ok(out)
+        },
+        None,
+    ))
+}
+
+fn check_fraction(q: f64) -> Result<()> {
+    if (0.0..=1.0).contains(&q) {
+        Ok(())
+    } else {
+        Err(Error::unsupported(
+            format!("Quantile must be between 0 and 1, got {}", q),
+            q,
+        ))
+    }
+}
+
+fn bucket_of(v: f64, low: f64, high: f64, count: f64) -> i64 {
+    if v < low {
+        0
+    } else if v >= high {
+        count as i64 + 1
+    } else {
+        // 浮点误差可能让靠近 high 的值算到 count + 1
+        (((v - low) / (high - low) * count).floor() as i64 + 1).min(count as i64)
+    }
+}
+
+/// 非 null 也不是 NaN 的值
+fn values(s: &Series) -> polars::prelude::Result<Vec<f64>> {
+    let ca = s.cast::<Float64Type>()?;
+    let values = ca.f64()?.into_iter().flatten().filter(|v| !v.is_nan());
+    Ok(values.collect())
+}
+
+/// 蓄水池抽样，种子固定，同样的数据每次得到同样的结果
+fn sample(s: &Series) -> polars::prelude::Result<Vec<f64>> {
+    let ca = s.cast::<Float64Type>()?;
+    let mut reservoir = Vec::with_capacity(SAMPLE_SIZE.min(s.len()));
+    let mut rng = Rng::new(0);
+    let values = ca.f64()?.into_iter().flatten().filter(|v| !v.is_nan());
+    for (i, v) in values.enumerate() {
+        if i < SAMPLE_SIZE {
+            reservoir.push(v);
+            continue;
+        }
+        let j = rng.below(i as u64 + 1) as usize;
+        if j < SAMPLE_SIZE {
+            reservoir[j] = v;
+        }
+    }
+    Ok(reservoir)
+}
+
+/// 第 q 分位数，排名在 q * (n - 1)，不是整数时在相邻的两个值之间插值
+fn percentile(mut values: Vec<f64>, q: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let cmp = |a: &f64, b: &f64| a.partial_cmp(b).unwrap_or(Ordering::Equal);
+    let rank = q * (values.len() - 1) as f64;
+    let i = rank.floor() as usize;
+    let (_, lower, higher) = values.select_nth_unstable_by(i, cmp);
+    let lower = *lower;
+    let fraction = rank - i as f64;
+    if fraction == 0.0 {
+        return Some(lower);
+    }
+    let upper = higher.iter().copied().fold(f64::INFINITY, f64::min);
+    Some(lower + (upper - lower) * fraction)
+}
+
+/// 对每个非 null 的值计算哈希
+fn for_each_hash(s: &Series, mut f: impl FnMut(u64)) -> polars::prelude::Result<()> {
+    match s.dtype() {
+        DataType::Utf8 => s.utf8()?.into_iter().flatten().for_each(|v| f(hash(v))),
+        DataType::Float32 | DataType::Float64 => {
+            let ca = s.cast::<Float64Type>()?;
+            // 0.0 和 -0.0 是同一个值
+            ca.f64()?
+                .into_iter()
+                .flatten()
+                .for_each(|v| f(hash(&(v + 0.0).to_bits())));
+        }
+        _ => match s.cast::<Int64Type>() {
+            Ok(ca) => ca.i64()?.into_iter().flatten().for_each(|v| f(hash(&v))),
+            Err(_) => {
+                let ca = s.cast::<Utf8Type>()?;
+                ca.utf8()?.into_iter().flatten().for_each(|v| f(hash(v)));
+            }
+        },
+    }
+    Ok(())
+}
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// HyperLogLog 基数估计，见 Flajolet 等人 2007 年的论文
+#[derive(Debug, Clone)]
+pub(crate) struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub(crate) fn new() -> Self {
+        Self {
+            registers: vec![0; REGISTERS],
+        }
+    }
+
+    /// 前 PRECISION 位选择寄存器，寄存器记录剩下的位里第一个 1 出现的最大位置
+    pub(crate) fn insert(&mut self, hash: u64) {
+        let index = (hash >> (64 - PRECISION)) as usize;
+        let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub(crate) fn estimate(&self) -> f64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let raw = alpha * m * m / sum;
+        // 基数小的时候用线性计数修正
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hyperloglog_is_close_to_exact() {
+        for n in [1_000u64, 50_000, 1_000_000] {
+            let mut hll = HyperLogLog::new();
+            // 每个值出现两次，重复的值不影响结果
+            for i in (0..n).chain(0..n) {
+                hll.insert(hash(&i));
+            }
+            let error = (hll.estimate() - n as f64).abs() / n as f64;
+            assert!(error < 0.03, "n = {}, error = {}", n, error);
+        }
+    }
+
+    #[test]
+    fn approx_count_distinct_works() {
+        let df = df!(
+            "g" => (0..100_000).map(|i| i % 2).collect::<Vec<i64>>(),
+            "v" => (0..100_000).map(|i| (i % 30_000) as f64).collect::<Vec<f64>>()
+        )
+        .unwrap();
+        let exact = df.column("v").unwrap().n_unique().unwrap() as f64;
+
+        let out = df
+            .clone()
+            .lazy()
+            .select(vec![approx_count_distinct(col("v"))])
+            .collect()
+            .unwrap();
+        let estimate = out.column("v").unwrap().u64().unwrap().get(0).unwrap() as f64;
+        assert!((estimate - exact).abs() / exact < 0.03);
+
+        // 每组 15000 个不同的值
+        let out = df
+            .lazy()
+            .groupby(vec![col("g")])
+            .agg(vec![approx_count_distinct(col("v"))])
+            .collect()
+            .unwrap();
+        assert_eq!(out.height(), 2);
+        for v in out.column("v").unwrap().u64().unwrap().into_iter() {
+            let v = v.unwrap() as f64;
+            assert!((v - 15_000.0).abs() / 15_000.0 < 0.03);
+        }
+    }
+
+    #[test]
+    fn percentile_works() {
+        let values: Vec<f64> = (1..=10).map(|v| v as f64).collect();
+        assert_eq!(percentile(values.clone(), 0.0), Some(1.0));
+        assert_eq!(percentile(values.clone(), 1.0), Some(10.0));
+        assert_eq!(percentile(values.clone(), 0.5), Some(5.5));
+        assert!((percentile(values, 0.95).unwrap() - 9.55).abs() < 1e-9);
+        assert_eq!(percentile(vec![], 0.5), None);
+        assert!(check_fraction(1.5).is_err());
+    }
+
+    #[test]
+    fn approx_quantile_is_close_to_exact() {
+        // 打乱顺序的 0..200000
+        let n = 200_000u64;
+        let values: Vec<f64> = (0..n).map(|i| ((i * 7919) % n) as f64).collect();
+        let s = Series::new("v", &values);
+        for q in [0.05, 0.5, 0.95] {
+            let exact = percentile(values.clone(), q).unwrap();
+            let approx = percentile(sample(&s).unwrap(), q).unwrap();
+            assert!(
+                (approx - exact).abs() / n as f64 <= 0.02,
+                "q = {}, exact = {}, approx = {}",
+                q,
+                exact,
+                approx
+            );
+        }
+    }
+
+    #[test]
+    fn buckets_work() {
+        assert_eq!(bucket_of(-1.0, 0.0, 100.0, 10.0), 0);
+        assert_eq!(bucket_of(0.0, 0.0, 100.0, 10.0), 1);
+        assert_eq!(bucket_of(99.9, 0.0, 100.0, 10.0), 10);
+        assert_eq!(bucket_of(100.0, 0.0, 100.0, 10.0), 11);
+        assert!(width_bucket(col("v"), 1.0, 1.0, 10.0).is_err());
+
+        let df = df!("v" => &[-7i64, 3, 37]).unwrap();
+        let out = df
+            .lazy()
+            .select(vec![bucket(col("v"), 10.0).unwrap()])
+            .collect()
+            .unwrap();
+        let v: Vec<_> = out
+            .column("v")
+            .unwrap()
+            .i64()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(v, vec![Some(-10), Some(0), Some(30)]);
+
+        // 桶的下界超出 i64 的范围时是 null
+        let df = df!("v" => &[i64::MIN, i64::MAX]).unwrap();
+        let out = df
+            .lazy()
+            .select(vec![bucket(col("v"), 3.0).unwrap()])
+            .collect()
+            .unwrap();
+        let v: Vec<_> = out
+            .column("v")
+            .unwrap()
+            .i64()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(v, vec![None, Some(i64::MAX / 3 * 3)]);
+    }
+}
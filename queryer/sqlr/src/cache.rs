@@ -0,0 +1,202 @@
+use instant::Instant;
+use polars::prelude::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// 查询结果缓存的配置
+#[derive(Debug, Clone)]
+pub struct ResultCacheConfig {
+    /// 结果缓存的时间，即使数据源没有变化，超过这个时间也会重新执行查询
+    pub ttl: Duration,
+    /// 所有缓存的结果最多占用的内存（估算值），超出时淘汰最久没有用到的结果
+    pub max_bytes: usize,
+}
+
+impl Default for ResultCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(60),
+            max_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// 结果缓存的统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// 因为过期或者内存不够被淘汰的结果
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// 查询结果缓存，键由 session 根据规范化的 SQL 和数据源的校验值生成
+#[derive(Debug, Default)]
+pub(crate) struct ResultCache {
+    entries: HashMap<String, Entry>,
+    bytes: usize,
+    /// 每次访问加一，用来找出最久没有用到的结果
+    clock: u64,
+    stats: CacheStats,
+}
+
+#[derive(Debug)]
+struct Entry {
+    df: DataFrame,
+    size: usize,
+    created: Instant,
+    used: u64,
+}
+
+impl ResultCache {
+    pub(crate) fn get(&mut self, key: &str, config: &ResultCacheConfig) -> Option<DataFrame> {
+        self.clock += 1;
+        let expired = match self.entries.get_mut(key) {
+            Some(entry) if entry.created.elapsed() <= config.ttl => {
+                entry.used = self.clock;
+                self.stats.hits += 1;
+                return Some(entry.df.clone());
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            self.evict(key);
+        }
+        self.stats.misses += 1;
+        None
+    }
+
+    pub(crate) fn insert(&mut self, key: String, df: DataFrame, config: &ResultCacheConfig) {
+        let size = estimated_size(&df);
+        // 比整个缓存还大的结果不缓存，免得把别的结果都淘汰掉
+        if size > config.max_bytes {
+            return;
+        }
+        self.remove(&key);
+        while self.bytes + size > config.max_bytes {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => self.evict(&oldest),
+                None => break,
+            }
+        }
+
+        self.clock += 1;
+        self.bytes += size;
+        let entry = Entry {
+            df,
+            size,
+            created: Instant::now(),
+            used: self.clock,
+        };
+        self.entries.insert(key, entry);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            bytes: self.bytes,
+            ..self.stats
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.bytes -= entry.size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 过期或者内存不够时淘汰，计入 evictions；同一个键的结果被替换时不算
+    fn evict(&mut self, key: &str) {
+        if self.remove(key) {
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+/// 估算 DataFrame 占用的内存：定长的类型按宽度计算，字符串加上内容的长度
+fn estimated_size(df: &DataFrame) -> usize {
+    df.get_columns()
+        .iter()
+        .map(|s| match s.dtype() {
+            DataType::Utf8 => s
+                .utf8()
+                .map(|ca| ca.into_iter().flatten().map(|v| v.len()).sum::<usize>())
+                .unwrap_or_default()
+                .saturating_add(s.len() * 8),
+            DataType::Boolean | DataType::Int8 | DataType::UInt8 => s.len(),
+            DataType::Int16 | DataType::UInt16 => s.len() * 2,
+            DataType::Int32 | DataType::UInt32 | DataType::Float32 | DataType::Date32 => {
+                s.len() * 4
+            }
+            _ => s.len() * 8,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(n: i64) -> DataFrame {
+        df!("v" => (0..n).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn result_cache_evicts_least_recently_used() {
+        let config = ResultCacheConfig {
+            ttl: Duration::from_secs(60),
+            max_bytes: 8 * 250,
+        };
+        let mut cache = ResultCache::default();
+        cache.insert("a".into(), frame(100), &config);
+        cache.insert("b".into(), frame(100), &config);
+        assert!(cache.get("a", &config).is_some());
+
+        // 放不下时淘汰最久没有用到的 b
+        cache.insert("c".into(), frame(100), &config);
+        assert!(cache.get("b", &config).is_none());
+        assert!(cache.get("a", &config).is_some());
+        // 比整个缓存还大的结果不缓存
+        cache.insert("d".into(), frame(1000), &config);
+        assert!(cache.get("d", &config).is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (2, 1600, 1));
+
+        // 替换同一个键的结果不算淘汰
+        cache.insert("a".into(), frame(50), &config);
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.stats().bytes, 1200);
+    }
+
+    #[test]
+    fn result_cache_expires() {
+        let config = ResultCacheConfig {
+            ttl: Duration::from_millis(0),
+            ..Default::default()
+        };
+        let mut cache = ResultCache::default();
+        cache.insert("a".into(), frame(10), &config);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(cache.get("a", &config).is_none());
+        assert_eq!((cache.stats().entries, cache.stats().evictions), (0, 1));
+    }
+}
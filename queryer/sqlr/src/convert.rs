@@ -1,10 +1,43 @@
-use anyhow::{anyhow, Result};
+use crate::approx;
+use crate::error::{Error, Result};
+use crate::json::JsonOptions;
+use crate::params::{is_placeholder, Param, Params};
+use crate::reshape::{name_of, Reshape};
+use crate::sample::Sample;
+use crate::temporal::{self, Interval, Unit};
 use polars::prelude::*;
 use sqlparser::ast::{
-    BinaryOperator as SqlBinaryOperator, Expr as SqlExpr, FunctionArg, Ident, Offset as SqlOffset,
-    OrderByExpr, Select, SelectItem, SetExpr, Statement, TableFactor, TableWithJoins,
-    Value as SqlValue,
+    BinaryOperator as SqlBinaryOperator, DataType as SqlDataType, Expr as SqlExpr,
+    Function as SqlFunction, FunctionArg, Offset as SqlOffset, OrderByExpr, Select, SelectItem,
+    SetExpr, Statement, TableFactor, TableWithJoins, UnaryOperator, Value as SqlValue,
 };
+use std::collections::HashMap;
+
+/// 用户自定义函数：输入转换好的参数，输出 DataFrame 的 Expr
+pub type Udf = Arc<dyn Fn(Vec<Expr>) -> Result<Expr> + Send + Sync>;
+
+/// 函数名到 Udf 的映射
+pub(crate) type Udfs = HashMap<String, Udf>;
+
+/// 转换 SQL 时需要的上下文，比如 session 里注册的 Udf 和绑定的参数
+#[derive(Clone, Copy, Default)]
+pub struct Context<'a> {
+    pub(crate) udfs: Option<&'a Udfs>,
+    pub(crate) params: Option<&'a Params>,
+}
+
+impl<'a> Context<'a> {
+    /// 取出占位符绑定的值
+    fn param(&self, placeholder: &str) -> Result<&'a Param> {
+        match self.params {
+            Some(params) => params.get(placeholder),
+            None => Err(Error::execution(format!(
+                "Parameter {} is not bound",
+                placeholder
+            ))),
+        }
+    }
+}
 
 /// 解析出来的 SQL
 #[warn(dead_code)]
@@ -12,28 +45,76 @@ pub struct Sql<'a> {
     pub(crate) selection: Vec<Expr>,
     pub(crate) condition: Option<Expr>,
     pub(crate) source: &'a str,
+    /// 数据源是数据库时要读取的表，比如 `'file://ref.db'.country`
+    pub(crate) table: Option<&'a str>,
+    /// `read_json(...)` 的参数
+    pub(crate) json: Option<JsonOptions>,
+    /// 读取数据源之后先做的 PIVOT / UNPIVOT
+    pub(crate) reshape: Option<Reshape>,
+    /// TABLESAMPLE 抽样，在 PIVOT / UNPIVOT 之前
+    pub(crate) sample: Option<Sample>,
+    /// WHERE 的原始表达式，用来把条件下推到数据源
+    pub(crate) predicate: Option<&'a SqlExpr>,
     pub(crate) order_by: Vec<(String, bool)>,
     pub(crate) offset: Option<i64>,
     pub(crate) limit: Option<usize>,
+    /// GROUP BY 的分组键，有分组时 selection 里只剩下聚合的表达式
+    pub(crate) group_by: Vec<Expr>,
+    /// `time_bucket_gapfill` 分组时需要补齐的列和桶的间隔
+    pub(crate) gap_fill: Option<(String, Interval)>,
+}
+
+/// FROM 里的数据源
+pub(crate) struct Relation<'a> {
+    /// 表名或者数据源地址
+    pub(crate) source: &'a str,
+    /// 数据源是数据库时要读取的表
+    pub(crate) table: Option<&'a str>,
+    /// `read_json(...)` 的参数
+    pub(crate) json: Option<JsonOptions>,
+    /// `pivot(...)` 或者 `unpivot(...)` 的参数
+    pub(crate) reshape: Option<Reshape>,
+    /// `tablesample(...)` 的参数
+    pub(crate) sample: Option<Sample>,
+}
+
+impl<'a> Relation<'a> {
+    fn new(source: &'a str, table: Option<&'a str>) -> Self {
+        Self {
+            source,
+            table,
+            json: None,
+            reshape: None,
+            sample: None,
+        }
+    }
 }
 
 // 因为 Rust trait 的孤儿规则，我们如果要想对已有的类型实现已有的 trait，
 // 需要简单包装一下
 
-pub struct Expression(pub(crate) Box<SqlExpr>);
+pub struct Expression<'a>(pub(crate) Box<SqlExpr>, pub(crate) Context<'a>);
+pub struct Func<'a>(pub(crate) SqlFunction, pub(crate) Context<'a>);
 pub struct Operation(pub(crate) SqlBinaryOperator);
-pub struct Projection<'a>(pub(crate) &'a SelectItem);
+pub struct Projection<'a>(pub(crate) &'a SelectItem, pub(crate) Context<'a>);
 pub struct Source<'a>(pub(crate) &'a [TableWithJoins]);
 pub struct Order<'a>(pub(crate) &'a OrderByExpr);
-pub struct Offset<'a>(pub(crate) &'a SqlOffset);
-pub struct Limit<'a>(pub(crate) &'a SqlExpr);
+pub struct Offset<'a>(pub(crate) &'a SqlOffset, pub(crate) Context<'a>);
+pub struct Limit<'a>(pub(crate) &'a SqlExpr, pub(crate) Context<'a>);
 pub struct Value(pub(crate) SqlValue);
 
 /// 把 SqlParser 解析出来的 Statement 转换成我们需要的结构
 impl<'a> TryFrom<&'a Statement> for Sql<'a> {
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn try_from(sql: &'a Statement) -> Result<Self, Self::Error> {
+        Sql::with_context(sql, Context::default())
+    }
+}
+
+impl<'a> Sql<'a> {
+    /// 带上下文的转换，函数调用会先在 ctx 里查找 Udf
+    pub(crate) fn with_context(sql: &'a Statement, ctx: Context<'a>) -> Result<Self> {
         match sql {
             // 目前我们只关心 query (select ... from ... where ...)
             Statement::Query(q) => {
@@ -44,26 +125,87 @@ impl<'a> TryFrom<&'a Statement> for Sql<'a> {
                     from: table_with_joins,
                     selection: where_clause,
                     projection,
-
-                    group_by: _,
+                    group_by,
                     ..
                 } = match &q.body {
                     SetExpr::Select(statement) => statement.as_ref(),
-                    _ => return Err(anyhow!("We only support Select Query at the moment")),
+                    body => {
+                        return Err(Error::unsupported(
+                            "We only support Select Query at the moment",
+                            body,
+                        ))
+                    }
                 };
 
-                let source = Source(table_with_joins).try_into()?;
+                let Relation {
+                    source,
+                    table,
+                    json,
+                    reshape,
+                    sample,
+                } = Source(table_with_joins).try_into()?;
 
                 let condition = match where_clause {
-                    Some(expr) => Some(Expression(Box::new(expr.to_owned())).try_into()?),
+                    Some(expr) => Some(Expression(Box::new(expr.to_owned()), ctx).try_into()?),
                     None => None,
                 };
 
                 let mut selection = Vec::with_capacity(8);
                 for p in projection {
-                    let expr = Projection(p).try_into()?;
-                    println!("expr: {:?}", expr);
-                    selection.push(expr);
+                    selection.push(Projection(p, ctx).try_into()?);
+                }
+
+                let mut group = Vec::with_capacity(group_by.len());
+                let mut gap_fill = None;
+                let mut is_key = vec![false; projection.len()];
+                for expr in group_by {
+                    // 分组键优先用 SELECT 里对应的表达式，这样输出的列名就是它的别名
+                    let (key, sql_expr, alias) = match find_projection(projection, expr) {
+                        Some(i) => {
+                            is_key[i] = true;
+                            let (sql_expr, alias) = match &projection[i] {
+                                SelectItem::ExprWithAlias { expr, alias } => (expr, Some(alias)),
+                                SelectItem::UnnamedExpr(expr) => (expr, None),
+                                item => {
+                                    return Err(Error::unsupported(
+                                        format!("Can not group by {}", item),
+                                        item,
+                                    ))
+                                }
+                            };
+                            (selection[i].clone(), sql_expr, alias)
+                        }
+                        None if matches!(expr, SqlExpr::Value(_)) => {
+                            return Err(Error::unsupported(
+                                format!("GROUP BY position {} is out of range", expr),
+                                expr,
+                            ))
+                        }
+                        None => (
+                            Expression(Box::new(expr.clone()), ctx).try_into()?,
+                            expr,
+                            None,
+                        ),
+                    };
+                    if let Some(interval) = gap_fill_interval(sql_expr)? {
+                        let name = alias.map(|a| a.value.clone());
+                        gap_fill = Some((name.unwrap_or_else(|| "time_bucket".into()), interval));
+                    }
+                    group.push(key);
+                }
+                if gap_fill.is_some() && group.len() > 1 {
+                    return Err(Error::unsupported(
+                        "time_bucket_gapfill must be the only GROUP BY key",
+                        "time_bucket_gapfill",
+                    ));
+                }
+                if !group.is_empty() {
+                    selection = selection
+                        .into_iter()
+                        .zip(is_key)
+                        .filter(|(_, is_key)| !is_key)
+                        .map(|(expr, _)| expr)
+                        .collect();
                 }
 
                 let mut order_by = Vec::new();
@@ -71,47 +213,282 @@ impl<'a> TryFrom<&'a Statement> for Sql<'a> {
                     order_by.push(Order(expr).try_into()?);
                 }
 
-                let offset = offset.map(|v| Offset(v).into());
-                let limit = limit.map(|v| Limit(v).into());
+                let offset = offset.map(|v| Offset(v, ctx).try_into()).transpose()?;
+                let limit = limit.map(|v| Limit(v, ctx).try_into()).transpose()?;
 
                 Ok(Sql {
                     selection,
                     condition,
                     source,
+                    table,
+                    json,
+                    reshape,
+                    sample,
+                    predicate: where_clause.as_ref(),
                     order_by,
                     offset,
                     limit,
+                    group_by: group,
+                    gap_fill,
                 })
             }
-            _ => Err(anyhow!("We only support Query at the moment")),
+            stmt => Err(Error::unsupported(
+                "We only support Query at the moment",
+                stmt,
+            )),
+        }
+    }
+}
+
+/// 在 SELECT 里查找 GROUP BY 引用的表达式：同样的表达式、别名，或者从 1 开始的序号
+fn find_projection(projection: &[SelectItem], expr: &SqlExpr) -> Option<usize> {
+    if let SqlExpr::Value(SqlValue::Number(n, _)) = expr {
+        return match n.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= projection.len() => Some(n - 1),
+            _ => None,
+        };
+    }
+    projection.iter().position(|p| match p {
+        SelectItem::UnnamedExpr(e) => e == expr,
+        SelectItem::ExprWithAlias { expr: e, alias } => {
+            e == expr || matches!(expr, SqlExpr::Identifier(id) if id.value == alias.value)
+        }
+        _ => false,
+    })
+}
+
+/// `time_bucket_gapfill('1 week', ts)` 的间隔
+fn gap_fill_interval(expr: &SqlExpr) -> Result<Option<Interval>> {
+    match expr {
+        SqlExpr::Function(f) if f.name.to_string().to_lowercase() == "time_bucket_gapfill" => {
+            match f.args.first() {
+                Some(FunctionArg::Unnamed(SqlExpr::Value(SqlValue::SingleQuotedString(s)))) => {
+                    Ok(Some(s.parse()?))
+                }
+                _ => Ok(None),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+/// 查询是否调用了 is_volatile 认为每次结果都可能不同的函数，比如 now()
+///
+/// 我们只支持单个 SELECT，所以只需要检查投影、WHERE、GROUP BY、HAVING、ORDER BY 和表函数的参数
+pub(crate) fn calls_volatile(stmt: &Statement, is_volatile: &dyn Fn(&str) -> bool) -> bool {
+    let (query, select) = match stmt {
+        Statement::Query(q) => match &q.body {
+            SetExpr::Select(select) => (q, select),
+            _ => return false,
+        },
+        _ => return false,
+    };
+
+    let mut exprs: Vec<&SqlExpr> = Vec::new();
+    for item in &select.projection {
+        if let SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } = item {
+            exprs.push(expr);
+        }
+    }
+    exprs.extend(&select.selection);
+    exprs.extend(&select.group_by);
+    exprs.extend(&select.having);
+    exprs.extend(query.order_by.iter().map(|o| &o.expr));
+    for table in &select.from {
+        if let TableFactor::Table { args, .. } = &table.relation {
+            exprs.extend(args.iter().map(function_arg));
+        }
+    }
+    exprs.into_iter().any(|expr| calls(expr, is_volatile))
+}
+
+fn function_arg(arg: &FunctionArg) -> &SqlExpr {
+    match arg {
+        FunctionArg::Unnamed(expr) => expr,
+        FunctionArg::Named { arg, .. } => arg,
+    }
+}
+
+/// 表达式里有没有调用满足 f 的函数
+fn calls(expr: &SqlExpr, f: &dyn Fn(&str) -> bool) -> bool {
+    let any = |exprs: &[SqlExpr]| exprs.iter().any(|expr| calls(expr, f));
+    match expr {
+        SqlExpr::Function(func) => {
+            f(&func.name.to_string().to_lowercase())
+                || func.args.iter().any(|arg| calls(function_arg(arg), f))
+        }
+        SqlExpr::BinaryOp { left, right, .. } => calls(left, f) || calls(right, f),
+        SqlExpr::UnaryOp { expr, .. }
+        | SqlExpr::Nested(expr)
+        | SqlExpr::IsNull(expr)
+        | SqlExpr::IsNotNull(expr)
+        | SqlExpr::Cast { expr, .. }
+        | SqlExpr::Extract { expr, .. } => calls(expr, f),
+        SqlExpr::Between {
+            expr, low, high, ..
+        } => calls(expr, f) || calls(low, f) || calls(high, f),
+        SqlExpr::InList { expr, list, .. } => calls(expr, f) || any(list),
+        SqlExpr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            operand.iter().chain(else_result).any(|expr| calls(expr, f))
+                || any(conditions)
+                || any(results)
         }
+        _ => false,
     }
 }
 
 /// 把 SqlParser 的 Expr 转换成 DataFrame 的 Expr
-impl TryFrom<Expression> for Expr {
-    type Error = anyhow::Error;
+impl<'a> TryFrom<Expression<'a>> for Expr {
+    type Error = Error;
 
-    fn try_from(expr: Expression) -> Result<Self, Self::Error> {
+    fn try_from(expr: Expression<'a>) -> Result<Self, Self::Error> {
+        let ctx = expr.1;
         match *expr.0 {
             SqlExpr::BinaryOp { left, op, right } => Ok(Expr::BinaryExpr {
-                left: Box::new(Expression(left).try_into()?),
+                left: Box::new(Expression(left, ctx).try_into()?),
                 op: Operation(op).try_into()?,
-                right: Box::new(Expression(right).try_into()?),
+                right: Box::new(Expression(right, ctx).try_into()?),
             }),
             SqlExpr::Wildcard => Ok(Self::Wildcard),
-            SqlExpr::IsNull(expr) => Ok(Self::IsNull(Box::new(Expression(expr).try_into()?))),
-            SqlExpr::IsNotNull(expr) => Ok(Self::IsNotNull(Box::new(Expression(expr).try_into()?))),
+            SqlExpr::IsNull(expr) => Ok(Self::IsNull(Box::new(Expression(expr, ctx).try_into()?))),
+            SqlExpr::IsNotNull(expr) => {
+                Ok(Self::IsNotNull(Box::new(Expression(expr, ctx).try_into()?)))
+            }
+            // 占位符在解析时被改写成了 `$1`、`:name` 这样的标识符
+            SqlExpr::Identifier(id) if id.quote_style.is_none() && is_placeholder(&id.value) => {
+                Ok(ctx.param(&id.value)?.into())
+            }
             SqlExpr::Identifier(id) => Ok(Self::Column(Arc::new(id.value))),
             SqlExpr::Value(v) => Ok(Self::Literal(Value(v).try_into()?)),
-            v => Err(anyhow!("expr {:#?} is not supported", v)),
+            SqlExpr::Nested(expr) => Expression(expr, ctx).try_into(),
+            SqlExpr::UnaryOp {
+                op: UnaryOperator::Minus,
+                expr,
+            } => Ok(lit(0) - Expression(expr, ctx).try_into()?),
+            SqlExpr::UnaryOp {
+                op: UnaryOperator::Plus,
+                expr,
+            } => Expression(expr, ctx).try_into(),
+            SqlExpr::Function(func) => Func(func, ctx).try_into(),
+            SqlExpr::Extract { field, expr } => {
+                let unit: Unit = field.to_string().parse()?;
+                Ok(temporal::extract(unit, Expression(expr, ctx).try_into()?))
+            }
+            SqlExpr::Cast {
+                expr,
+                data_type: SqlDataType::Date | SqlDataType::Timestamp,
+            } => Ok(temporal::to_timestamp(Expression(expr, ctx).try_into()?)),
+            v => Err(Error::unsupported(
+                format!("expr {} is not supported", v),
+                &v,
+            )),
+        }
+    }
+}
+
+/// 把 SqlParser 的函数调用转换成 DataFrame 的 Expr，Udf 优先于内置函数
+impl<'a> TryFrom<Func<'a>> for Expr {
+    type Error = Error;
+
+    fn try_from(f: Func<'a>) -> Result<Self, Self::Error> {
+        let ctx = f.1;
+        let name = f.0.name.to_string().to_lowercase();
+        let fragment = f.0.to_string();
+        let mut raw = Vec::with_capacity(f.0.args.len());
+        let mut args = Vec::with_capacity(f.0.args.len());
+        for arg in f.0.args {
+            let expr = match arg {
+                FunctionArg::Unnamed(expr) => expr,
+                FunctionArg::Named { arg, .. } => arg,
+            };
+            raw.push(expr.clone());
+            args.push(Expression(Box::new(expr), ctx).try_into()?);
+        }
+
+        if let Some(udf) = ctx.udfs.and_then(|udfs| udfs.get(&name)) {
+            return udf(args);
+        }
+
+        let mut args = args.into_iter();
+        let mut arg = || {
+            args.next().ok_or_else(|| {
+                Error::unsupported(format!("Function {} requires an argument", name), &fragment)
+            })
+        };
+        // 日期函数的单位和间隔需要是字符串常量，比如 'month'、'1 week'
+        let literal = |i: usize| match raw.get(i) {
+            Some(SqlExpr::Value(SqlValue::SingleQuotedString(s))) => Ok(s.as_str()),
+            _ => Err(Error::unsupported(
+                format!("Argument {} of {} must be a string literal", i + 1, name),
+                &fragment,
+            )),
+        };
+        // 分位数和分桶的参数需要是数字常量，比如 0.95
+        let number = |i: usize| {
+            let n = match raw.get(i) {
+                Some(SqlExpr::Value(SqlValue::Number(n, _))) => n.parse::<f64>().ok(),
+                Some(SqlExpr::UnaryOp {
+                    op: UnaryOperator::Minus,
+                    expr,
+                }) => match expr.as_ref() {
+                    SqlExpr::Value(SqlValue::Number(n, _)) => n.parse::<f64>().ok().map(|n| -n),
+                    _ => None,
+                },
+                _ => None,
+            };
+            n.ok_or_else(|| {
+                Error::unsupported(
+                    format!("Argument {} of {} must be a number", i + 1, name),
+                    &fragment,
+                )
+            })
+        };
+        match name.as_str() {
+            "count" => Ok(arg()?.count()),
+            "sum" => Ok(arg()?.sum()),
+            "now" => Ok(temporal::now()),
+            "to_timestamp" => Ok(temporal::to_timestamp(arg()?)),
+            "date_trunc" => {
+                let unit = literal(0)?.parse()?;
+                arg()?;
+                Ok(temporal::date_trunc(unit, arg()?))
+            }
+            "date_add" => {
+                let interval = literal(1)?.parse()?;
+                Ok(temporal::date_add(arg()?, interval))
+            }
+            "date_diff" => {
+                let unit = literal(0)?.parse()?;
+                arg()?;
+                Ok(temporal::date_diff(unit, arg()?, arg()?))
+            }
+            "time_bucket" | "time_bucket_gapfill" => {
+                let interval = literal(0)?.parse()?;
+                arg()?;
+                temporal::time_bucket(interval, arg()?)
+            }
+            "approx_count_distinct" => Ok(approx::approx_count_distinct(arg()?)),
+            "percentile_cont" | "quantile" => approx::percentile_cont(arg()?, number(1)?),
+            "approx_median" => approx::approx_quantile(arg()?, 0.5),
+            "approx_quantile" => approx::approx_quantile(arg()?, number(1)?),
+            "width_bucket" => approx::width_bucket(arg()?, number(1)?, number(2)?, number(3)?),
+            "bucket" => approx::bucket(arg()?, number(1)?),
+            _ => Err(Error::unsupported(
+                format!("Function {} is not supported", f.0.name),
+                &fragment,
+            )),
         }
     }
 }
 
 /// 把 SqlParser 的 BinaryOperator 转换成 DataFrame 的 Operator
 impl TryFrom<Operation> for Operator {
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn try_from(op: Operation) -> Result<Self, Self::Error> {
         match op.0 {
@@ -128,100 +505,182 @@ impl TryFrom<Operation> for Operator {
             SqlBinaryOperator::NotEq => Ok(Self::NotEq),
             SqlBinaryOperator::And => Ok(Self::And),
             SqlBinaryOperator::Or => Ok(Self::Or),
-            v => Err(anyhow!("Operator {} is not supported", v)),
+            v => Err(Error::unsupported(
+                format!("Operator {} is not supported", v),
+                &v,
+            )),
         }
     }
 }
 
 /// 把 SqlParser 的 SelectItem 转换成 DataFrame 的 Expr
 impl<'a> TryFrom<Projection<'a>> for Expr {
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn try_from(p: Projection<'a>) -> Result<Self, Self::Error> {
+        let ctx = p.1;
         match p.0 {
-            SelectItem::UnnamedExpr(SqlExpr::Identifier(id)) => Ok(col(&id.to_string())),
-            SelectItem::UnnamedExpr(SqlExpr::Function(func)) => match func.name.0[0].value.as_str()
+            SelectItem::UnnamedExpr(SqlExpr::Identifier(id)) => Ok(col(&id.value)),
+            // 日期、近似聚合和分桶函数的结果默认以函数名命名，避免和参数列重名
+            SelectItem::UnnamedExpr(expr @ SqlExpr::Function(f))
+                if temporal::FUNCTIONS
+                    .iter()
+                    .chain(approx::FUNCTIONS.iter())
+                    .any(|name| f.name.to_string().eq_ignore_ascii_case(name)) =>
             {
-                "count" => Ok(count(match func.args.first() {
-                    Some(FunctionArg::Unnamed(SqlExpr::Identifier(Ident { value, .. }))) => {
-                        value.as_str()
-                    }
-                    _ => "",
-                })),
-                "sum" => Ok(sum(match func.args.first() {
-                    Some(FunctionArg::Unnamed(SqlExpr::Identifier(Ident { value, .. }))) => {
-                        value.as_str()
-                    }
-                    _ => "",
-                })),
-                _ => Err(anyhow!("Function {} is not supported", func.name)),
-            },
+                let name = f.name.to_string().to_lowercase();
+                let expr: Expr = Expression(Box::new(expr.to_owned()), ctx).try_into()?;
+                Ok(expr.alias(name.trim_end_matches("_gapfill")))
+            }
+            SelectItem::UnnamedExpr(expr @ SqlExpr::Extract { field, .. }) => {
+                let name = field.to_string().to_lowercase();
+                let expr: Expr = Expression(Box::new(expr.to_owned()), ctx).try_into()?;
+                Ok(expr.alias(&name))
+            }
+            SelectItem::UnnamedExpr(expr) => Expression(Box::new(expr.to_owned()), ctx).try_into(),
             SelectItem::ExprWithAlias {
                 expr: SqlExpr::Identifier(id),
                 alias,
             } => Ok(Expr::Alias(
-                Box::new(Expr::Column(Arc::new(id.to_string()))),
-                Arc::new(alias.to_string()),
+                Box::new(Expr::Column(Arc::new(id.value.clone()))),
+                Arc::new(alias.value.clone()),
             )),
-            SelectItem::ExprWithAlias {
-                expr: SqlExpr::Function(func),
-                alias,
-            } => Ok(Expr::Alias(
-                Box::new(match func.name.0[0].value.as_str() {
-                    "count" => Ok(count(match func.args.first() {
-                        Some(FunctionArg::Unnamed(SqlExpr::Identifier(Ident {
-                            value, ..
-                        }))) => value.as_str(),
-                        _ => "",
-                    })),
-                    "sum" => Ok(sum(match func.args.first() {
-                        Some(FunctionArg::Unnamed(SqlExpr::Identifier(Ident {
-                            value, ..
-                        }))) => value.as_str(),
-                        _ => "",
-                    })),
-                    _ => Err(anyhow!("Function {} is not supported", func.name)),
-                }?),
-                Arc::new(alias.to_string()),
+            SelectItem::ExprWithAlias { expr, alias } => Ok(Expr::Alias(
+                Box::new(Expression(Box::new(expr.to_owned()), ctx).try_into()?),
+                Arc::new(alias.value.clone()),
             )),
             SelectItem::QualifiedWildcard(v) => Ok(col(&v.to_string())),
             SelectItem::Wildcard => Ok(col("*")),
-            item => Err(anyhow!("projection {} not supported", item)),
         }
     }
 }
 
-impl<'a> TryFrom<Source<'a>> for &'a str {
-    type Error = anyhow::Error;
+impl<'a> TryFrom<Source<'a>> for Relation<'a> {
+    type Error = Error;
 
     fn try_from(source: Source<'a>) -> Result<Self, Self::Error> {
         if source.0.len() != 1 {
-            return Err(anyhow!("We only support single data source at the moment"));
+            let from: Vec<_> = source.0.iter().map(|t| t.to_string()).collect();
+            return Err(Error::unsupported(
+                "We only support single data source at the moment",
+                from.join(", "),
+            ));
         }
 
         let table = &source.0[0];
         if !table.joins.is_empty() {
-            return Err(anyhow!("We do not support joint data source at the moment"));
+            return Err(Error::unsupported(
+                "We do not support joint data source at the moment",
+                &table.joins[0],
+            ));
         }
 
         match &table.relation {
-            TableFactor::Table { name, .. } => Ok(&name.0.first().unwrap().value),
-            _ => Err(anyhow!("We only support table")),
+            // sqlite_scan('file://ref.db', 'country')、read_json('url', path => '$.data')
+            // 或者 PIVOT / UNPIVOT 改写成的 pivot(t, sum(v), k, 'a', 'b')
+            TableFactor::Table { name, args, .. } if !args.is_empty() => {
+                let string = |arg: &'a SqlExpr| match arg {
+                    SqlExpr::Value(SqlValue::SingleQuotedString(s)) => Some(s.as_str()),
+                    SqlExpr::Value(SqlValue::Number(s, _)) => Some(s.as_str()),
+                    _ => None,
+                };
+                let unsupported = |msg: &str| Error::unsupported(msg, &table.relation);
+                // PIVOT 和 TABLESAMPLE 的数据源，可以是 'file://ref.db'.country 这样的数据库表
+                let source_of = |expr: &'a SqlExpr| match expr {
+                    SqlExpr::CompoundIdentifier(ids) if ids.len() == 2 => {
+                        Some((ids[0].value.as_str(), Some(ids[1].value.as_str())))
+                    }
+                    expr => name_of(expr).map(|source| (source, None)),
+                };
+                let mut exprs = Vec::new();
+                let mut named = Vec::new();
+                for arg in args {
+                    match arg {
+                        FunctionArg::Unnamed(expr) => exprs.push(expr),
+                        FunctionArg::Named { name, arg } => named.push((&name.value, string(arg))),
+                    }
+                }
+                let unnamed: Vec<_> = exprs.iter().copied().map(string).collect();
+
+                match name.to_string().to_lowercase().as_str() {
+                    "sqlite_scan" => match (unnamed.as_slice(), named.is_empty()) {
+                        ([Some(file), Some(name)], true) => Ok(Relation::new(*file, Some(*name))),
+                        _ => Err(unsupported(
+                            "sqlite_scan expects sqlite_scan('file', 'table')",
+                        )),
+                    },
+                    "read_json" => {
+                        let source = match unnamed.as_slice() {
+                            [Some(source)] => *source,
+                            _ => {
+                                return Err(unsupported(
+                                    "read_json expects read_json('url', name => 'value', ...)",
+                                ))
+                            }
+                        };
+                        let mut options = JsonOptions::default();
+                        for (name, value) in named {
+                            let value = value.ok_or_else(|| {
+                                unsupported("read_json arguments must be literals")
+                            })?;
+                            options.set(name, value)?;
+                        }
+                        Ok(Relation {
+                            json: Some(options),
+                            ..Relation::new(source, None)
+                        })
+                    }
+                    func @ ("pivot" | "unpivot") if named.is_empty() && exprs.len() >= 3 => {
+                        let (source, table) = source_of(exprs[0])
+                            .ok_or_else(|| unsupported("Can not resolve PIVOT source"))?;
+                        let reshape = if func == "pivot" {
+                            Reshape::pivot(&exprs[1..])?
+                        } else {
+                            Reshape::unpivot(&exprs[1..])?
+                        };
+                        Ok(Relation {
+                            reshape: Some(reshape),
+                            ..Relation::new(source, table)
+                        })
+                    }
+                    "tablesample" if named.is_empty() && !exprs.is_empty() => {
+                        let (source, table) = source_of(exprs[0])
+                            .ok_or_else(|| unsupported("Can not resolve TABLESAMPLE source"))?;
+                        Ok(Relation {
+                            sample: Some(Sample::from_args(&exprs[1..])?),
+                            ..Relation::new(source, table)
+                        })
+                    }
+                    _ => Err(unsupported(
+                        "We only support sqlite_scan, read_json, pivot, unpivot and tablesample as table function",
+                    )),
+                }
+            }
+            // 'file://ref.db'.country 的第二部分是数据库里的表
+            TableFactor::Table { name, .. } => match name.0.as_slice() {
+                [source] => Ok(Relation::new(&source.value, None)),
+                [source, table] => Ok(Relation::new(&source.value, Some(&table.value))),
+                _ => Err(Error::unsupported(
+                    format!("Can not resolve table {}", name),
+                    name,
+                )),
+            },
+            relation => Err(Error::unsupported("We only support table", relation)),
         }
     }
 }
 
 /// 把 SqlParser 的 order by expr 转换成 (列名, 排序方法)
 impl<'a> TryFrom<Order<'a>> for (String, bool) {
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn try_from(o: Order) -> Result<Self, Self::Error> {
         let name = match &o.0.expr {
-            SqlExpr::Identifier(id) => id.to_string(),
+            SqlExpr::Identifier(id) => id.value.clone(),
             expr => {
-                return Err(anyhow!(
-                    "We only support identifier for order by, got {}",
-                    expr
+                return Err(Error::unsupported(
+                    format!("We only support identifier for order by, got {}", expr),
+                    expr,
                 ))
             }
         };
@@ -231,37 +690,58 @@ impl<'a> TryFrom<Order<'a>> for (String, bool) {
 }
 
 /// 把 SqlParser 的 offset expr 转换成 i64
-impl<'a> From<Offset<'a>> for i64 {
-    fn from(offset: Offset) -> Self {
-        match offset.0 {
-            SqlOffset {
-                value: SqlExpr::Value(SqlValue::Number(v, _b)),
-                ..
-            } => v.parse().unwrap_or(0),
-            _ => 0,
+impl<'a> TryFrom<Offset<'a>> for i64 {
+    type Error = Error;
+
+    fn try_from(offset: Offset<'a>) -> Result<Self, Self::Error> {
+        match &offset.0.value {
+            SqlExpr::Value(SqlValue::Number(v, _b)) => Ok(v.parse().unwrap_or(0)),
+            SqlExpr::Identifier(id) if is_placeholder(&id.value) => {
+                match offset.1.param(&id.value)? {
+                    Param::Int(v) if *v >= 0 => Ok(*v),
+                    v => Err(Error::execution(format!(
+                        "Offset {} must be a non-negative integer, got {:?}",
+                        id, v
+                    ))),
+                }
+            }
+            _ => Ok(0),
         }
     }
 }
 
 /// 把 SqlParser 的 Limit expr 转换成 usize
-impl<'a> From<Limit<'a>> for usize {
-    fn from(l: Limit<'a>) -> Self {
+impl<'a> TryFrom<Limit<'a>> for usize {
+    type Error = Error;
+
+    fn try_from(l: Limit<'a>) -> Result<Self, Self::Error> {
         match l.0 {
-            SqlExpr::Value(SqlValue::Number(v, _b)) => v.parse().unwrap_or(usize::MAX),
-            _ => usize::MAX,
+            SqlExpr::Value(SqlValue::Number(v, _b)) => Ok(v.parse().unwrap_or(usize::MAX)),
+            SqlExpr::Identifier(id) if is_placeholder(&id.value) => match l.1.param(&id.value)? {
+                Param::Int(v) if *v >= 0 => Ok(*v as usize),
+                v => Err(Error::execution(format!(
+                    "Limit {} must be a non-negative integer, got {:?}",
+                    id, v
+                ))),
+            },
+            _ => Ok(usize::MAX),
         }
     }
 }
 
 /// 把 SqlParser 的 value 转换成 DataFrame 支持的 LiteralValue
 impl TryFrom<Value> for LiteralValue {
-    type Error = anyhow::Error;
+    type Error = Error;
     fn try_from(v: Value) -> Result<Self, Self::Error> {
         match v.0 {
             SqlValue::Number(v, _) => Ok(LiteralValue::Float64(v.parse().unwrap())),
+            SqlValue::SingleQuotedString(v) => Ok(LiteralValue::Utf8(v)),
             SqlValue::Boolean(v) => Ok(LiteralValue::Boolean(v)),
             SqlValue::Null => Ok(LiteralValue::Null),
-            v => Err(anyhow!("Value {} is not supported", v)),
+            v => Err(Error::unsupported(
+                format!("Value {} is not supported", v),
+                &v,
+            )),
         }
     }
 }
@@ -287,4 +767,121 @@ mod tests {
         assert_eq!(sql.order_by, vec![("c".into(), true)]);
         assert_eq!(sql.selection, vec![col("a"), col("b"), col("c")]);
     }
+
+    #[test]
+    fn calls_volatile_works() {
+        let is_now = |name: &str| name == "now";
+        let volatile = |sql: &str| {
+            let statement = &Parser::parse_sql(&TryDialect, sql).unwrap()[0];
+            calls_volatile(statement, &is_now)
+        };
+        assert!(volatile(
+            "select a from t where ts < date_add(now(), '-1 day')"
+        ));
+        assert!(volatile(
+            "select a from t order by -date_diff('day', ts, NOW())"
+        ));
+        assert!(!volatile("select snow(a) from t"));
+        assert!(!volatile("select 'now()' as now from t"));
+    }
+
+    #[test]
+    fn parse_database_table_works() {
+        let sql = "select a from 'file://ref.db'.country where a > 1";
+        let statement = match &crate::parser::parse(sql).unwrap()[0] {
+            crate::parser::Stmt::Sql(stmt) => stmt.clone(),
+            stmt => panic!("expect query, got {}", stmt),
+        };
+        let sql: Sql = (&statement).try_into().unwrap();
+        assert_eq!(sql.source, "file://ref.db");
+        assert_eq!(sql.table, Some("country"));
+        assert!(sql.predicate.is_some());
+
+        let sql = "select a from sqlite_scan('file://ref.db', 'country')";
+        let statement = &Parser::parse_sql(&TryDialect, sql).unwrap()[0];
+        let sql: Sql = statement.try_into().unwrap();
+        assert_eq!((sql.source, sql.table), ("file://ref.db", Some("country")));
+    }
+
+    #[test]
+    fn parse_pivot_works() {
+        let sql = "select * from 'file://ref.db'.cases pivot (avg(v) for k in ('a', 2021))";
+        let statement = match &crate::parser::parse(sql).unwrap()[0] {
+            crate::parser::Stmt::Sql(stmt) => stmt.clone(),
+            stmt => panic!("expect query, got {}", stmt),
+        };
+        let sql: Sql = (&statement).try_into().unwrap();
+        assert_eq!((sql.source, sql.table), ("file://ref.db", Some("cases")));
+        assert_eq!(
+            sql.reshape,
+            Some(Reshape::Pivot {
+                agg: crate::reshape::PivotAgg::Mean,
+                value: "v".into(),
+                on: "k".into(),
+                values: vec!["a".into(), "2021".into()],
+            })
+        );
+
+        let sql = "select * from t tablesample bernoulli (5) repeatable (1)";
+        let statement = match &crate::parser::parse(sql).unwrap()[0] {
+            crate::parser::Stmt::Sql(stmt) => stmt.clone(),
+            stmt => panic!("expect query, got {}", stmt),
+        };
+        let sql: Sql = (&statement).try_into().unwrap();
+        assert_eq!(sql.source, "t");
+        assert_eq!(sql.sample.map(|s| s.seed), Some(Some(1)));
+
+        let sql = "select * from unpivot(t, v, k)";
+        let statement = &Parser::parse_sql(&TryDialect, sql).unwrap()[0];
+        assert!(Sql::try_from(statement).is_err());
+    }
+
+    #[test]
+    fn parse_sql_with_placeholders_works() {
+        let sql = "select a from t where a > ? and b = :name limit ? offset ?";
+        let statement = match &crate::parser::parse(sql).unwrap()[0] {
+            crate::parser::Stmt::Sql(stmt) => stmt.clone(),
+            stmt => panic!("expect query, got {}", stmt),
+        };
+        // 没有绑定参数时转换失败
+        assert!(Sql::try_from(&statement).is_err());
+
+        let params = Params::positional(vec![5, 3, 10]).bind_named("name", "x");
+        let ctx = Context {
+            params: Some(&params),
+            ..Default::default()
+        };
+        let sql = Sql::with_context(&statement, ctx).unwrap();
+        // `?` 按出现顺序依次对应 $1、$2、$3
+        assert_eq!(sql.limit, Some(3));
+        assert_eq!(sql.offset, Some(10));
+        assert_eq!(
+            sql.condition,
+            Some(col("a").gt(lit(5i64)).and(col("b").eq(lit("x"))))
+        );
+    }
+
+    #[test]
+    fn parse_group_by_works() {
+        let sql = "select time_bucket_gapfill('1 month', d) as m, sum(v) from t group by m";
+        let statement = &Parser::parse_sql(&TryDialect, sql).unwrap()[0];
+        let sql: Sql = statement.try_into().unwrap();
+        assert_eq!(sql.group_by.len(), 1);
+        // 分组键从 selection 里移走了，只剩下聚合
+        assert_eq!(sql.selection, vec![col("v").sum()]);
+        assert_eq!(
+            sql.gap_fill,
+            Some((
+                "m".into(),
+                Interval {
+                    months: 1,
+                    millis: 0
+                }
+            ))
+        );
+
+        let sql = "select a, count(b) from t group by 3";
+        let statement = &Parser::parse_sql(&TryDialect, sql).unwrap()[0];
+        assert!(Sql::try_from(statement).is_err());
+    }
 }
@@ -4,15 +4,20 @@ use sqlparser::dialect::Dialect;
 pub struct TryDialect;
 
 impl Dialect for TryDialect {
+    /// 列名可以用 `"..."` 或者 `` `...` `` 括起来，这样就能包含空格等字符
+    fn is_delimited_identifier_start(&self, ch: char) -> bool {
+        ch == '"' || ch == '`'
+    }
+
     fn is_identifier_start(&self, ch: char) -> bool {
-        ch.is_ascii_lowercase() || ch.is_ascii_uppercase() || ch == '_'
+        ch.is_alphabetic() || ch == '_'
     }
 
+    // 为了直接在 FROM 后面写 url，标识符里允许出现 url 里的字符。
+    // `+` 会和加法冲突，带 `+` 的 url 需要用单引号括起来：FROM 'https://...'
     fn is_identifier_part(&self, ch: char) -> bool {
-        ch.is_ascii_lowercase()
-            || ch.is_ascii_uppercase()
-            || ch.is_ascii_digit()
-            || [':', '/', '?', '&', '=', '-', '_', '.'].contains(&ch)
+        ch.is_alphanumeric()
+            || [':', '/', '?', '&', '=', '-', '_', '.', '%', '#', '~'].contains(&ch)
     }
 }
 
@@ -33,4 +38,10 @@ mod tests {
     fn it_works() {
         assert!(Parser::parse_sql(&TryDialect, &example_sql()).is_ok());
     }
+
+    #[test]
+    fn quoted_and_unicode_identifiers_work() {
+        let sql = "SELECT \"new cases\", `total deaths`, 国家, café FROM https://a.com/~me/data%20v2.csv#s1";
+        assert!(Parser::parse_sql(&TryDialect, sql).is_ok());
+    }
 }
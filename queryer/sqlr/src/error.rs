@@ -0,0 +1,233 @@
+use std::fmt::Write;
+use std::time::Duration;
+use thiserror::Error;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// 出错的 SQL 片段所在的位置，行和列都从 1 开始，列按字符计算
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+    /// 出错片段的字符数，渲染时用来画 `^^^`
+    pub len: usize,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// SQL 语法错误
+    #[error("parse error: {message}")]
+    Parse {
+        message: String,
+        location: Option<Location>,
+    },
+    /// 能解析但我们还不支持的 SQL
+    #[error("unsupported: {message}")]
+    Unsupported {
+        message: String,
+        /// 不支持的 SQL 片段
+        fragment: Option<String>,
+        location: Option<Location>,
+    },
+    /// 获取数据源失败
+    #[error("fetch error: {0}")]
+    Fetch(BoxError),
+    /// 把数据源加载成 DataSet 失败
+    #[error("load error: {0}")]
+    Load(BoxError),
+    /// 执行查询失败
+    #[error("execution error: {0}")]
+    Execution(BoxError),
+    /// 把一行数据反序列化成 Rust 类型失败，row 从 0 开始
+    #[error("failed to deserialize row {row}: {message}")]
+    Deserialize {
+        row: usize,
+        /// 出错的列，缺少的列也会记录在这里
+        column: Option<String>,
+        message: String,
+    },
+    /// 查询超过了设置的超时时间
+    #[error("query timed out after {0:?}")]
+    Timeout(Duration),
+    /// 查询被 [`crate::CancelHandle`] 取消
+    #[error("query cancelled")]
+    Cancelled,
+    /// 数据源或者 COPY 的目标不在允许的列表里
+    #[error("source {0} is not allowed")]
+    NotAllowed(String),
+    /// 超出了资源限制，resource 是 "bytes" 或者 "rows"
+    #[error("{resource} limit exceeded: {limit}")]
+    LimitExceeded { resource: &'static str, limit: u64 },
+    /// 脚本里某一条语句执行失败，index 从 1 开始
+    #[error("statement #{index} failed: {source}")]
+    Statement {
+        index: usize,
+        statement: String,
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    pub fn unsupported(message: impl Into<String>, fragment: impl ToString) -> Self {
+        Self::Unsupported {
+            message: message.into(),
+            fragment: Some(fragment.to_string()),
+            location: None,
+        }
+    }
+
+    pub fn fetch(e: impl Into<BoxError>) -> Self {
+        Self::Fetch(e.into())
+    }
+
+    pub fn load(e: impl Into<BoxError>) -> Self {
+        Self::Load(e.into())
+    }
+
+    pub fn execution(e: impl Into<BoxError>) -> Self {
+        Self::Execution(e.into())
+    }
+
+    /// 出错的位置
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Self::Parse { location, .. } | Self::Unsupported { location, .. } => location.as_ref(),
+            Self::Statement { source, .. } => source.location(),
+            _ => None,
+        }
+    }
+
+    /// 在 sql 里查找不支持的片段，补上位置信息
+    pub(crate) fn locate(self, sql: &str) -> Self {
+        match self {
+            Self::Unsupported {
+                message,
+                fragment: Some(fragment),
+                location: None,
+            } => {
+                let location = find_fragment(sql, &fragment);
+                Self::Unsupported {
+                    message,
+                    fragment: Some(fragment),
+                    location,
+                }
+            }
+            Self::Statement {
+                index,
+                statement,
+                source,
+            } => Self::Statement {
+                index,
+                statement,
+                source: Box::new(source.locate(sql)),
+            },
+            e => e,
+        }
+    }
+
+    /// 把错误渲染成带有源码和 `^` 标记的多行文本，方便在命令行或者 Python 里展示
+    pub fn render(&self, sql: &str) -> String {
+        let mut out = format!("error: {}", self);
+        let location = match self.location() {
+            Some(location) => location,
+            None => return out,
+        };
+        let line = sql.lines().nth(location.line - 1).unwrap_or_default();
+        let gutter = location.line.to_string().len();
+
+        let _ = write!(
+            out,
+            "\n{:>w$}--> line {}, column {}\n{:>w$} |\n{} | {}\n{:>w$} | {}{}",
+            "",
+            location.line,
+            location.column,
+            "",
+            location.line,
+            line,
+            "",
+            " ".repeat(location.column - 1),
+            "^".repeat(location.len.max(1)),
+            w = gutter
+        );
+        out
+    }
+}
+
+/// 按字节偏移计算行列
+pub(crate) fn location_at(sql: &str, offset: usize, len: usize) -> Location {
+    let before = &sql[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    Location {
+        line,
+        column: sql[line_start..offset].chars().count() + 1,
+        len,
+    }
+}
+
+/// 不区分大小写地查找片段第一次出现的位置
+fn find_fragment(sql: &str, fragment: &str) -> Option<Location> {
+    if fragment.is_empty() {
+        return None;
+    }
+    let offset = sql.to_lowercase().find(&fragment.to_lowercase())?;
+    // 大小写转换可能改变字节长度，这时候偏移不可靠，干脆不给位置
+    if !sql.is_char_boundary(offset) || sql.to_lowercase().len() != sql.len() {
+        return None;
+    }
+    Some(location_at(sql, offset, fragment.chars().count()))
+}
+
+impl From<sqlparser::parser::ParserError> for Error {
+    fn from(e: sqlparser::parser::ParserError) -> Self {
+        use sqlparser::parser::ParserError;
+
+        let message = match e {
+            ParserError::TokenizerError(message) | ParserError::ParserError(message) => message,
+        };
+        // 位置在 parser::parse 里补上
+        Self::Parse {
+            message,
+            location: None,
+        }
+    }
+}
+
+impl From<polars::prelude::PolarsError> for Error {
+    fn from(e: polars::prelude::PolarsError) -> Self {
+        Self::Execution(Box::new(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locate_and_render_works() {
+        let sql = "SELECT a,\n  foo(b) FROM t";
+        let err = Error::unsupported("Function foo is not supported", "foo(b)").locate(sql);
+        assert_eq!(
+            err.location(),
+            Some(&Location {
+                line: 2,
+                column: 3,
+                len: 6
+            })
+        );
+        assert_eq!(
+            err.render(sql),
+            "error: unsupported: Function foo is not supported\n \
+             --> line 2, column 3\n  |\n2 |   foo(b) FROM t\n  |   ^^^^^^"
+        );
+    }
+
+    #[test]
+    fn render_without_location_works() {
+        let err = Error::execution("boom");
+        assert_eq!(err.render("SELECT 1"), "error: execution error: boom");
+    }
+}
@@ -0,0 +1,342 @@
+use crate::error::{Error, Result};
+use crate::loader::{unique_names, Load};
+use crate::DataSet;
+use calamine::{DataType as Cell, Ods, Range, Reader, Xls, Xlsb, Xlsx};
+use polars::prelude::*;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// 在前面几行里找表头，跳过表格上方的标题之类的内容
+const HEADER_SEARCH_ROWS: usize = 10;
+
+/// 表格文件的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workbook {
+    Xlsx,
+    /// xlsb 也是 zip，但是内容是二进制的，calamine 用单独的 reader 读取
+    Xlsb,
+    Xls,
+    Ods,
+}
+
+/// 读取表格里的一个 sheet
+///
+/// 地址的 fragment 用来选择 sheet 和单元格范围，比如 `file://report.xlsx#Sheet2!B3:F20`，
+/// 不指定 sheet 时读取第一个，不指定范围时读取所有用到的单元格
+#[derive(Debug)]
+pub struct ExcelLoader {
+    pub(crate) path: PathBuf,
+    pub(crate) workbook: Workbook,
+    pub(crate) sheet: Option<String>,
+    pub(crate) range: Option<CellRange>,
+}
+
+/// `A1:D20` 形式的单元格范围，行列都从 0 开始
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellRange {
+    start: (u32, u32),
+    end: (u32, u32),
+}
+
+impl Workbook {
+    /// 按扩展名判断格式
+    pub fn from_extension(path: &str) -> Option<Self> {
+        let ext = path.rsplit_once('.')?.1.to_lowercase();
+        match ext.as_str() {
+            "xlsx" | "xlsm" => Some(Workbook::Xlsx),
+            "xlsb" => Some(Workbook::Xlsb),
+            "xls" => Some(Workbook::Xls),
+            "ods" => Some(Workbook::Ods),
+            _ => None,
+        }
+    }
+
+    /// 按文件开头的字节判断格式：xlsx 和 ods 都是 zip，ods 的第一个文件是 mimetype
+    pub fn from_magic(header: &[u8]) -> Option<Self> {
+        const ODS_MIMETYPE: &[u8] = b"mimetypeapplication/vnd.oasis.opendocument.spreadsheet";
+        if header.starts_with(b"PK\x03\x04") {
+            match header.get(30..) {
+                Some(rest) if rest.starts_with(ODS_MIMETYPE) => Some(Workbook::Ods),
+                _ => Some(Workbook::Xlsx),
+            }
+        } else if header.starts_with(&[0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1]) {
+            Some(Workbook::Xls)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for CellRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let err = || Error::load(format!("Invalid cell range {}, expect A1:D20", s));
+        let (start, end) = s.split_once(':').ok_or_else(err)?;
+        let start = parse_cell(start).ok_or_else(err)?;
+        let end = parse_cell(end).ok_or_else(err)?;
+        if start.0 > end.0 || start.1 > end.1 {
+            return Err(err());
+        }
+        Ok(Self { start, end })
+    }
+}
+
+/// `B3` 转换成 (行, 列)，都从 0 开始
+fn parse_cell(s: &str) -> Option<(u32, u32)> {
+    let s = s.trim().to_uppercase();
+    let split = s.find(|c: char| c.is_ascii_digit())?;
+    let (letters, digits) = s.split_at(split);
+    if letters.is_empty() || !letters.chars().all(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+    let col = letters.bytes().try_fold(0u32, |acc, b| {
+        acc.checked_mul(26)?.checked_add((b - b'A' + 1) as u32)
+    })?;
+    let row: u32 = digits.parse().ok()?;
+    (row >= 1).then(|| (row - 1, col - 1))
+}
+
+/// 数据源是不是表格，是的话返回去掉 fragment 之后的地址和 loader 的参数
+///
+/// 数据源按扩展名判断，本地文件还会检查 header（用 read_header 读到的文件头）
+pub(crate) fn spreadsheet_source<'a>(
+    url: &'a str,
+    header: Option<&[u8]>,
+) -> Result<Option<(&'a str, Workbook, Option<String>, Option<CellRange>)>> {
+    let (location, fragment) = match url.split_once('#') {
+        Some((location, fragment)) => (location, Some(fragment)),
+        None => (url, None),
+    };
+    let path = location.split('?').next().unwrap_or(location);
+    let workbook = match Workbook::from_extension(path) {
+        Some(workbook) => workbook,
+        None => match header.and_then(Workbook::from_magic) {
+            Some(workbook) => workbook,
+            None => return Ok(None),
+        },
+    };
+
+    let (sheet, range) = match fragment.map(|f| f.split_once('!').unwrap_or((f, ""))) {
+        Some((sheet, range)) => {
+            let sheet = (!sheet.is_empty()).then(|| sheet.to_owned());
+            let range = (!range.is_empty()).then(|| range.parse()).transpose()?;
+            (sheet, range)
+        }
+        None => (None, None),
+    };
+    Ok(Some((location, workbook, sheet, range)))
+}
+
+impl Load for ExcelLoader {
+    type Error = Error;
+
+    fn load(self) -> Result<DataSet, Self::Error> {
+        let sheet = self.sheet.as_deref();
+        let range = match self.workbook {
+            Workbook::Xlsx => worksheet::<Xlsx<_>>(&self.path, sheet)?,
+            Workbook::Xlsb => worksheet::<Xlsb<_>>(&self.path, sheet)?,
+            Workbook::Xls => worksheet::<Xls<_>>(&self.path, sheet)?,
+            Workbook::Ods => worksheet::<Ods<_>>(&self.path, sheet)?,
+        };
+        to_dataframe(&range, self.range).map(DataSet)
+    }
+}
+
+fn worksheet<R>(path: &Path, sheet: Option<&str>) -> Result<Range<Cell>>
+where
+    R: Reader<RS = BufReader<File>>,
+{
+    let file = File::open(path).map_err(Error::load)?;
+    let mut workbook = R::new(BufReader::new(file)).map_err(|e| Error::load(format!("{:?}", e)))?;
+    let names = workbook.sheet_names().to_vec();
+    let name = match sheet {
+        Some(name) => name.to_owned(),
+        None => names
+            .first()
+            .cloned()
+            .ok_or_else(|| Error::load("The workbook has no sheet"))?,
+    };
+    match workbook.worksheet_range(&name) {
+        Some(range) => range.map_err(|e| Error::load(format!("{:?}", e))),
+        None => Err(Error::load(format!(
+            "Sheet {} not found, available sheets: {}",
+            name,
+            names.join(", ")
+        ))),
+    }
+}
+
+/// 取出范围内的单元格，去掉整行为空的行，找到表头后按列生成 Series
+fn to_dataframe(range: &Range<Cell>, cells: Option<CellRange>) -> Result<DataFrame> {
+    let bounds = match (cells, range.start(), range.end()) {
+        (Some(cells), _, _) => cells,
+        (None, Some(start), Some(end)) => CellRange { start, end },
+        // 空的 sheet
+        _ => return Ok(DataFrame::new(Vec::<Series>::new())?),
+    };
+
+    let rows: Vec<Vec<&Cell>> = (bounds.start.0..=bounds.end.0)
+        .map(|row| {
+            (bounds.start.1..=bounds.end.1)
+                .map(|col| range.get_value((row, col)).unwrap_or(&Cell::Empty))
+                .collect()
+        })
+        .filter(|row: &Vec<&Cell>| row.iter().any(|cell| !is_empty(cell)))
+        .collect();
+
+    let width = (bounds.end.1 - bounds.start.1 + 1) as usize;
+    let (names, rows) = match find_header(&rows) {
+        Some(i) => (
+            unique_names(rows[i].iter().map(|c| c.to_string())),
+            &rows[i + 1..],
+        ),
+        None => (unique_names(vec![String::new(); width]), &rows[..]),
+    };
+
+    let columns = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| to_series(name, rows.iter().map(|row| row[i])))
+        .collect::<Result<_>>()?;
+    Ok(DataFrame::new(columns)?)
+}
+
+/// 表头是前几行里第一个所有单元格都是非空文本的行
+fn find_header(rows: &[Vec<&Cell>]) -> Option<usize> {
+    rows.iter().take(HEADER_SEARCH_ROWS).position(|row| {
+        row.iter()
+            .all(|cell| matches!(cell, Cell::String(s) if !s.trim().is_empty()))
+    })
+}
+
+fn is_empty(cell: &Cell) -> bool {
+    match cell {
+        Cell::Empty | Cell::Error(_) => true,
+        Cell::String(s) => s.trim().is_empty(),
+        _ => false,
+    }
+}
+
+/// 按单元格的类型决定列的类型：整数、小数、布尔值、日期，混合的类型都转成文本。
+/// 空单元格和错误（比如 #N/A）是 null
+fn to_series<'a>(name: &str, cells: impl Iterator<Item = &'a Cell> + Clone) -> Result<Series> {
+    let values = || cells.clone().filter(|cell| !is_empty(cell));
+    let has_value = values().next().is_some();
+    let all = |f: fn(&Cell) -> bool| has_value && values().all(f);
+
+    if all(|c| matches!(c, Cell::Int(_)) || matches!(c, Cell::Float(v) if v.fract() == 0.0)) {
+        let values: Vec<_> = cells
+            .map(|cell| match cell {
+                Cell::Int(v) => Some(*v),
+                Cell::Float(v) => Some(*v as i64),
+                _ => None,
+            })
+            .collect();
+        return Ok(Series::new(name, values));
+    }
+    if all(|c| matches!(c, Cell::Int(_) | Cell::Float(_))) {
+        let values: Vec<_> = cells
+            .map(|cell| match cell {
+                Cell::Int(v) => Some(*v as f64),
+                Cell::Float(v) => Some(*v),
+                _ => None,
+            })
+            .collect();
+        return Ok(Series::new(name, values));
+    }
+    if all(|c| matches!(c, Cell::Bool(_))) {
+        let values: Vec<_> = cells.map(|cell| cell.get_bool()).collect();
+        return Ok(Series::new(name, values));
+    }
+    if all(|c| matches!(c, Cell::DateTime(_))) {
+        // Excel 的日期是从 1899-12-30 开始的天数
+        let values: Vec<_> = cells
+            .map(|cell| match cell {
+                Cell::DateTime(v) => Some(((v - 25569.0) * 86_400_000.0).round() as i64),
+                _ => None,
+            })
+            .collect();
+        let ca = Int64Chunked::new_from_opt_slice(name, &values).cast::<Date64Type>()?;
+        return Ok(ca.into_series());
+    }
+
+    let values: Vec<_> = cells
+        .map(|cell| (!is_empty(cell)).then(|| cell.to_string()))
+        .collect();
+    let values: Vec<_> = values.iter().map(|v| v.as_deref()).collect();
+    Ok(Series::new(name, values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cell_range_works() {
+        let range: CellRange = "B3:AA20".parse().unwrap();
+        assert_eq!(range.start, (2, 1));
+        assert_eq!(range.end, (19, 26));
+        assert!("B3".parse::<CellRange>().is_err());
+        assert!("D3:B1".parse::<CellRange>().is_err());
+        assert!("3B:D4".parse::<CellRange>().is_err());
+    }
+
+    #[test]
+    fn spreadsheet_source_works() {
+        let (location, workbook, sheet, range) =
+            spreadsheet_source("https://a.com/report.xlsx#Sheet 2!A1:C10", None)
+                .unwrap()
+                .unwrap();
+        assert_eq!(location, "https://a.com/report.xlsx");
+        assert_eq!(workbook, Workbook::Xlsx);
+        assert_eq!(sheet.as_deref(), Some("Sheet 2"));
+        assert_eq!(range, Some("A1:C10".parse().unwrap()));
+
+        let (_, workbook, sheet, range) = spreadsheet_source("https://a.com/report.ods", None)
+            .unwrap()
+            .unwrap();
+        assert_eq!((workbook, sheet, range), (Workbook::Ods, None, None));
+        let (_, workbook, _, _) = spreadsheet_source("https://a.com/report.XLSB", None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(workbook, Workbook::Xlsb);
+        assert!(spreadsheet_source("https://a.com/report.csv", None)
+            .unwrap()
+            .is_none());
+        // 本地文件没有扩展名时看文件头
+        let (_, workbook, _, _) = spreadsheet_source("file:///tmp/report", Some(b"PK\x03\x04"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(workbook, Workbook::Xlsx);
+    }
+
+    #[test]
+    fn to_dataframe_detects_header_and_types() {
+        let mut range = Range::new((0, 0), (4, 2));
+        range.set_value((0, 0), Cell::String("Quarterly report".into()));
+        for (col, name) in ["name", "cases", "active"].iter().enumerate() {
+            range.set_value((1, col as u32), Cell::String(name.to_string()));
+        }
+        range.set_value((2, 0), Cell::String("a".into()));
+        range.set_value((2, 1), Cell::Float(1.0));
+        range.set_value((2, 2), Cell::Bool(true));
+        range.set_value((4, 0), Cell::String("b".into()));
+        range.set_value((4, 1), Cell::Float(2.5));
+        range.set_value((4, 2), Cell::Error(calamine::CellErrorType::NA));
+
+        let df = to_dataframe(&range, None).unwrap();
+        assert_eq!(df.get_column_names(), vec!["name", "cases", "active"]);
+        assert_eq!(df.height(), 2);
+        assert_eq!(df.column("cases").unwrap().dtype(), &DataType::Float64);
+        assert_eq!(df.column("active").unwrap().dtype(), &DataType::Boolean);
+        assert_eq!(df.column("active").unwrap().null_count(), 1);
+
+        // 范围里没有表头时用生成的列名
+        let df = to_dataframe(&range, Some("B3:B5".parse().unwrap())).unwrap();
+        assert_eq!(df.get_column_names(), vec!["column_1"]);
+        assert_eq!(df.height(), 2);
+    }
+}
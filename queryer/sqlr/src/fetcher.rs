@@ -1,43 +1,375 @@
-use anyhow::{anyhow, Result};
+use crate::error::{Error, Result};
 use async_trait::async_trait;
-use tokio::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+#[cfg(feature = "native")]
+use {
+    std::sync::OnceLock,
+    std::time::UNIX_EPOCH,
+    tempfile::NamedTempFile,
+    tokio::fs,
+    tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    tracing::Span,
+};
 
 // Rust 的 async trait 还没有稳定，可以用 async_trait 宏
 #[async_trait]
 pub trait Fetch {
     type Error;
     async fn fetch(&self) -> Result<String, Self::Error>;
+    /// 把数据落地到磁盘上，避免在内存里保存整个数据源
+    async fn spill(&self) -> Result<Spilled, Self::Error>;
+}
+
+/// 检查地址是否允许访问，http 数据源每次重定向之后都会用它检查新的地址
+pub type Check<'a> = &'a (dyn Fn(&str) -> Result<()> + Sync);
+
+/// 落地到磁盘上的数据源，临时文件在 drop 时删除
+#[derive(Debug)]
+pub enum Spilled {
+    Local(PathBuf),
+    #[cfg(feature = "native")]
+    Temp(NamedTempFile),
 }
 
-/// 从文件源或者 http 源中获取数据，组成 data frame
-pub async fn retrieve_data(source: impl AsRef<str>) -> Result<String> {
+impl Spilled {
+    pub fn path(&self) -> &Path {
+        match self {
+            Spilled::Local(path) => path,
+            #[cfg(feature = "native")]
+            Spilled::Temp(file) => file.path(),
+        }
+    }
+}
+
+/// 不支持的数据源。wasm 里没有文件系统和 reqwest，只能查询注册的内存数据
+fn unsupported_source(source: &str) -> Error {
+    let msg = if cfg!(feature = "native") {
+        "We only support http/https/file at the moment"
+    } else {
+        "Only registered tables are supported in WebAssembly"
+    };
+    Error::unsupported(msg, source)
+}
+
+/// 从文件源或者 http 源中获取数据，组成 data frame，max_bytes 限制最多读取的字节数
+#[cfg_attr(not(feature = "native"), allow(unused_variables))]
+pub async fn retrieve_data(
+    source: impl AsRef<str>,
+    max_bytes: Option<u64>,
+    check: Check<'_>,
+) -> Result<String> {
     let name = source.as_ref();
-    match &name[..4] {
+    match name {
         // 包括 http / https
-        "http" => UrlFetcher(name).fetch().await,
+        #[cfg(feature = "native")]
+        _ if name.starts_with("http") => UrlFetcher(name, max_bytes, check).fetch().await,
         // 处理 file://<filename>
-        "file" => FileFetcher(name).fetch().await,
-        _ => Err(anyhow!("We only support http/https/file at the moment")),
+        #[cfg(feature = "native")]
+        _ if name.starts_with("file://") => FileFetcher(name, max_bytes).fetch().await,
+        _ => Err(unsupported_source(name)),
+    }
+}
+
+/// 和 retrieve_data 一样，但数据落地到磁盘，而不是读成一个 String
+#[cfg_attr(not(feature = "native"), allow(unused_variables))]
+pub async fn spill_data(
+    source: impl AsRef<str>,
+    max_bytes: Option<u64>,
+    check: Check<'_>,
+) -> Result<Spilled> {
+    let name = source.as_ref();
+    match name {
+        #[cfg(feature = "native")]
+        _ if name.starts_with("http") => UrlFetcher(name, max_bytes, check).spill().await,
+        #[cfg(feature = "native")]
+        _ if name.starts_with("file://") => FileFetcher(name, max_bytes).spill().await,
+        _ => Err(unsupported_source(name)),
+    }
+}
+
+/// 等待 HEAD 请求的最长时间，查询本身的超时更短时用查询的超时
+#[cfg(feature = "native")]
+const VALIDATOR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 数据源当前版本的校验值：文件用大小和修改时间，http 用 HEAD 返回的 ETag 或者 Last-Modified
+///
+/// 拿不到（包括 HEAD 请求超时）的时候返回空字符串，这时只能靠结果缓存的过期时间发现数据源的变化
+#[cfg(not(feature = "native"))]
+pub(crate) async fn validator(_source: &str, _timeout: Option<Duration>) -> String {
+    String::new()
+}
+
+#[cfg(feature = "native")]
+pub(crate) async fn validator(source: &str, timeout: Option<Duration>) -> String {
+    let source = source.split('#').next().unwrap_or(source);
+    if let Some(path) = source.strip_prefix("file://") {
+        return match fs::metadata(path).await {
+            Ok(meta) => {
+                let modified = meta
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                format!("{}:{}", meta.len(), modified.as_nanos())
+            }
+            Err(_) => String::new(),
+        };
     }
+    if !source.starts_with("http") {
+        return String::new();
+    }
+    let timeout = timeout.map_or(VALIDATOR_TIMEOUT, |t| t.min(VALIDATOR_TIMEOUT));
+    match client().head(source).timeout(timeout).send().await {
+        Ok(resp) => {
+            let headers = resp.headers();
+            headers
+                .get(reqwest::header::ETAG)
+                .or_else(|| headers.get(reqwest::header::LAST_MODIFIED))
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_owned()
+        }
+        Err(_) => String::new(),
+    }
+}
+
+/// 边下载边按行读取的数据源，读够了就可以停下来，剩下的部分不会被下载
+pub(crate) enum Body {
+    Reader(Box<dyn AsyncBufRead + Send + Unpin>),
+    #[cfg(feature = "native")]
+    Http {
+        resp: reqwest::Response,
+        /// 已经下载但还没有读取的部分
+        pending: Vec<u8>,
+        read: u64,
+        max_bytes: Option<u64>,
+    },
 }
 
-struct UrlFetcher<'a>(pub(crate) &'a str);
-struct FileFetcher<'a>(pub(crate) &'a str);
+/// 和 retrieve_data 一样，但不会一次读取整个数据源
+#[cfg_attr(not(feature = "native"), allow(unused_variables))]
+pub(crate) async fn open_body(
+    source: &str,
+    max_bytes: Option<u64>,
+    check: Check<'_>,
+) -> Result<Body> {
+    match source {
+        #[cfg(feature = "native")]
+        _ if source.starts_with("http") => Ok(Body::Http {
+            resp: UrlFetcher(source, max_bytes, check).get().await?,
+            pending: Vec::new(),
+            read: 0,
+            max_bytes,
+        }),
+        #[cfg(feature = "native")]
+        _ if source.starts_with("file://") => {
+            FileFetcher(source, max_bytes).check_size().await?;
+            Body::open_file(&source[7..]).await
+        }
+        _ => Err(unsupported_source(source)),
+    }
+}
+
+/// 判断文件格式时读取的开头的字节数
+const HEADER_LEN: u64 = 128;
+
+/// 本地文件开头的字节，用来判断文件格式，不是本地文件或者读取失败时返回 None
+#[cfg(feature = "native")]
+pub(crate) async fn read_header(source: &str) -> Option<Vec<u8>> {
+    let path = source.strip_prefix("file://")?;
+    let file = fs::File::open(path).await.ok()?;
+    let mut header = Vec::with_capacity(HEADER_LEN as usize);
+    file.take(HEADER_LEN).read_to_end(&mut header).await.ok()?;
+    Some(header)
+}
+
+#[cfg(not(feature = "native"))]
+pub(crate) async fn read_header(_source: &str) -> Option<Vec<u8>> {
+    None
+}
 
+/// 把数据写到本地文件，用于 COPY ... TO
+#[cfg(feature = "native")]
+pub(crate) async fn write_file(path: &str, data: Vec<u8>) -> Result<()> {
+    fs::write(path, data).await.map_err(Error::execution)
+}
+
+#[cfg(not(feature = "native"))]
+pub(crate) async fn write_file(path: &str, _data: Vec<u8>) -> Result<()> {
+    Err(unsupported_source(path))
+}
+
+impl Body {
+    #[cfg(feature = "native")]
+    pub(crate) async fn open_file(path: impl AsRef<Path>) -> Result<Body> {
+        let file = fs::File::open(path).await.map_err(Error::fetch)?;
+        Ok(Body::Reader(Box::new(BufReader::new(file))))
+    }
+
+    #[cfg(not(feature = "native"))]
+    pub(crate) async fn open_file(path: impl AsRef<Path>) -> Result<Body> {
+        Err(unsupported_source(&path.as_ref().display().to_string()))
+    }
+
+    /// 读取一行追加到 buf，返回读取的字节数，读完时返回 0
+    pub(crate) async fn read_line(&mut self, buf: &mut String) -> Result<usize> {
+        match self {
+            Body::Reader(reader) => reader.read_line(buf).await.map_err(Error::fetch),
+            #[cfg(feature = "native")]
+            Body::Http {
+                resp,
+                pending,
+                read,
+                max_bytes,
+            } => loop {
+                let end = match pending.iter().position(|b| *b == b'\n') {
+                    Some(i) => i + 1,
+                    None => match resp.chunk().await.map_err(Error::fetch)? {
+                        Some(chunk) => {
+                            *read += chunk.len() as u64;
+                            check_bytes(*read, *max_bytes)?;
+                            pending.extend_from_slice(&chunk);
+                            continue;
+                        }
+                        None => pending.len(),
+                    },
+                };
+                // 换行符不会出现在 UTF-8 多字节字符的中间，按行切分总是完整的字符
+                let line: Vec<u8> = pending.drain(..end).collect();
+                buf.push_str(std::str::from_utf8(&line).map_err(Error::fetch)?);
+                return Ok(line.len());
+            },
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+struct UrlFetcher<'a>(
+    pub(crate) &'a str,
+    pub(crate) Option<u64>,
+    pub(crate) Check<'a>,
+);
+#[cfg(feature = "native")]
+struct FileFetcher<'a>(pub(crate) &'a str, pub(crate) Option<u64>);
+
+/// 检查已经读取的字节数是否超出限制
+#[cfg(feature = "native")]
+fn check_bytes(len: u64, max_bytes: Option<u64>) -> Result<()> {
+    match max_bytes {
+        Some(limit) if len > limit => Err(Error::LimitExceeded {
+            resource: "bytes",
+            limit,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// 最多跟随的重定向次数
+#[cfg(feature = "native")]
+const MAX_REDIRECTS: usize = 10;
+
+/// 建立连接的最长时间，下载本身的时间由查询的超时限制
+#[cfg(feature = "native")]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 所有 http 请求共用的客户端，不自动跟随重定向
+#[cfg(feature = "native")]
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .unwrap_or_default()
+    })
+}
+
+#[cfg(feature = "native")]
+impl<'a> UrlFetcher<'a> {
+    /// 自己跟随重定向，每一跳都要检查，免得允许的主机把请求重定向到内网的地址
+    async fn get(&self) -> Result<reqwest::Response> {
+        let mut url = reqwest::Url::parse(self.0).map_err(Error::fetch)?;
+        for _ in 0..=MAX_REDIRECTS {
+            let resp = client()
+                .get(url.clone())
+                .send()
+                .await
+                .map_err(Error::fetch)?;
+            let location = match resp.headers().get(reqwest::header::LOCATION) {
+                Some(location) if resp.status().is_redirection() => location,
+                _ => {
+                    Span::current().record("status", &u64::from(resp.status().as_u16()));
+                    // 服务器告诉了我们大小的话，不用下载就知道超出了限制
+                    check_bytes(resp.content_length().unwrap_or(0), self.1)?;
+                    return Ok(resp);
+                }
+            };
+            let location = location.to_str().map_err(Error::fetch)?;
+            url = url.join(location).map_err(Error::fetch)?;
+            (self.2)(url.as_str())?;
+        }
+        Err(Error::fetch(format!("Too many redirects from {}", self.0)))
+    }
+}
+
+#[cfg(feature = "native")]
 #[async_trait]
 impl<'a> Fetch for UrlFetcher<'a> {
-    type Error = anyhow::Error;
+    type Error = Error;
 
     async fn fetch(&self) -> Result<String, Self::Error> {
-        Ok(reqwest::get(self.0).await?.text().await?)
+        let mut resp = self.get().await?;
+        let mut buf = Vec::new();
+        while let Some(chunk) = resp.chunk().await.map_err(Error::fetch)? {
+            buf.extend_from_slice(&chunk);
+            check_bytes(buf.len() as u64, self.1)?;
+        }
+        String::from_utf8(buf).map_err(Error::fetch)
+    }
+
+    async fn spill(&self) -> Result<Spilled, Self::Error> {
+        let mut resp = self.get().await?;
+        let temp = NamedTempFile::new().map_err(Error::fetch)?;
+        let mut file = fs::File::from_std(temp.reopen().map_err(Error::fetch)?);
+        // 按块写入临时文件，不需要把整个 body 放在内存里
+        let mut written = 0;
+        while let Some(chunk) = resp.chunk().await.map_err(Error::fetch)? {
+            written += chunk.len() as u64;
+            check_bytes(written, self.1)?;
+            file.write_all(&chunk).await.map_err(Error::fetch)?;
+        }
+        file.flush().await.map_err(Error::fetch)?;
+        Ok(Spilled::Temp(temp))
     }
 }
 
+#[cfg(feature = "native")]
+impl<'a> FileFetcher<'a> {
+    async fn check_size(&self) -> Result<()> {
+        if self.1.is_some() {
+            let meta = fs::metadata(&self.0[7..]).await.map_err(Error::fetch)?;
+            check_bytes(meta.len(), self.1)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "native")]
 #[async_trait]
 impl<'a> Fetch for FileFetcher<'a> {
-    type Error = anyhow::Error;
+    type Error = Error;
 
     async fn fetch(&self) -> Result<String, Self::Error> {
-        Ok(fs::read_to_string(&self.0[7..]).await?)
+        self.check_size().await?;
+        fs::read_to_string(&self.0[7..]).await.map_err(Error::fetch)
+    }
+
+    async fn spill(&self) -> Result<Spilled, Self::Error> {
+        self.check_size().await?;
+        Ok(Spilled::Local(PathBuf::from(&self.0[7..])))
     }
 }
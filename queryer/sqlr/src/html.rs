@@ -0,0 +1,349 @@
+use crate::error::{Error, Result};
+use crate::loader::{unique_names, Load};
+use crate::DataSet;
+use polars::prelude::*;
+use scraper::{ElementRef, Html, Selector};
+use std::fmt;
+
+/// colspan / rowspan 的上限，防止恶意的页面生成巨大的表格
+const MAX_SPAN: usize = 1000;
+
+/// 读取 HTML 页面里的一个 `<table>`
+#[derive(Debug)]
+pub struct HtmlLoader {
+    pub(crate) data: String,
+    pub(crate) table: TableSelector,
+}
+
+/// 选择页面里的哪个表格：`#table=2` 是第二个表格，`#population` 是 id 为 population 的表格
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TableSelector {
+    /// 从 1 开始的序号
+    Index(usize),
+    Id(String),
+}
+
+impl Default for TableSelector {
+    fn default() -> Self {
+        TableSelector::Index(1)
+    }
+}
+
+impl TableSelector {
+    pub fn from_fragment(fragment: &str) -> Self {
+        let value = fragment.strip_prefix("table=").unwrap_or(fragment);
+        match value.parse() {
+            Ok(i) if i >= 1 => TableSelector::Index(i),
+            _ => TableSelector::Id(value.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for TableSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableSelector::Index(i) => write!(f, "table={}", i),
+            TableSelector::Id(id) => write!(f, "#{}", id),
+        }
+    }
+}
+
+/// 内容是不是 HTML 页面
+pub fn is_html(data: &str) -> bool {
+    let head: String = data.trim_start().chars().take(1024).collect();
+    let head = head.to_lowercase();
+    head.starts_with("<!doctype html") || head.starts_with("<html") || head.contains("<table")
+}
+
+impl Load for HtmlLoader {
+    type Error = Error;
+
+    fn load(self) -> Result<DataSet, Self::Error> {
+        let document = Html::parse_document(&self.data);
+        let selector = Selector::parse("table").expect("valid selector");
+        let mut tables = document.select(&selector);
+        let table = match &self.table {
+            TableSelector::Index(i) => i.checked_sub(1).and_then(|i| tables.nth(i)),
+            TableSelector::Id(id) => tables.find(|t| t.value().id() == Some(id.as_str())),
+        }
+        .ok_or_else(|| Error::load(format!("Can not find {} in the page", self.table)))?;
+        to_dataframe(table).map(DataSet)
+    }
+}
+
+/// 展开 colspan 和 rowspan 之前的单元格
+#[derive(Debug, Clone, Default)]
+struct Cell {
+    text: String,
+    is_header: bool,
+    colspan: usize,
+    rowspan: usize,
+}
+
+fn to_dataframe(table: ElementRef) -> Result<DataFrame> {
+    let (head, body) = table_rows(table);
+    let header_rows = head.len();
+    let mut grid = expand(head.into_iter().chain(body).collect());
+
+    // 没有 thead 时，全是 th 的第一行是表头
+    let header_rows = match grid.first() {
+        Some(row) if header_rows == 0 && row.iter().all(|c| c.is_header) => 1,
+        _ => header_rows,
+    };
+    let width = grid.iter().map(|row| row.len()).max().unwrap_or(0);
+    for row in grid.iter_mut() {
+        row.resize(width, Cell::default());
+    }
+    let (header, body) = grid.split_at(header_rows);
+    let body: Vec<_> = body
+        .iter()
+        .filter(|row| row.iter().any(|c| !c.text.is_empty()))
+        .collect();
+
+    // 多行表头按列把文本连起来，比如 "Population 2020"
+    let names = (0..width).map(|col| {
+        let mut parts: Vec<&str> = Vec::new();
+        for row in header {
+            let text = row[col].text.as_str();
+            if !text.is_empty() && !parts.contains(&text) {
+                parts.push(text);
+            }
+        }
+        parts.join(" ")
+    });
+
+    let columns = unique_names(names)
+        .iter()
+        .enumerate()
+        .map(|(col, name)| {
+            let values: Vec<_> = body
+                .iter()
+                .map(|row| Some(row[col].text.as_str()).filter(|v| !is_null(v)))
+                .collect();
+            to_series(name, &values)
+        })
+        .collect();
+    Ok(DataFrame::new(columns)?)
+}
+
+/// thead 里的行和其它的行，不包括嵌套的表格
+fn table_rows(table: ElementRef) -> (Vec<Vec<Cell>>, Vec<Vec<Cell>>) {
+    let mut head = Vec::new();
+    let mut body = Vec::new();
+    for child in children(table) {
+        match child.value().name() {
+            "thead" => head.extend(children(child).filter(is_row).map(cells)),
+            "tbody" | "tfoot" => body.extend(children(child).filter(is_row).map(cells)),
+            "tr" => body.push(cells(child)),
+            _ => {}
+        }
+    }
+    (head, body)
+}
+
+fn children(element: ElementRef) -> impl Iterator<Item = ElementRef> {
+    element.children().filter_map(ElementRef::wrap)
+}
+
+fn is_row(element: &ElementRef) -> bool {
+    element.value().name() == "tr"
+}
+
+fn cells(row: ElementRef) -> Vec<Cell> {
+    let span = |cell: &ElementRef, name: &str| {
+        cell.value()
+            .attr(name)
+            .and_then(|v| v.trim().parse().ok())
+            .filter(|n: &usize| *n >= 1)
+            .unwrap_or(1)
+            .min(MAX_SPAN)
+    };
+    children(row)
+        .filter(|cell| matches!(cell.value().name(), "td" | "th"))
+        .map(|cell| Cell {
+            text: cell
+                .text()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" "),
+            is_header: cell.value().name() == "th",
+            colspan: span(&cell, "colspan"),
+            rowspan: span(&cell, "rowspan"),
+        })
+        .collect()
+}
+
+/// 展开 colspan 和 rowspan：跨列的单元格重复放在每一列，跨行的单元格重复放在下面的行
+fn expand(rows: Vec<Vec<Cell>>) -> Vec<Vec<Cell>> {
+    // 每一列还要向下占用几行，以及占用的单元格
+    let mut spans: Vec<(usize, Cell)> = Vec::new();
+    let mut grid = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        let mut out: Vec<Cell> = Vec::new();
+        let mut cells = row.into_iter().peekable();
+        loop {
+            let col = out.len();
+            if let Some((remaining, cell)) = spans.get_mut(col).filter(|(n, _)| *n > 0) {
+                *remaining -= 1;
+                out.push(cell.clone());
+                continue;
+            }
+            match cells.next() {
+                Some(cell) => {
+                    for _ in 0..cell.colspan {
+                        let col = out.len();
+                        if spans.len() <= col {
+                            spans.resize(col + 1, (0, Cell::default()));
+                        }
+                        spans[col] = (cell.rowspan - 1, cell.clone());
+                        out.push(cell.clone());
+                    }
+                }
+                // 后面还有上一行跨下来的单元格，中间空出来的位置补上空的单元格
+                None if spans.iter().skip(col).any(|(n, _)| *n > 0) => out.push(Cell::default()),
+                None => break,
+            }
+        }
+        grid.push(out);
+    }
+    grid
+}
+
+fn is_null(text: &str) -> bool {
+    matches!(
+        text.to_lowercase().as_str(),
+        "" | "-" | "–" | "—" | ".." | "n/a" | "na"
+    )
+}
+
+/// 解析带格式的数字：千分位、货币符号、百分号、括号表示的负数和 `[1]` 这样的脚注。
+/// 返回数字以及它是不是整数
+fn parse_number(text: &str) -> Option<(f64, bool)> {
+    let mut text = text.trim();
+    while let (true, Some(i)) = (text.ends_with(']'), text.rfind('[')) {
+        text = text[..i].trim_end();
+    }
+    let negative = text.starts_with('(') && text.ends_with(')');
+    let cleaned: String = text
+        .chars()
+        .filter(|c| {
+            !matches!(
+                c,
+                ',' | ' ' | '\u{a0}' | '\u{202f}' | '$' | '€' | '£' | '¥' | '%' | '(' | ')'
+            )
+        })
+        .map(|c| if c == '−' { '-' } else { c })
+        .collect();
+    let is_numeric = cleaned.chars().any(|c| c.is_ascii_digit())
+        && cleaned
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E'));
+    if !is_numeric {
+        return None;
+    }
+    let v: f64 = cleaned.parse().ok()?;
+    let is_int = !cleaned.contains(|c| matches!(c, '.' | 'e' | 'E'));
+    Some((if negative { -v } else { v }, is_int))
+}
+
+/// 所有的值都是数字时转换成 Int64 或者 Float64，否则保留文本
+fn to_series(name: &str, values: &[Option<&str>]) -> Series {
+    let numbers: Option<Vec<_>> = values
+        .iter()
+        .map(|v| match v {
+            Some(v) => parse_number(v).map(Some),
+            None => Some(None),
+        })
+        .collect();
+
+    match numbers {
+        Some(numbers) if numbers.iter().any(|n| n.is_some()) => {
+            if numbers.iter().flatten().all(|(_, is_int)| *is_int) {
+                let values: Vec<_> = numbers.iter().map(|n| n.map(|(v, _)| v as i64)).collect();
+                Series::new(name, values)
+            } else {
+                let values: Vec<_> = numbers.iter().map(|n| n.map(|(v, _)| v)).collect();
+                Series::new(name, values)
+            }
+        }
+        _ => Series::new(name, values),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"<!DOCTYPE html>
+<html><body>
+<table id="menu"><tr><td>home</td></tr></table>
+<table id="population">
+  <thead>
+    <tr><th rowspan="2">Country</th><th colspan="2">Population</th></tr>
+    <tr><th>2020</th><th>2021</th></tr>
+  </thead>
+  <tbody>
+    <tr><td>China</td><td>1,402,112,000</td><td>1,412,360,000[1]</td></tr>
+    <tr><td>Iceland</td><td>366,425</td><td>—</td></tr>
+    <tr><td rowspan="2">Island</td><td>(1,000)</td><td>2.5%</td></tr>
+    <tr><td>7</td><td>8</td></tr>
+  </tbody>
+</table>
+</body></html>"#;
+
+    fn load(fragment: &str) -> Result<DataSet> {
+        HtmlLoader {
+            data: PAGE.into(),
+            table: TableSelector::from_fragment(fragment),
+        }
+        .load()
+    }
+
+    #[test]
+    fn html_table_works() {
+        assert!(is_html(PAGE));
+        assert!(!is_html("a,b\n1,2\n"));
+
+        let ds = load("population").unwrap();
+        assert_eq!(
+            ds.get_column_names(),
+            vec!["Country", "Population 2020", "Population 2021"]
+        );
+        assert_eq!(ds.height(), 4);
+        assert_eq!(
+            ds.column("Population 2020").unwrap().dtype(),
+            &DataType::Int64
+        );
+        assert_eq!(
+            ds.column("Population 2020").unwrap().sum::<i64>(),
+            Some(1_402_477_432)
+        );
+        assert_eq!(
+            ds.column("Population 2021").unwrap().dtype(),
+            &DataType::Float64
+        );
+        assert_eq!(ds.column("Population 2021").unwrap().null_count(), 1);
+        // 跨行的单元格在下一行重复
+        let country = ds.column("Country").unwrap().utf8().unwrap();
+        assert_eq!(country.get(3), Some("Island"));
+
+        assert_eq!(load("table=2").unwrap().height(), 4);
+        let ds = load("table=1").unwrap();
+        assert_eq!(ds.get_column_names(), vec!["column_1"]);
+        assert!(matches!(load("table=3"), Err(Error::Load(_))));
+    }
+
+    #[test]
+    fn parse_number_works() {
+        assert_eq!(parse_number("1,234"), Some((1234.0, true)));
+        assert_eq!(parse_number("$1,234.50"), Some((1234.5, false)));
+        assert_eq!(parse_number("(12)"), Some((-12.0, true)));
+        assert_eq!(parse_number("−3 [note 2]"), Some((-3.0, true)));
+        assert_eq!(parse_number("12.5%"), Some((12.5, false)));
+        assert_eq!(parse_number("inf"), None);
+        assert_eq!(parse_number("2021-01-01"), None);
+    }
+}
@@ -0,0 +1,445 @@
+use crate::error::{Error, Result};
+use crate::fetcher::retrieve_data;
+use polars::prelude::*;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use tracing::info;
+use url::Url;
+
+/// 最多跟随的页数，防止分页的链接形成循环
+const DEFAULT_MAX_PAGES: usize = 100;
+
+/// `read_json('url', path => '$.data.items', ...)` 的参数
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonOptions {
+    /// 记录所在的位置，指向数组时每个元素是一行，指向对象时只有一行
+    pub(crate) path: JsonPath,
+    /// 需要展开的数组列，数组里的每个元素生成一行
+    pub(crate) explode: Vec<String>,
+    /// 下一页链接在返回内容里的位置
+    pub(crate) next: Option<JsonPath>,
+    /// 页码参数的名字，每次加一，直到某一页没有记录
+    pub(crate) page: Option<String>,
+    pub(crate) max_pages: usize,
+}
+
+/// `$.data.items[0]['a b']` 形式的路径
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JsonPath(Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl Default for JsonOptions {
+    fn default() -> Self {
+        Self {
+            path: JsonPath::default(),
+            explode: Vec::new(),
+            next: None,
+            page: None,
+            max_pages: DEFAULT_MAX_PAGES,
+        }
+    }
+}
+
+impl JsonOptions {
+    /// 设置一个命名参数
+    pub(crate) fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        match name.to_lowercase().as_str() {
+            "path" => self.path = value.parse()?,
+            "explode" => {
+                self.explode = value
+                    .split(',')
+                    .map(|c| c.trim().to_owned())
+                    .filter(|c| !c.is_empty())
+                    .collect()
+            }
+            "next" => self.next = Some(value.parse()?),
+            "page" => self.page = Some(value.to_owned()),
+            "max_pages" => {
+                self.max_pages = value.parse().map_err(|_| {
+                    Error::unsupported(format!("Invalid max_pages {}", value), value)
+                })?
+            }
+            _ => {
+                return Err(Error::unsupported(
+                    format!(
+                    "Unknown read_json argument {}, expect path, explode, next, page or max_pages",
+                    name
+                ),
+                    name,
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for JsonPath {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let err = || Error::unsupported(format!("Invalid json path {}, expect $.a.b[0]", s), s);
+        let mut rest = s.trim().strip_prefix('$').ok_or_else(err)?;
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(r) = rest.strip_prefix('.') {
+                let end = r.find(|c| c == '.' || c == '[').unwrap_or(r.len());
+                if end == 0 {
+                    return Err(err());
+                }
+                segments.push(Segment::Key(r[..end].to_owned()));
+                rest = &r[end..];
+            } else if let Some(r) = rest.strip_prefix('[') {
+                let end = r.find(']').ok_or_else(err)?;
+                let inner = r[..end].trim();
+                let quoted = inner
+                    .strip_prefix('\'')
+                    .and_then(|v| v.strip_suffix('\''))
+                    .or_else(|| inner.strip_prefix('"').and_then(|v| v.strip_suffix('"')));
+                segments.push(match quoted {
+                    Some(key) => Segment::Key(key.to_owned()),
+                    None => Segment::Index(inner.parse().map_err(|_| err())?),
+                });
+                rest = &r[end + 1..];
+            } else {
+                return Err(err());
+            }
+        }
+        Ok(Self(segments))
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("$")?;
+        for segment in &self.0 {
+            match segment {
+                Segment::Key(key) => write!(f, "['{}']", key)?,
+                Segment::Index(i) => write!(f, "[{}]", i)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl JsonPath {
+    fn lookup<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        self.0.iter().try_fold(value, |v, segment| match segment {
+            Segment::Key(key) => v.get(key),
+            Segment::Index(i) => v.get(i),
+        })
+    }
+}
+
+/// 读取 JSON API，按 options 跟随分页，把所有页的记录合并成一个 DataFrame
+///
+/// check 用来检查下一页的地址是否允许访问
+pub(crate) async fn read_json<F>(
+    url: &str,
+    options: &JsonOptions,
+    max_bytes: Option<u64>,
+    check: F,
+) -> Result<DataFrame>
+where
+    F: Fn(&str) -> Result<()> + Sync,
+{
+    let mut records = Vec::new();
+    let mut url = url.to_owned();
+
+    for _ in 0..options.max_pages {
+        info!(url = url.as_str(), "retrieving json page");
+        let body = retrieve_data(&url, max_bytes, &check).await?;
+        let doc: Value = serde_json::from_str(&body).map_err(Error::load)?;
+        let page = match options.path.lookup(&doc) {
+            Some(Value::Array(items)) => items.clone(),
+            Some(Value::Null) => Vec::new(),
+            Some(item) => vec![item.clone()],
+            None => {
+                return Err(Error::load(format!(
+                    "Can not find records at {} in {}",
+                    options.path, url
+                )))
+            }
+        };
+        let is_empty = page.is_empty();
+        records.extend(page);
+
+        let next = match (&options.next, &options.page) {
+            (Some(next), _) => match next.lookup(&doc) {
+                Some(Value::String(link)) if !link.is_empty() => Some(resolve(&url, link)?),
+                _ => None,
+            },
+            (None, Some(param)) if !is_empty => Some(next_page(&url, param)?),
+            _ => None,
+        };
+        match next {
+            Some(next) => {
+                check(&next)?;
+                url = next;
+            }
+            None => return to_dataframe(records, &options.explode),
+        }
+    }
+
+    Err(Error::LimitExceeded {
+        resource: "pages",
+        limit: options.max_pages as u64,
+    })
+}
+
+/// 下一页的链接可以是相对地址，但不能离开当前的 scheme、主机和端口：
+/// 链接来自远程的文档，不管有没有 allowed_sources，都不能让它把我们带到本地文件或者别的主机
+fn resolve(base: &str, link: &str) -> Result<String> {
+    let base = Url::parse(base).map_err(Error::fetch)?;
+    let next = base.join(link).map_err(Error::fetch)?;
+    if next.scheme() != base.scheme()
+        || next.host_str() != base.host_str()
+        || next.port_or_known_default() != base.port_or_known_default()
+    {
+        return Err(Error::NotAllowed(next.to_string()));
+    }
+    Ok(next.to_string())
+}
+
+/// 把地址里的页码加一，没有页码时当前是第 1 页
+fn next_page(url: &str, param: &str) -> Result<String> {
+    let mut parsed = Url::parse(url).map_err(Error::fetch)?;
+    let pairs: Vec<(String, String)> = parsed.query_pairs().into_owned().collect();
+    let current = match pairs.iter().find(|(k, _)| k == param) {
+        Some((_, v)) => v
+            .parse::<u64>()
+            .map_err(|_| Error::fetch(format!("Invalid page {}={} in {}", param, v, url)))?,
+        None => 1,
+    };
+
+    parsed
+        .query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs.iter().filter(|(k, _)| k != param))
+        .append_pair(param, &(current + 1).to_string());
+    Ok(parsed.to_string())
+}
+
+/// 一行数据：展开之后的列名和值，保持 JSON 里的顺序
+type Row = Vec<(String, Value)>;
+
+/// 把嵌套的对象展开成 `a.b.c` 形式的列，数组保持原样
+fn flatten(prefix: &str, value: Value, row: &mut Row) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                let name = match prefix {
+                    "" => key,
+                    _ => format!("{}.{}", prefix, key),
+                };
+                flatten(&name, value, row);
+            }
+        }
+        value => row.push((prefix.to_owned(), value)),
+    }
+}
+
+/// 数组里的每个元素生成一行，空数组保留一行 null
+fn explode(mut row: Row, column: &str) -> Vec<Row> {
+    let i = match row.iter().position(|(k, v)| k == column && v.is_array()) {
+        Some(i) => i,
+        None => return vec![row],
+    };
+    let items = match row.remove(i).1 {
+        Value::Array(items) => items,
+        _ => unreachable!(),
+    };
+    if items.is_empty() {
+        row.push((column.to_owned(), Value::Null));
+        return vec![row];
+    }
+    items
+        .into_iter()
+        .map(|item| {
+            let mut row = row.clone();
+            flatten(column, item, &mut row);
+            row
+        })
+        .collect()
+}
+
+pub(crate) fn to_dataframe(records: Vec<Value>, explode_columns: &[String]) -> Result<DataFrame> {
+    let mut rows: Vec<Row> = records
+        .into_iter()
+        .map(|record| {
+            let mut row = Vec::new();
+            match record {
+                record @ Value::Object(_) => flatten("", record, &mut row),
+                value => row.push(("value".to_owned(), value)),
+            }
+            row
+        })
+        .collect();
+    for column in explode_columns {
+        rows = rows
+            .into_iter()
+            .flat_map(|row| explode(row, column))
+            .collect();
+    }
+
+    // 所有行里出现过的列，按第一次出现的顺序
+    let mut names: Vec<&str> = Vec::new();
+    let rows: Vec<HashMap<&str, &Value>> = rows
+        .iter()
+        .map(|row| {
+            for (k, _) in row {
+                if !names.contains(&k.as_str()) {
+                    names.push(k);
+                }
+            }
+            row.iter().map(|(k, v)| (k.as_str(), v)).collect()
+        })
+        .collect();
+
+    let columns = names
+        .iter()
+        .map(|name| {
+            let values: Vec<_> = rows
+                .iter()
+                .map(|row| row.get(name).copied().filter(|v| !v.is_null()))
+                .collect();
+            to_series(name, &values)
+        })
+        .collect();
+    Ok(DataFrame::new(columns)?)
+}
+
+/// 按 JSON 值的类型决定列的类型，混合的类型转成文本，数组和对象保留 JSON 文本
+fn to_series(name: &str, values: &[Option<&Value>]) -> Series {
+    let present = || values.iter().flatten();
+    let has_value = present().next().is_some();
+
+    if has_value && present().all(|v| v.is_boolean()) {
+        let values: Vec<_> = values.iter().map(|v| v.and_then(Value::as_bool)).collect();
+        return Series::new(name, values);
+    }
+    if has_value && present().all(|v| v.is_i64()) {
+        let values: Vec<_> = values.iter().map(|v| v.and_then(Value::as_i64)).collect();
+        return Series::new(name, values);
+    }
+    if has_value && present().all(|v| v.is_number()) {
+        let values: Vec<_> = values.iter().map(|v| v.and_then(Value::as_f64)).collect();
+        return Series::new(name, values);
+    }
+
+    let values: Vec<_> = values
+        .iter()
+        .map(|v| {
+            v.map(|v| match v {
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
+            })
+        })
+        .collect();
+    let values: Vec<_> = values.iter().map(|v| v.as_deref()).collect();
+    Series::new(name, values)
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn json_path_works() {
+        let path: JsonPath = "$.data['the items'][1].id".parse().unwrap();
+        let doc = json!({"data": {"the items": [{"id": 1}, {"id": 2}]}});
+        assert_eq!(path.lookup(&doc), Some(&json!(2)));
+        assert_eq!("$".parse::<JsonPath>().unwrap().lookup(&doc), Some(&doc));
+        assert!("data.items".parse::<JsonPath>().is_err());
+        assert!("$..items".parse::<JsonPath>().is_err());
+    }
+
+    #[test]
+    fn to_dataframe_flattens_and_explodes() {
+        let records = vec![
+            json!({"id": 1, "user": {"name": "a", "geo": {"lat": 1.5}}, "tags": ["x", "y"]}),
+            json!({"id": 2, "user": {"name": "b"}, "tags": [], "extra": true}),
+        ];
+        let df = to_dataframe(records.clone(), &[]).unwrap();
+        assert_eq!(
+            df.get_column_names(),
+            vec!["id", "user.name", "user.geo.lat", "tags", "extra"]
+        );
+        assert_eq!(df.column("id").unwrap().dtype(), &DataType::Int64);
+        assert_eq!(df.column("user.geo.lat").unwrap().null_count(), 1);
+        assert_eq!(df.column("tags").unwrap().dtype(), &DataType::Utf8);
+
+        let df = to_dataframe(records, &["tags".into()]).unwrap();
+        assert_eq!(df.height(), 3);
+        assert_eq!(df.column("tags").unwrap().null_count(), 1);
+    }
+
+    #[test]
+    fn next_page_works() {
+        assert_eq!(
+            next_page("https://a.com/items?size=10", "page").unwrap(),
+            "https://a.com/items?size=10&page=2"
+        );
+        assert_eq!(
+            next_page("https://a.com/items?page=3&size=10", "page").unwrap(),
+            "https://a.com/items?size=10&page=4"
+        );
+        assert_eq!(
+            resolve("https://a.com/v1/items?page=1", "/v1/items?cursor=abc").unwrap(),
+            "https://a.com/v1/items?cursor=abc"
+        );
+        for link in [
+            "file:///etc/passwd",
+            "https://evil.com/v1/items",
+            "https://a.com:8443/v1/items",
+            "//10.0.0.1/admin",
+        ] {
+            assert!(matches!(
+                resolve("https://a.com/v1/items", link),
+                Err(Error::NotAllowed(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn read_json_follows_next_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let page = |i: usize, next: Option<&str>| {
+            let doc = json!({"data": {"items": [{"page": i}]}, "links": {"next": next}});
+            std::fs::write(dir.path().join(format!("{}.json", i)), doc.to_string()).unwrap();
+        };
+        page(1, Some("2.json"));
+        page(2, None);
+        page(3, Some("https://a.com/items.json"));
+
+        let options = JsonOptions {
+            path: "$.data.items".parse().unwrap(),
+            next: Some("$.links.next".parse().unwrap()),
+            ..Default::default()
+        };
+        let url = format!("file://{}/1.json", dir.path().display());
+        let df = read_json(&url, &options, None, |_| Ok(())).await.unwrap();
+        assert_eq!(df.column("page").unwrap().sum::<i64>(), Some(3));
+
+        let err = read_json(&url, &options, None, |next| {
+            Err(Error::NotAllowed(next.to_owned()))
+        })
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Error::NotAllowed(_)));
+
+        // 即使没有限制，也不会跟随到别的 scheme 或者主机
+        let url = format!("file://{}/3.json", dir.path().display());
+        let err = read_json(&url, &options, None, |_| Ok(()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotAllowed(_)));
+    }
+}
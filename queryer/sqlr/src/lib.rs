@@ -1,20 +1,51 @@
-use anyhow::{anyhow, Result};
 use polars::prelude::*;
-use sqlparser::parser::Parser;
-use std::convert::TryInto;
+use serde::de::DeserializeOwned;
+use std::io::Cursor;
 use std::ops::{Deref, DerefMut};
-use tracing::info;
 
+mod approx;
+mod cache;
 mod convert;
 mod dialect;
+mod error;
+mod excel;
 mod fetcher;
+mod html;
+mod json;
+mod limits;
 mod loader;
-use convert::Sql;
-use fetcher::retrieve_data;
-use loader::detect_content;
-
+mod params;
+mod parser;
+mod reshape;
+mod rows;
+mod sample;
+mod session;
+mod sqlite;
+mod stats;
+mod stream;
+mod temporal;
+#[cfg(feature = "wasm")]
+mod wasm;
+#[cfg(feature = "native")]
+mod watch;
+mod writer;
+
+pub use cache::{CacheStats, ResultCacheConfig};
+pub use convert::Udf;
 pub use dialect::example_sql;
 pub use dialect::TryDialect;
+pub use error::{Error, Location, Result};
+pub use limits::{CancelHandle, QueryOptions};
+pub use params::{Param, Params, Prepared};
+pub use rows::Rows;
+pub use session::{Session, SessionConfig};
+pub use stats::QueryStats;
+pub use stream::RecordBatchStream;
+#[cfg(feature = "wasm")]
+pub use wasm::Database;
+#[cfg(feature = "native")]
+pub use watch::{Diff, Update, Watch};
+pub use writer::Format;
 
 #[derive(Debug)]
 pub struct DataSet(DataFrame);
@@ -35,56 +66,69 @@ impl DerefMut for DataSet {
     }
 }
 
+impl From<DataFrame> for DataSet {
+    fn from(df: DataFrame) -> Self {
+        Self(df)
+    }
+}
+
 impl DataSet {
+    /// 没有任何列的 DataSet，用于不返回数据的语句
+    pub fn empty() -> Self {
+        Self(DataFrame::new_no_checks(Vec::new()))
+    }
+
     /// 从 DataSet 转换成 csv
     pub fn to_csv(&self) -> Result<String> {
         let mut buf = Vec::new();
         let writer = CsvWriter::new(&mut buf);
         writer.finish(self)?;
-        Ok(String::from_utf8(buf)?)
+        String::from_utf8(buf).map_err(Error::execution)
     }
-}
 
-/// 从 from 中获取数据，从 where 中过滤，最后选取需要返回的列
-pub async fn query<T: AsRef<str>>(sql: T) -> Result<DataSet> {
-    let ast = Parser::parse_sql(&TryDialect, sql.as_ref())?;
-
-    if ast.len() != 1 {
-        return Err(anyhow!("Only support single sql at the moment"));
+    /// 按指定格式序列化 DataSet
+    pub fn to_bytes(&self, format: Format) -> Result<Vec<u8>> {
+        let mut buf = Cursor::new(Vec::new());
+        match format {
+            Format::Csv => CsvWriter::new(&mut buf).finish(self)?,
+            Format::Json => JsonWriter::new(&mut buf)
+                .with_json_format(JsonFormat::Json)
+                .finish(self)?,
+            Format::NdJson => JsonWriter::new(&mut buf)
+                .with_json_format(JsonFormat::JsonLines)
+                .finish(self)?,
+            #[cfg(feature = "native")]
+            Format::Parquet => ParquetWriter::new(&mut buf).finish(self)?,
+            #[cfg(feature = "native")]
+            Format::Arrow => IpcWriter::new(&mut buf).finish(self)?,
+            #[cfg(not(feature = "native"))]
+            Format::Parquet | Format::Arrow => {
+                return Err(Error::unsupported(
+                    format!("Format {} is not supported in wasm", format),
+                    format,
+                ))
+            }
+        }
+        Ok(buf.into_inner())
     }
 
-    let sql = &ast[0];
-
-    // 整个 SQL AST 转换成我们定义的 Sql 结构的细节都埋藏在 try_into() 中
-    // 我们只需关注数据结构的使用，怎么转换可以之后需要的时候才关注，这是
-    // 关注点分离，是我们控制软件复杂度的法宝。
-    let Sql {
-        source,
-        condition,
-        selection,
-        offset,
-        limit,
-        order_by,
-    } = sql.try_into()?;
-
-    info!("retrieving data from source: {}", source);
-
-    // 从 source 读入一个 DataSet
-    // detect_content，怎么 detect 不重要，重要的是它能根据内容返回 DataSet
-    let ds = detect_content(retrieve_data(source).await?).load()?;
-
-    let mut filtered = match condition {
-        Some(expr) => ds.0.lazy().filter(expr),
-        None => ds.0.lazy(),
-    };
-
-    filtered = order_by
-        .into_iter()
-        .fold(filtered, |acc, (col, desc)| acc.sort(&col, desc));
+    /// 按列名把每一行反序列化成 T，迭代时才逐行转换
+    pub fn rows<T: DeserializeOwned>(&self) -> Rows<'_, T> {
+        Rows::new(self)
+    }
 
-    if offset.is_some() || limit.is_some() {
-        filtered = filtered.slice(offset.unwrap_or(0), limit.unwrap_or(usize::MAX));
+    /// 把所有行反序列化成 `Vec<T>`
+    pub fn to_vec<T: DeserializeOwned>(&self) -> Result<Vec<T>> {
+        self.rows().collect()
     }
+}
+
+/// 从 from 中获取数据，从 where 中过滤，最后选取需要返回的列
+pub async fn query<T: AsRef<str>>(sql: T) -> Result<DataSet> {
+    Session::new().query(sql).await
+}
 
-    Ok(DataSet(filtered.select(selection).collect()?))
+/// 执行查询，并把结果的每一行反序列化成 T
+pub async fn query_as<T: DeserializeOwned, S: AsRef<str>>(sql: S) -> Result<Vec<T>> {
+    query(sql).await?.to_vec()
 }
@@ -0,0 +1,141 @@
+use crate::error::{Error, Result};
+use futures::future::{self, Either};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// 单次查询的选项：超时、资源限制和取消
+///
+/// 超时和取消在异步的等待点生效（比如下载数据源的时候），
+/// polars 正在计算的那一步不会被打断，会在它结束后返回错误
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    /// 整个查询的最长执行时间，wasm 下没有计时器，这个选项不生效
+    pub timeout: Option<Duration>,
+    /// 单个数据源最多下载的字节数
+    pub max_bytes: Option<u64>,
+    /// 读取的数据源和查询结果最多的行数
+    pub max_rows: Option<usize>,
+    /// 用来从别的任务取消查询
+    pub cancel: Option<CancelHandle>,
+}
+
+/// 取消查询的句柄，clone 之后可以交给别的任务或者线程
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<CancelState>);
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 取消查询，正在等待的查询会返回 [`Error::Cancelled`]
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// 等到被取消为止
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.0.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl QueryOptions {
+    /// 检查行数是否超出限制
+    pub(crate) fn check_rows(&self, rows: usize) -> Result<()> {
+        match self.max_rows {
+            Some(max) if rows > max => Err(Error::LimitExceeded {
+                resource: "rows",
+                limit: max as u64,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 在超时和取消的约束下执行 fut
+pub(crate) async fn guard<T, F>(options: &QueryOptions, fut: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let cancellable = async {
+        match &options.cancel {
+            Some(cancel) if cancel.is_cancelled() => Err(Error::Cancelled),
+            Some(cancel) => {
+                futures::pin_mut!(fut);
+                let cancelled = cancel.cancelled();
+                futures::pin_mut!(cancelled);
+                match future::select(fut, cancelled).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right(_) => Err(Error::Cancelled),
+                }
+            }
+            None => fut.await,
+        }
+    };
+
+    #[cfg(feature = "native")]
+    if let Some(timeout) = options.timeout {
+        return tokio::time::timeout(timeout, cancellable)
+            .await
+            .map_err(|_| Error::Timeout(timeout))?;
+    }
+    cancellable.await
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn guard_times_out() {
+        let options = QueryOptions {
+            timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let result = guard(&options, async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(())
+        })
+        .await;
+        assert!(matches!(result, Err(Error::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn guard_can_be_cancelled_from_another_task() {
+        let cancel = CancelHandle::new();
+        let options = QueryOptions {
+            cancel: Some(cancel.clone()),
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            cancel.cancel();
+        });
+        let result = guard(&options, async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(())
+        })
+        .await;
+        assert!(matches!(result, Err(Error::Cancelled)));
+    }
+}
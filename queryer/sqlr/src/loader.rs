@@ -1,8 +1,15 @@
+use crate::error::{Error, Result};
+use crate::excel::ExcelLoader;
+use crate::html::{is_html, HtmlLoader, TableSelector};
+use crate::sqlite::SqliteLoader;
 use crate::DataSet;
-use anyhow::Result;
 use polars::prelude::*;
+use std::collections::HashSet;
 use std::io::Cursor;
 
+/// 推断 csv 每一列的类型时看的行数，所有读取 csv 的地方都要一致，否则同一个数据源的类型会不同
+pub(crate) const INFER_SCHEMA_ROWS: usize = 16;
+
 pub trait Load {
     type Error;
     fn load(self) -> Result<DataSet, Self::Error>;
@@ -12,6 +19,9 @@ pub trait Load {
 #[non_exhaustive]
 pub enum Loader {
     Csv(CsvLoader),
+    Sqlite(SqliteLoader),
+    Excel(ExcelLoader),
+    Html(HtmlLoader),
 }
 
 #[derive(Default, Debug)]
@@ -21,22 +31,56 @@ impl Loader {
     pub fn load(self) -> Result<DataSet> {
         match self {
             Loader::Csv(csv) => csv.load(),
+            Loader::Sqlite(sqlite) => sqlite.load(),
+            Loader::Excel(excel) => excel.load(),
+            Loader::Html(html) => html.load(),
         }
     }
 }
 
-pub fn detect_content(data: String) -> Loader {
-    // TODO: 内容检测
+/// 根据内容选择 loader，fragment 是数据源地址里 `#` 之后的部分，比如 HTML 里选择哪个表格
+pub fn detect_content(data: String, fragment: Option<&str>) -> Loader {
+    if is_html(&data) {
+        return Loader::Html(HtmlLoader {
+            data,
+            table: fragment
+                .map(TableSelector::from_fragment)
+                .unwrap_or_default(),
+        });
+    }
     Loader::Csv(CsvLoader(data))
 }
 
+/// 表头作为列名：空的名字用 column_1 这样的序号代替，重复的名字加上序号
+pub(crate) fn unique_names(names: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    names
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let name = match name.trim() {
+                "" => format!("column_{}", i + 1),
+                name => name.to_owned(),
+            };
+            let mut unique = name.clone();
+            let mut n = 1;
+            while !seen.insert(unique.clone()) {
+                n += 1;
+                unique = format!("{}_{}", name, n);
+            }
+            unique
+        })
+        .collect()
+}
+
 impl Load for CsvLoader {
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn load(self) -> Result<DataSet, Self::Error> {
         let df = CsvReader::new(Cursor::new(self.0))
-            .infer_schema(Some(16))
-            .finish()?;
+            .infer_schema(Some(INFER_SCHEMA_ROWS))
+            .finish()
+            .map_err(Error::load)?;
         Ok(DataSet(df))
     }
 }
@@ -0,0 +1,129 @@
+use crate::error::{Error, Result};
+use crate::parser::Stmt;
+use polars::prelude::*;
+use std::collections::HashMap;
+
+/// 用 [`crate::Session::prepare`] 解析好的语句，执行时绑定参数，不需要重新解析 SQL
+#[derive(Debug, Clone)]
+pub struct Prepared {
+    pub(crate) stmt: Stmt,
+    /// 原始的 SQL，出错时用来定位
+    pub(crate) sql: String,
+}
+
+/// 绑定到占位符上的值
+#[derive(Debug, Clone, PartialEq)]
+pub enum Param {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+/// 一组绑定的参数，`$1` / `?` 按位置绑定，`:name` 按名字绑定
+#[derive(Debug, Clone, Default)]
+pub struct Params(HashMap<String, Param>);
+
+impl Params {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 按顺序绑定 `$1`、`$2`... 或者依次出现的 `?`
+    pub fn positional<I, P>(values: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<Param>,
+    {
+        values
+            .into_iter()
+            .enumerate()
+            .fold(Self::new(), |params, (i, v)| params.bind(i + 1, v))
+    }
+
+    /// 绑定 `$index`，index 从 1 开始
+    pub fn bind(mut self, index: usize, value: impl Into<Param>) -> Self {
+        self.0.insert(format!("${}", index), value.into());
+        self
+    }
+
+    /// 绑定 `:name`
+    pub fn bind_named(mut self, name: &str, value: impl Into<Param>) -> Self {
+        self.0.insert(format!(":{}", name), value.into());
+        self
+    }
+
+    /// 按占位符排序后的所有绑定，用作结果缓存的键的一部分
+    pub(crate) fn fingerprint(&self) -> String {
+        let mut params: Vec<_> = self.0.iter().collect();
+        params.sort_by(|a, b| a.0.cmp(b.0));
+        format!("{:?}", params)
+    }
+
+    /// 按占位符取出绑定的值，占位符形如 `$1` 或 `:name`
+    pub(crate) fn get(&self, placeholder: &str) -> Result<&Param> {
+        self.0
+            .get(placeholder)
+            .ok_or_else(|| Error::execution(format!("Parameter {} is not bound", placeholder)))
+    }
+}
+
+/// 判断标识符是不是解析时改写出来的占位符
+pub(crate) fn is_placeholder(name: &str) -> bool {
+    name.len() > 1 && (name.starts_with('$') || name.starts_with(':'))
+}
+
+impl From<&Param> for Expr {
+    fn from(p: &Param) -> Self {
+        match p {
+            Param::Null => Expr::Literal(LiteralValue::Null),
+            Param::Bool(v) => lit(*v),
+            Param::Int(v) => lit(*v),
+            Param::Float(v) => lit(*v),
+            Param::Str(v) => lit(v.as_str()),
+        }
+    }
+}
+
+impl From<bool> for Param {
+    fn from(v: bool) -> Self {
+        Self::Bool(v)
+    }
+}
+
+impl From<i32> for Param {
+    fn from(v: i32) -> Self {
+        Self::Int(v as i64)
+    }
+}
+
+impl From<i64> for Param {
+    fn from(v: i64) -> Self {
+        Self::Int(v)
+    }
+}
+
+impl From<f64> for Param {
+    fn from(v: f64) -> Self {
+        Self::Float(v)
+    }
+}
+
+impl From<&str> for Param {
+    fn from(v: &str) -> Self {
+        Self::Str(v.to_owned())
+    }
+}
+
+impl From<String> for Param {
+    fn from(v: String) -> Self {
+        Self::Str(v)
+    }
+}
+
+impl<T: Into<Param>> From<Option<T>> for Param {
+    fn from(v: Option<T>) -> Self {
+        v.map(Into::into).unwrap_or(Param::Null)
+    }
+}
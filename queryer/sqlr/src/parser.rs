@@ -0,0 +1,699 @@
+use crate::error::{location_at, Error, Location, Result};
+use crate::writer::Format;
+use crate::TryDialect;
+use sqlparser::ast::{Query, Statement};
+use sqlparser::keywords::Keyword;
+use sqlparser::parser::Parser;
+use sqlparser::tokenizer::{Token, Tokenizer, Whitespace};
+use std::fmt;
+use std::path::Path;
+use tracing::info_span;
+
+/// 我们支持的语句：SqlParser 能解析的标准语句，以及它还不支持的扩展语法
+#[derive(Debug, Clone)]
+pub(crate) enum Stmt {
+    Sql(Statement),
+    CopyTo(CopyTo),
+}
+
+/// COPY (SELECT ...) TO 'file://...' [WITH (FORMAT ...)]
+#[derive(Debug, Clone)]
+pub(crate) struct CopyTo {
+    pub(crate) query: Box<Query>,
+    pub(crate) target: String,
+    pub(crate) format: Format,
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stmt::Sql(stmt) => write!(f, "{}", stmt),
+            Stmt::CopyTo(copy) => write!(
+                f,
+                "COPY ({}) TO '{}' WITH (FORMAT {})",
+                copy.query, copy.target, copy.format
+            ),
+        }
+    }
+}
+
+/// 把 sql 解析成一组语句，语句之间用 `;` 分隔
+pub(crate) fn parse(sql: &str) -> Result<Vec<Stmt>> {
+    let _span = info_span!("parse", len = sql.len()).entered();
+    let tokens = Tokenizer::new(&TryDialect, sql)
+        .tokenize()
+        .map_err(|e| Error::Parse {
+            message: e.message,
+            location: Some(Location {
+                line: e.line as usize,
+                column: e.col as usize,
+                len: 1,
+            }),
+        })?;
+    let tokens = rewrite_relations(rewrite_sources(rewrite_placeholders(with_offsets(
+        sql, tokens,
+    ))));
+
+    // 除空白外每个 token 的文本和字节偏移，解析出错时用来定位
+    let spans: Vec<_> = tokens
+        .iter()
+        .filter(|(token, _)| !matches!(token, Token::Whitespace(_)))
+        .map(|(token, offset)| (token.to_string(), *offset))
+        .collect();
+
+    let tokens = tokens.into_iter().map(|(token, _)| token).collect();
+    let mut parser = Parser::new(tokens, &TryDialect);
+    parse_statements(&mut parser).map_err(|e| match e {
+        Error::Parse {
+            message,
+            location: None,
+        } => {
+            let location = Some(failed_location(sql, &spans, &mut parser, &message));
+            Error::Parse { message, location }
+        }
+        e => e,
+    })
+}
+
+fn parse_statements(parser: &mut Parser) -> Result<Vec<Stmt>> {
+    let mut stmts = Vec::new();
+    let mut expecting_delimiter = false;
+
+    loop {
+        while parser.consume_token(&Token::SemiColon) {
+            expecting_delimiter = false;
+        }
+
+        let token = parser.peek_token();
+        if token == Token::EOF {
+            break;
+        }
+        if expecting_delimiter {
+            return Err(parse_error(format!(
+                "Expected end of statement, found: {}",
+                token
+            )));
+        }
+
+        stmts.push(parse_statement(parser)?);
+        expecting_delimiter = true;
+    }
+
+    Ok(stmts)
+}
+
+fn parse_error(message: String) -> Error {
+    Error::Parse {
+        message,
+        location: None,
+    }
+}
+
+/// 解析失败后，parser 停在出错的 token 附近：数一下还剩多少 token 就知道是哪一个
+fn failed_location(
+    sql: &str,
+    spans: &[(String, usize)],
+    parser: &mut Parser,
+    message: &str,
+) -> Location {
+    let mut remaining = 0;
+    while parser.next_token() != Token::EOF {
+        remaining += 1;
+    }
+    let index = spans.len() - remaining;
+
+    // 有的错误是先消费了 token 再报错，这时出错的是前一个 token
+    let found = |i: usize| {
+        spans
+            .get(i)
+            .map(|(text, _)| message.ends_with(&format!("found: {}", text)))
+            .unwrap_or(false)
+    };
+    let index = if index > 0 && !found(index) && found(index - 1) {
+        index - 1
+    } else {
+        index
+    };
+
+    match spans.get(index) {
+        Some((text, offset)) => location_at(sql, *offset, text.chars().count()),
+        None => location_at(sql, sql.len(), 1),
+    }
+}
+
+/// 计算每个 token 在 sql 里的字节偏移
+fn with_offsets(sql: &str, tokens: Vec<Token>) -> Vec<(Token, usize)> {
+    let mut offset = 0;
+    tokens
+        .into_iter()
+        .map(|token| {
+            let start = offset;
+            offset += source_len(&sql[start..], &token);
+            (token, start)
+        })
+        .collect()
+}
+
+/// token 在源码中占的字节数。大部分 token 的 Display 和源码一致，
+/// 带引号的字符串和标识符可能有转义，需要在源码里找到结束的引号；
+/// `\r\n` 的 Display 只有一个 `\n`
+fn source_len(rest: &str, token: &Token) -> usize {
+    let len = match token {
+        Token::Whitespace(Whitespace::Newline) if rest.starts_with("\r\n") => 2,
+        Token::SingleQuotedString(_) => quoted_len(rest, '\''),
+        Token::Word(w) => match w.quote_style {
+            Some('[') => quoted_len(rest, ']'),
+            Some(quote) => quoted_len(rest, quote),
+            None => w.value.len(),
+        },
+        token => token.to_string().len(),
+    };
+    // 万一算错了也不能停在多字节字符的中间，否则后面切片时会 panic
+    let mut len = len.min(rest.len());
+    while !rest.is_char_boundary(len) {
+        len += 1;
+    }
+    len
+}
+
+/// 跳过开头的引号，找到对应的结束引号，连续两个引号是转义
+fn quoted_len(rest: &str, close: char) -> usize {
+    let mut chars = rest.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == close {
+            if chars.peek().map(|(_, c)| *c) == Some(close) {
+                chars.next();
+                continue;
+            }
+            return i + c.len_utf8();
+        }
+    }
+    rest.len()
+}
+
+/// SqlParser 不认识 `$1`、`?`、`:name` 这样的占位符，我们把它们改写成标识符，
+/// 转换成 DataFrame 的 Expr 时再从绑定的参数里取值。`?` 按出现的顺序编号为 `$1`、`$2`...
+fn rewrite_placeholders(tokens: Vec<(Token, usize)>) -> Vec<(Token, usize)> {
+    let mut result = Vec::with_capacity(tokens.len());
+    let mut positional = 0;
+    let mut tokens = tokens.into_iter().peekable();
+
+    while let Some((token, offset)) = tokens.next() {
+        let placeholder = match (&token, tokens.peek().map(|(t, _)| t)) {
+            (Token::Char('?'), _) => {
+                positional += 1;
+                format!("${}", positional)
+            }
+            (Token::Char('$'), Some(Token::Number(n, _))) => format!("${}", n),
+            (Token::Colon, Some(Token::Word(w))) if w.quote_style.is_none() => {
+                format!(":{}", w.value)
+            }
+            _ => {
+                result.push((token, offset));
+                continue;
+            }
+        };
+        if !matches!(token, Token::Char('?')) {
+            tokens.next();
+        }
+        result.push((Token::make_word(&placeholder, None), offset));
+    }
+
+    result
+}
+
+/// SqlParser 要求 FROM 后面是标识符，我们允许用单引号括起来的字符串作为数据源，
+/// 比如 `FROM 'https://...'`，把它改写成用双引号括起来的标识符
+fn rewrite_sources(tokens: Vec<(Token, usize)>) -> Vec<(Token, usize)> {
+    let in_query = in_query(&tokens);
+    let mut after_from = false;
+    tokens
+        .into_iter()
+        .zip(in_query)
+        .map(|((token, offset), in_query)| {
+            let token = match token {
+                Token::Whitespace(_) => return (token, offset),
+                Token::SingleQuotedString(s) if after_from => Token::make_word(&s, Some('"')),
+                token => token,
+            };
+            after_from = in_query && is_relation_start(&token);
+            (token, offset)
+        })
+        .collect()
+}
+
+fn is_relation_start(token: &Token) -> bool {
+    matches!(token, Token::Word(w) if w.keyword == Keyword::FROM || w.keyword == Keyword::JOIN)
+}
+
+/// 每个 token 是否直接处在查询里，而不是在函数调用的括号里。只有查询里的 FROM / JOIN 后面是数据源，
+/// `EXTRACT(year FROM '2021-01-31')`、`SUBSTRING(s FROM 2)` 里的 FROM 不是
+fn in_query(tokens: &[(Token, usize)]) -> Vec<bool> {
+    // 每层括号是不是查询，None 表示还没看到括号里的第一个 token
+    let mut levels = vec![Some(true)];
+    let mut after_from = false;
+    tokens
+        .iter()
+        .map(|(token, _)| {
+            let top = levels.len() - 1;
+            if matches!(token, Token::Whitespace(_)) {
+                return levels[top].unwrap_or(false);
+            }
+            // 子查询的括号紧跟在 FROM / JOIN 之后，或者以 SELECT / WITH 开头
+            let current = *levels[top].get_or_insert_with(|| match token {
+                Token::Word(w) => w.keyword == Keyword::SELECT || w.keyword == Keyword::WITH,
+                _ => false,
+            });
+            match token {
+                Token::LParen => levels.push(if after_from { Some(true) } else { None }),
+                Token::RParen if top > 0 => {
+                    levels.pop();
+                }
+                _ => {}
+            }
+            after_from = current && is_relation_start(token);
+            current
+        })
+        .collect()
+}
+
+/// SqlParser 还不支持 PIVOT / UNPIVOT 和 TABLESAMPLE，我们把它们改写成表函数：
+/// `FROM t PIVOT (sum(v) FOR k IN ('a', 'b'))` 改写成 `FROM pivot(t, sum(v), k, 'a', 'b')`，
+/// `FROM t UNPIVOT (v FOR k IN (a, b))` 改写成 `FROM unpivot(t, v, k, a, b)`，
+/// `FROM t TABLESAMPLE SYSTEM (10 ROWS) REPEATABLE (42)` 改写成 `FROM tablesample(t, system, 10, rows, 42)`
+fn rewrite_relations(tokens: Vec<(Token, usize)>) -> Vec<(Token, usize)> {
+    let in_query = in_query(&tokens);
+    let mut result: Vec<(Token, usize)> = Vec::with_capacity(tokens.len());
+    // 最近的 FROM / JOIN 之后的数据源在 result 里开始的位置
+    let mut relation = None;
+    let mut i = 0;
+
+    while i < tokens.len() {
+        let (token, offset) = &tokens[i];
+        match token {
+            token if in_query[i] && is_relation_start(token) => {
+                result.push(tokens[i].clone());
+                relation = Some(result.len());
+                i += 1;
+                continue;
+            }
+            Token::Word(w)
+                if matches!(
+                    w.keyword,
+                    Keyword::ON | Keyword::WHERE | Keyword::GROUP | Keyword::ORDER | Keyword::LIMIT
+                ) =>
+            {
+                relation = None
+            }
+            // 已经是 `FROM pivot(...)` 这样的表函数时不用改写
+            Token::Word(w) if w.quote_style.is_none() && has_source(&result, relation) => {
+                let name = w.value.to_lowercase();
+                let rewritten = match name.as_str() {
+                    "pivot" | "unpivot" => pivot_at(&tokens, i),
+                    "tablesample" => sample_at(&tokens, i),
+                    _ => None,
+                };
+                if let Some((args, end)) = rewritten {
+                    let source: Vec<_> = result
+                        .drain(relation.unwrap_or_default()..)
+                        .filter(|(t, _)| !matches!(t, Token::Whitespace(_)))
+                        .collect();
+                    result.push((Token::make_word(&name, None), *offset));
+                    result.push((Token::LParen, *offset));
+                    result.extend(source);
+                    result.push((Token::Comma, *offset));
+                    result.extend(args);
+                    result.push((Token::RParen, tokens[end].1));
+                    i = end + 1;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        result.push(tokens[i].clone());
+        i += 1;
+    }
+
+    result
+}
+
+/// from 之后第一个不是空白的 token
+fn next_significant(tokens: &[(Token, usize)], from: usize) -> Option<usize> {
+    (from..tokens.len()).find(|i| !matches!(tokens[*i].0, Token::Whitespace(_)))
+}
+
+/// i 是 PIVOT / UNPIVOT，返回改写后的参数和右括号的位置
+fn pivot_at(tokens: &[(Token, usize)], i: usize) -> Option<(Vec<(Token, usize)>, usize)> {
+    let open = next_significant(tokens, i + 1).filter(|j| tokens[*j].0 == Token::LParen)?;
+    let close = matching_paren(tokens, open)?;
+    Some((pivot_args(&tokens[open + 1..close]), close))
+}
+
+/// i 是 TABLESAMPLE，后面是 `BERNOULLI | SYSTEM (n [PERCENT | ROWS]) [REPEATABLE (seed)]`，
+/// 返回 `method, n, unit [, seed]` 和最后一个右括号的位置
+fn sample_at(tokens: &[(Token, usize)], i: usize) -> Option<(Vec<(Token, usize)>, usize)> {
+    let word = |j: usize| match &tokens[j].0 {
+        Token::Word(w) if w.quote_style.is_none() => Some(w.value.to_lowercase()),
+        _ => None,
+    };
+    // `(n [unit])` 或者 `(seed)` 里的数字和可选的单位
+    let group = |open: usize| -> Option<(Token, Option<String>, usize)> {
+        let close = matching_paren(tokens, open)?;
+        let inner: Vec<_> = (open + 1..close)
+            .filter(|j| !matches!(tokens[*j].0, Token::Whitespace(_)))
+            .collect();
+        match inner.as_slice() {
+            [n] if matches!(tokens[*n].0, Token::Number(..)) => {
+                Some((tokens[*n].0.clone(), None, close))
+            }
+            [n, unit] if matches!(tokens[*n].0, Token::Number(..)) => {
+                Some((tokens[*n].0.clone(), Some(word(*unit)?), close))
+            }
+            _ => None,
+        }
+    };
+
+    let method = next_significant(tokens, i + 1)?;
+    let method_name = word(method).filter(|m| m == "bernoulli" || m == "system")?;
+    let open = next_significant(tokens, method + 1).filter(|j| tokens[*j].0 == Token::LParen)?;
+    let (size, unit, mut end) = group(open)?;
+    let unit = unit.unwrap_or_else(|| "percent".into());
+    if unit != "percent" && unit != "rows" {
+        return None;
+    }
+
+    let offset = tokens[i].1;
+    let mut args = vec![
+        (Token::make_word(&method_name, None), tokens[method].1),
+        (Token::Comma, offset),
+        (size, tokens[open].1),
+        (Token::Comma, offset),
+        (Token::make_word(&unit, None), offset),
+    ];
+    let repeatable = next_significant(tokens, end + 1)
+        .filter(|j| word(*j).as_deref() == Some("repeatable"))
+        .and_then(|j| next_significant(tokens, j + 1))
+        .filter(|j| tokens[*j].0 == Token::LParen);
+    if let Some(open) = repeatable {
+        match group(open)? {
+            (seed, None, close) => {
+                args.push((Token::Comma, offset));
+                args.push((seed, tokens[open].1));
+                end = close;
+            }
+            _ => return None,
+        }
+    }
+    Some((args, end))
+}
+
+fn has_source(result: &[(Token, usize)], relation: Option<usize>) -> bool {
+    relation
+        .map(|start| {
+            result[start..]
+                .iter()
+                .any(|(t, _)| !matches!(t, Token::Whitespace(_)))
+        })
+        .unwrap_or(false)
+}
+
+/// 和 open 位置的左括号配对的右括号
+fn matching_paren(tokens: &[(Token, usize)], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, (token, _)) in tokens.iter().enumerate().skip(open) {
+        match token {
+            Token::LParen => depth += 1,
+            Token::RParen if depth == 1 => return Some(i),
+            Token::RParen => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// `sum(v) FOR k IN ('a', 'b')` 改写成 `sum(v), k, 'a', 'b'`
+fn pivot_args(tokens: &[(Token, usize)]) -> Vec<(Token, usize)> {
+    let mut args = Vec::with_capacity(tokens.len());
+    let mut depth = 0;
+    let mut after_in = false;
+    let mut in_list = false;
+
+    for (token, offset) in tokens {
+        match token {
+            Token::Whitespace(_) => continue,
+            Token::LParen if after_in => {
+                after_in = false;
+                in_list = true;
+                continue;
+            }
+            Token::RParen if in_list && depth == 0 => {
+                in_list = false;
+                continue;
+            }
+            Token::LParen => depth += 1,
+            Token::RParen => depth -= 1,
+            Token::Word(w) if depth == 0 && matches!(w.keyword, Keyword::FOR | Keyword::IN) => {
+                after_in = w.keyword == Keyword::IN;
+                args.push((Token::Comma, *offset));
+                continue;
+            }
+            _ => {}
+        }
+        after_in = false;
+        args.push((token.clone(), *offset));
+    }
+
+    args
+}
+
+fn parse_statement(parser: &mut Parser) -> Result<Stmt> {
+    // COPY table FROM ... 依旧交给 SqlParser
+    if parser.parse_keyword(Keyword::COPY) {
+        if parser.consume_token(&Token::LParen) {
+            return Ok(Stmt::CopyTo(parse_copy_to(parser)?));
+        }
+        parser.prev_token();
+    }
+
+    Ok(Stmt::Sql(parser.parse_statement()?))
+}
+
+/// 解析 COPY 之后的部分，此时左括号已经被消费掉了
+fn parse_copy_to(parser: &mut Parser) -> Result<CopyTo> {
+    let query = Box::new(parser.parse_query()?);
+    parser.expect_token(&Token::RParen)?;
+    parser.expect_keyword(Keyword::TO)?;
+    let target = parser.parse_literal_string()?;
+
+    let mut format = None;
+    if parser.parse_keyword(Keyword::WITH) {
+        parser.expect_token(&Token::LParen)?;
+        loop {
+            let option = parser.parse_identifier()?.value.to_lowercase();
+            let value = match parser.next_token() {
+                Token::Word(w) => w.value,
+                Token::SingleQuotedString(s) => s,
+                token => {
+                    return Err(parse_error(format!(
+                        "Expected option value, found: {}",
+                        token
+                    )))
+                }
+            };
+            match option.as_str() {
+                "format" => format = Some(value.parse()?),
+                v => {
+                    return Err(Error::unsupported(
+                        format!("COPY option {} is not supported", v),
+                        v,
+                    ))
+                }
+            }
+            if !parser.consume_token(&Token::Comma) {
+                break;
+            }
+        }
+        parser.expect_token(&Token::RParen)?;
+    }
+
+    // 没有指定 FORMAT 的时候，根据文件扩展名决定
+    let format = match format {
+        Some(format) => format,
+        None => Format::from_path(Path::new(&target)).ok_or_else(|| {
+            Error::unsupported(
+                format!("Cannot detect output format of {}", target),
+                &target,
+            )
+        })?,
+    };
+
+    Ok(CopyTo {
+        query,
+        target,
+        format,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_copy_to_works() {
+        let sql = "COPY (SELECT a FROM file://in.csv) TO 'file://out.json' WITH (FORMAT ndjson)";
+        let stmts = parse(sql).unwrap();
+        assert_eq!(stmts.len(), 1);
+        match &stmts[0] {
+            Stmt::CopyTo(copy) => {
+                assert_eq!(copy.target, "file://out.json");
+                assert_eq!(copy.format, Format::NdJson);
+            }
+            stmt => panic!("expect COPY TO, got {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn parse_multiple_statements_works() {
+        let sql = "CREATE VIEW v AS SELECT a FROM t; COPY (SELECT a FROM v) TO 'file://a.csv';;";
+        let stmts = parse(sql).unwrap();
+        assert_eq!(stmts.len(), 2);
+        assert_eq!(
+            stmts[1].to_string(),
+            "COPY (SELECT a FROM v) TO 'file://a.csv' WITH (FORMAT csv)"
+        );
+        assert!(parse("SELECT a FROM t SELECT b FROM t").is_err());
+    }
+
+    #[test]
+    fn parse_error_has_location() {
+        let sql = "SELECT a\nFROM t WHERE a = = 1";
+        match parse(sql).unwrap_err() {
+            Error::Parse { location, .. } => assert_eq!(
+                location,
+                Some(Location {
+                    line: 2,
+                    column: 18,
+                    len: 1
+                })
+            ),
+            e => panic!("expect parse error, got {:?}", e),
+        }
+
+        // 字符串里的转义不影响后面 token 的位置
+        let sql = "SELECT 'it''s' FROM t t2 t3";
+        match parse(sql).unwrap_err() {
+            Error::Parse { location, .. } => assert_eq!(
+                location,
+                Some(Location {
+                    line: 1,
+                    column: 26,
+                    len: 2
+                })
+            ),
+            e => panic!("expect parse error, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_error_location_with_crlf_works() {
+        // `\r\n` 占两个字节，算错的话后面的中文会从字符中间切开
+        let sql = "SELECT\r\n国家,\r\n人口 FROM t t2 t3";
+        match parse(sql).unwrap_err() {
+            Error::Parse { location, .. } => assert_eq!(
+                location,
+                Some(Location {
+                    line: 3,
+                    column: 14,
+                    len: 2
+                })
+            ),
+            e => panic!("expect parse error, got {:?}", e),
+        }
+        assert!(parse("SELECT\r\n国家 FROM t").is_ok());
+    }
+
+    #[test]
+    fn parse_copy_to_detects_format_by_extension() {
+        let sql = "COPY (SELECT a FROM file://in.csv) TO 'file://out.parquet'";
+        match &parse(sql).unwrap()[0] {
+            Stmt::CopyTo(copy) => assert_eq!(copy.format, Format::Parquet),
+            stmt => panic!("expect COPY TO, got {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn parse_pivot_works() {
+        let sql = "SELECT * FROM 't.csv' PIVOT (sum(v) FOR k IN ('a', 'b')) WHERE a > 1";
+        match &parse(sql).unwrap()[0] {
+            Stmt::Sql(stmt) => assert_eq!(
+                stmt.to_string(),
+                "SELECT * FROM pivot(\"t.csv\", sum(v), k, 'a', 'b') WHERE a > 1"
+            ),
+            stmt => panic!("expect SELECT, got {:?}", stmt),
+        }
+
+        let sql = "SELECT * FROM t UNPIVOT (value FOR metric IN (cases, deaths))";
+        match &parse(sql).unwrap()[0] {
+            Stmt::Sql(stmt) => assert_eq!(
+                stmt.to_string(),
+                "SELECT * FROM unpivot(t, value, metric, cases, deaths)"
+            ),
+            stmt => panic!("expect SELECT, got {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn parse_tablesample_works() {
+        let sql = "SELECT * FROM t TABLESAMPLE BERNOULLI (1.5) WHERE a > 1 LIMIT 10";
+        match &parse(sql).unwrap()[0] {
+            Stmt::Sql(stmt) => assert_eq!(
+                stmt.to_string(),
+                "SELECT * FROM tablesample(t, bernoulli, 1.5, percent) WHERE a > 1 LIMIT 10"
+            ),
+            stmt => panic!("expect SELECT, got {:?}", stmt),
+        }
+
+        let sql = "SELECT * FROM 'a.csv' TABLESAMPLE SYSTEM (100 ROWS) REPEATABLE (42)";
+        match &parse(sql).unwrap()[0] {
+            Stmt::Sql(stmt) => assert_eq!(
+                stmt.to_string(),
+                "SELECT * FROM tablesample(\"a.csv\", system, 100, rows, 42)"
+            ),
+            stmt => panic!("expect SELECT, got {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn parse_from_inside_function_is_not_source() {
+        let sql = "SELECT EXTRACT(year FROM '2021-01-31') FROM 't.csv'";
+        match &parse(sql).unwrap()[0] {
+            Stmt::Sql(stmt) => assert_eq!(
+                stmt.to_string(),
+                "SELECT EXTRACT(YEAR FROM '2021-01-31') FROM \"t.csv\""
+            ),
+            stmt => panic!("expect SELECT, got {:?}", stmt),
+        }
+
+        // 子查询里的数据源照样改写
+        let sql = "SELECT a FROM (SELECT a FROM 'a.csv') WHERE a IN (SELECT b FROM 'b.csv')";
+        match &parse(sql).unwrap()[0] {
+            Stmt::Sql(stmt) => assert_eq!(
+                stmt.to_string(),
+                "SELECT a FROM (SELECT a FROM \"a.csv\") WHERE a IN (SELECT b FROM \"b.csv\")"
+            ),
+            stmt => panic!("expect SELECT, got {:?}", stmt),
+        }
+    }
+
+    #[test]
+    fn parse_quoted_source_works() {
+        let sql = "SELECT a FROM 'https://a.com/data+v2.csv' WHERE b = 'x'";
+        match &parse(sql).unwrap()[0] {
+            Stmt::Sql(stmt) => assert_eq!(
+                stmt.to_string(),
+                "SELECT a FROM \"https://a.com/data+v2.csv\" WHERE b = 'x'"
+            ),
+            stmt => panic!("expect SELECT, got {:?}", stmt),
+        }
+    }
+}
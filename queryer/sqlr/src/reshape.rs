@@ -0,0 +1,305 @@
+use crate::error::{Error, Result};
+use polars::prelude::*;
+use sqlparser::ast::{Expr as SqlExpr, FunctionArg, Value as SqlValue};
+use std::str::FromStr;
+
+/// 在行和列之间转换数据
+///
+/// `t PIVOT (sum(v) FOR k IN ('a', 'b'))` 在解析时改写成表函数 `pivot(t, sum(v), k, 'a', 'b')`，
+/// `t UNPIVOT (v FOR k IN (a, b))` 改写成 `unpivot(t, v, k, a, b)`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reshape {
+    /// on 列的每个值变成一列，列的值是同一组里 value 的聚合。
+    /// values 为空时生成所有出现过的值，否则只生成 values 里的列
+    Pivot {
+        agg: PivotAgg,
+        value: String,
+        on: String,
+        values: Vec<String>,
+    },
+    /// columns 里的每一列变成一行，列名放在 name 列，值放在 value 列，值为 null 的行会被去掉
+    Unpivot {
+        value: String,
+        name: String,
+        columns: Vec<String>,
+    },
+}
+
+/// PIVOT 支持的聚合函数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PivotAgg {
+    Sum,
+    Mean,
+    Min,
+    Max,
+    Count,
+    First,
+    Last,
+    Median,
+}
+
+impl FromStr for PivotAgg {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "sum" => Ok(PivotAgg::Sum),
+            "avg" | "mean" => Ok(PivotAgg::Mean),
+            "min" => Ok(PivotAgg::Min),
+            "max" => Ok(PivotAgg::Max),
+            "count" => Ok(PivotAgg::Count),
+            "first" => Ok(PivotAgg::First),
+            "last" => Ok(PivotAgg::Last),
+            "median" => Ok(PivotAgg::Median),
+            _ => Err(Error::unsupported(
+                format!("PIVOT does not support aggregation {}", s),
+                s,
+            )),
+        }
+    }
+}
+
+/// 列名可以是标识符，也可以是字符串或者数字（比如 `IN (2020, 2021)`）
+pub(crate) fn name_of(expr: &SqlExpr) -> Option<&str> {
+    match expr {
+        SqlExpr::Identifier(id) => Some(&id.value),
+        SqlExpr::Value(SqlValue::SingleQuotedString(s))
+        | SqlExpr::Value(SqlValue::Number(s, _)) => Some(s),
+        _ => None,
+    }
+}
+
+fn names(args: &[&SqlExpr]) -> Result<Vec<String>> {
+    args.iter()
+        .map(|arg| {
+            name_of(arg)
+                .map(String::from)
+                .ok_or_else(|| Error::unsupported("Expect a column name", arg))
+        })
+        .collect()
+}
+
+impl Reshape {
+    /// pivot 表函数除数据源以外的参数：`sum(v), k [, 'a', 'b' ...]`
+    pub(crate) fn pivot(args: &[&SqlExpr]) -> Result<Self> {
+        let (agg, value) = match args.first() {
+            Some(SqlExpr::Function(f)) => match f.args.as_slice() {
+                [FunctionArg::Unnamed(arg)] => (f.name.to_string().parse()?, name_of(arg)),
+                _ => (f.name.to_string().parse()?, None),
+            },
+            _ => (PivotAgg::Sum, None),
+        };
+        let value = value.ok_or_else(|| {
+            Error::unsupported(
+                "PIVOT expects an aggregation of a column, like sum(value)",
+                args.first().map(|a| a.to_string()).unwrap_or_default(),
+            )
+        })?;
+        let on = args
+            .get(1)
+            .and_then(|arg| name_of(arg))
+            .ok_or_else(|| Error::unsupported("PIVOT expects FOR column", "PIVOT"))?;
+        Ok(Reshape::Pivot {
+            agg,
+            value: value.to_owned(),
+            on: on.to_owned(),
+            values: names(&args[2..])?,
+        })
+    }
+
+    /// unpivot 表函数除数据源以外的参数：`v, k, a, b ...`
+    pub(crate) fn unpivot(args: &[&SqlExpr]) -> Result<Self> {
+        match names(args)?.as_slice() {
+            [value, name, columns @ ..] if !columns.is_empty() => Ok(Reshape::Unpivot {
+                value: value.clone(),
+                name: name.clone(),
+                columns: columns.to_vec(),
+            }),
+            _ => Err(Error::unsupported(
+                "UNPIVOT expects (value FOR name IN (columns...))",
+                "UNPIVOT",
+            )),
+        }
+    }
+
+    pub(crate) fn apply(&self, df: DataFrame) -> Result<DataFrame> {
+        match self {
+            Reshape::Pivot {
+                agg,
+                value,
+                on,
+                values,
+            } => pivot(df, *agg, value, on, values),
+            Reshape::Unpivot {
+                value,
+                name,
+                columns,
+            } => unpivot(df, value, name, columns),
+        }
+    }
+}
+
+/// 除了 on 和 value 以外的列都是分组的键
+fn pivot(
+    mut df: DataFrame,
+    agg: PivotAgg,
+    value: &str,
+    on: &str,
+    values: &[String],
+) -> Result<DataFrame> {
+    // 生成的列名来自 on 列的值
+    let keys = df.column(on)?.cast::<Utf8Type>()?;
+    df.with_column(keys)?;
+
+    // 没有别的列时整个表是一组
+    const ALL: &str = "__pivot_all";
+    let mut index: Vec<String> = df
+        .get_column_names()
+        .into_iter()
+        .filter(|c| *c != on && *c != value)
+        .map(String::from)
+        .collect();
+    if index.is_empty() {
+        df.with_column(UInt32Chunked::full(ALL, 0, df.height()).into_series())?;
+        index.push(ALL.to_owned());
+    }
+
+    let mut gb = df.groupby(&index)?;
+    let pivoted = gb.pivot(on, value);
+    let mut out = match agg {
+        PivotAgg::Sum => pivoted.sum(),
+        PivotAgg::Mean => pivoted.mean(),
+        PivotAgg::Min => pivoted.min(),
+        PivotAgg::Max => pivoted.max(),
+        PivotAgg::Count => pivoted.count(),
+        PivotAgg::First => pivoted.first(),
+        PivotAgg::Last => pivoted.last(),
+        PivotAgg::Median => pivoted.median(),
+    }?;
+
+    // 指定了 IN (...) 时按给定的顺序输出，没有出现过的值是一列 null；否则按列名排序
+    let generated = if values.is_empty() {
+        let mut generated: Vec<String> = out
+            .get_column_names()
+            .into_iter()
+            .filter(|c| !index.iter().any(|i| i.as_str() == *c))
+            .map(String::from)
+            .collect();
+        generated.sort();
+        generated
+    } else {
+        values.to_vec()
+    };
+    for name in &generated {
+        if out.column(name).is_err() {
+            out.with_column(Float64Chunked::full_null(name, out.height()).into_series())?;
+        }
+    }
+
+    let columns: Vec<String> = index
+        .into_iter()
+        .filter(|c| c != ALL)
+        .chain(generated)
+        .collect();
+    Ok(out.select(&columns)?)
+}
+
+fn unpivot(mut df: DataFrame, value: &str, name: &str, columns: &[String]) -> Result<DataFrame> {
+    // 类型不同的列不能放在同一列里：都是数字时转换成 Float64，否则转换成文本
+    let dtypes: Vec<DataType> = columns
+        .iter()
+        .map(|c| df.column(c).map(|s| s.dtype().clone()))
+        .collect::<std::result::Result<_, _>>()?;
+    if dtypes.windows(2).any(|w| w[0] != w[1]) {
+        let numeric = dtypes.iter().all(|t| {
+            matches!(
+                t,
+                DataType::Int8
+                    | DataType::Int16
+                    | DataType::Int32
+                    | DataType::Int64
+                    | DataType::UInt8
+                    | DataType::UInt16
+                    | DataType::UInt32
+                    | DataType::UInt64
+                    | DataType::Float32
+                    | DataType::Float64
+            )
+        });
+        for c in columns {
+            let s = df.column(c)?;
+            let s = if numeric {
+                s.cast::<Float64Type>()?
+            } else {
+                s.cast::<Utf8Type>()?
+            };
+            df.with_column(s)?;
+        }
+    }
+
+    let id_vars: Vec<String> = df
+        .get_column_names()
+        .into_iter()
+        .filter(|c| !columns.iter().any(|v| v.as_str() == *c))
+        .map(String::from)
+        .collect();
+    let mut out = df.melt(&id_vars, columns)?;
+    if name != "variable" {
+        out.rename("variable", name)?;
+    }
+    if value != "value" {
+        out.rename("value", value)?;
+    }
+    Ok(out.drop_nulls(Some(&[value.to_owned()]))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn long() -> DataFrame {
+        df!(
+            "country" => &["a", "a", "b", "b", "b"],
+            "metric" => &["cases", "deaths", "cases", "deaths", "cases"],
+            "value" => &[10i64, 1, 20, 2, 5]
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn pivot_and_unpivot_work() {
+        let pivot = Reshape::Pivot {
+            agg: PivotAgg::Sum,
+            value: "value".into(),
+            on: "metric".into(),
+            values: vec![],
+        };
+        let wide = pivot.apply(long()).unwrap().sort("country", false).unwrap();
+        assert_eq!(wide.get_column_names(), vec!["country", "cases", "deaths"]);
+        assert_eq!(wide.column("cases").unwrap().sum::<i64>(), Some(35));
+
+        let unpivot = Reshape::Unpivot {
+            value: "value".into(),
+            name: "metric".into(),
+            columns: vec!["cases".into(), "deaths".into()],
+        };
+        let back = unpivot.apply(wide).unwrap();
+        assert_eq!(back.get_column_names(), vec!["country", "metric", "value"]);
+        assert_eq!(back.height(), 4);
+        assert_eq!(back.column("value").unwrap().sum::<i64>(), Some(38));
+    }
+
+    #[test]
+    fn pivot_with_values_works() {
+        let pivot = Reshape::Pivot {
+            agg: PivotAgg::Count,
+            value: "value".into(),
+            on: "metric".into(),
+            values: vec!["deaths".into(), "recovered".into()],
+        };
+        let wide = pivot.apply(long().drop("country").unwrap()).unwrap();
+        assert_eq!(wide.get_column_names(), vec!["deaths", "recovered"]);
+        assert_eq!(wide.height(), 1);
+        assert_eq!(wide.column("recovered").unwrap().null_count(), 1);
+    }
+}
@@ -0,0 +1,309 @@
+use crate::error::{Error, Result};
+use polars::prelude::*;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor,
+};
+use serde::forward_to_deserialize_any;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// 逐行把 DataSet 反序列化成 T，不会一次性生成所有的 T
+pub struct Rows<'a, T> {
+    columns: &'a [Series],
+    row: usize,
+    height: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, T> Rows<'a, T> {
+    pub(crate) fn new(df: &'a DataFrame) -> Self {
+        Self {
+            columns: df.get_columns(),
+            row: 0,
+            height: df.height(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: DeserializeOwned> Iterator for Rows<'a, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.row >= self.height {
+            return None;
+        }
+        let row = self.row;
+        self.row += 1;
+
+        let de = RowDeserializer {
+            columns: self.columns,
+            row,
+        };
+        Some(T::deserialize(de).map_err(|e| Error::Deserialize {
+            row,
+            column: e.column,
+            message: e.message,
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.height - self.row;
+        (n, Some(n))
+    }
+}
+
+/// 反序列化过程中的错误，column 记录出错的列
+#[derive(Debug)]
+struct DeError {
+    column: Option<String>,
+    message: String,
+}
+
+impl DeError {
+    fn in_column(mut self, name: &str) -> Self {
+        self.column.get_or_insert_with(|| name.to_owned());
+        self
+    }
+}
+
+impl de::Error for DeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self {
+            column: None,
+            message: msg.to_string(),
+        }
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        Self {
+            column: Some(field.to_owned()),
+            message: format!("missing column `{}`", field),
+        }
+    }
+}
+
+impl fmt::Display for DeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for DeError {}
+
+/// 一行数据：结构体和 map 按列名取值，tuple 和 Vec 按列的顺序取值
+struct RowDeserializer<'a> {
+    columns: &'a [Series],
+    row: usize,
+}
+
+impl<'de, 'a> de::Deserializer<'de> for RowDeserializer<'a> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_map(RowAccess {
+            columns: self.columns,
+            row: self.row,
+            index: 0,
+        })
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_seq(RowAccess {
+            columns: self.columns,
+            row: self.row,
+            index: 0,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct map struct enum
+        identifier ignored_any
+    }
+}
+
+struct RowAccess<'a> {
+    columns: &'a [Series],
+    row: usize,
+    index: usize,
+}
+
+impl<'a> RowAccess<'a> {
+    fn next_value<'de, T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, DeError> {
+        let series = &self.columns[self.index];
+        self.index += 1;
+        seed.deserialize(ValueDeserializer(series.get(self.row)))
+            .map_err(|e| e.in_column(series.name()))
+    }
+}
+
+impl<'de, 'a> MapAccess<'de> for RowAccess<'a> {
+    type Error = DeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, DeError> {
+        match self.columns.get(self.index) {
+            Some(series) => seed
+                .deserialize(series.name().into_deserializer())
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, DeError> {
+        self.next_value(seed)
+    }
+}
+
+impl<'de, 'a> SeqAccess<'de> for RowAccess<'a> {
+    type Error = DeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, DeError> {
+        if self.index >= self.columns.len() {
+            return Ok(None);
+        }
+        self.next_value(seed).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.columns.len() - self.index)
+    }
+}
+
+/// 单元格的值
+struct ValueDeserializer<'a>(AnyValue<'a>);
+
+impl<'de, 'a> de::Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0 {
+            AnyValue::Null => visitor.visit_unit(),
+            AnyValue::Boolean(v) => visitor.visit_bool(v),
+            AnyValue::Utf8(v) => visitor.visit_str(v),
+            AnyValue::UInt8(v) => visitor.visit_u8(v),
+            AnyValue::UInt16(v) => visitor.visit_u16(v),
+            AnyValue::UInt32(v) => visitor.visit_u32(v),
+            AnyValue::UInt64(v) => visitor.visit_u64(v),
+            AnyValue::Int8(v) => visitor.visit_i8(v),
+            AnyValue::Int16(v) => visitor.visit_i16(v),
+            AnyValue::Int32(v) => visitor.visit_i32(v),
+            AnyValue::Int64(v) => visitor.visit_i64(v),
+            AnyValue::Float32(v) => visitor.visit_f32(v),
+            AnyValue::Float64(v) => visitor.visit_f64(v),
+            // 日期等其它类型按它们的文本形式反序列化
+            v => visitor.visit_string(v.to_string()),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0 {
+            AnyValue::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct seq tuple tuple_struct
+        map struct enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DataSet, Error};
+    use polars::prelude::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Country {
+        name: String,
+        cases: f64,
+        deaths: Option<i64>,
+    }
+
+    fn sample() -> DataSet {
+        DataSet(
+            df!(
+                "name" => &["a", "b"],
+                "cases" => &[1.5, 2.0],
+                "deaths" => &[Some(1i64), None]
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn rows_works() {
+        let rows: Vec<Country> = sample().rows().collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                Country {
+                    name: "a".into(),
+                    cases: 1.5,
+                    deaths: Some(1)
+                },
+                Country {
+                    name: "b".into(),
+                    cases: 2.0,
+                    deaths: None
+                },
+            ]
+        );
+
+        let tuples: Vec<(String, f64)> = sample().rows().collect::<Result<_, _>>().unwrap();
+        assert_eq!(tuples[1], ("b".into(), 2.0));
+    }
+
+    #[test]
+    fn rows_reports_missing_column_and_type_mismatch() {
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Missing {
+            name: String,
+            population: i64,
+        }
+        match sample().rows::<Missing>().next().unwrap().unwrap_err() {
+            Error::Deserialize { row, column, .. } => {
+                assert_eq!(row, 0);
+                assert_eq!(column.as_deref(), Some("population"));
+            }
+            e => panic!("expect deserialize error, got {:?}", e),
+        }
+
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Mismatch {
+            name: i64,
+        }
+        match sample().rows::<Mismatch>().next().unwrap().unwrap_err() {
+            Error::Deserialize { column, .. } => assert_eq!(column.as_deref(), Some("name")),
+            e => panic!("expect deserialize error, got {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use crate::error::{Error, Result};
+use crate::reshape::name_of;
+use polars::prelude::*;
+use sqlparser::ast::{Expr as SqlExpr, Value as SqlValue};
+
+/// SYSTEM 抽样按块选择，每块这么多行，和 PostgreSQL 按数据页抽样类似
+const BLOCK_ROWS: usize = 1024;
+
+/// `TABLESAMPLE BERNOULLI (1)`、`TABLESAMPLE SYSTEM (100 ROWS) REPEATABLE (42)`
+///
+/// 在解析时改写成表函数 `tablesample(t, bernoulli, 1, percent)`。
+/// 指定了 REPEATABLE 时同样的数据每次得到同样的样本
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub method: SampleMethod,
+    pub size: SampleSize,
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleMethod {
+    /// 每一行独立地以给定的概率被选中
+    Bernoulli,
+    /// 以块为单位选择，比逐行选择快，但样本里的行是成块出现的
+    System,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleSize {
+    Percent(f64),
+    /// 固定的行数，不管哪种方法都是从所有行里均匀地选择
+    Rows(usize),
+}
+
+impl Sample {
+    /// tablesample 表函数除数据源以外的参数：`method, n, unit [, seed]`
+    pub(crate) fn from_args(args: &[&SqlExpr]) -> Result<Self> {
+        let invalid = || {
+            let args: Vec<_> = args.iter().map(|a| a.to_string()).collect();
+            Error::unsupported(
+                "TABLESAMPLE expects BERNOULLI | SYSTEM (n [PERCENT | ROWS]) [REPEATABLE (seed)]",
+                args.join(", "),
+            )
+        };
+        let number = |expr: &SqlExpr| match expr {
+            SqlExpr::Value(SqlValue::Number(n, _)) => n.parse::<f64>().ok(),
+            _ => None,
+        };
+
+        let (method, n, unit, seed) = match args {
+            [method, n, unit] => (method, n, unit, None),
+            [method, n, unit, seed] => (method, n, unit, Some(seed)),
+            _ => return Err(invalid()),
+        };
+        let method = match name_of(method).map(|m| m.to_lowercase()).as_deref() {
+            Some("bernoulli") => SampleMethod::Bernoulli,
+            Some("system") => SampleMethod::System,
+            _ => return Err(invalid()),
+        };
+        let n = number(n).filter(|n| *n >= 0.0).ok_or_else(invalid)?;
+        let size = match name_of(unit).map(|u| u.to_lowercase()).as_deref() {
+            Some("percent") if n <= 100.0 => SampleSize::Percent(n),
+            Some("rows") if n.fract() == 0.0 => SampleSize::Rows(n as usize),
+            _ => return Err(invalid()),
+        };
+        let seed = match seed {
+            Some(seed) => Some(
+                number(seed)
+                    .filter(|s| *s >= 0.0 && s.fract() == 0.0)
+                    .ok_or_else(invalid)? as u64,
+            ),
+            None => None,
+        };
+
+        Ok(Self { method, size, seed })
+    }
+
+    pub(crate) fn apply(&self, df: DataFrame) -> Result<DataFrame> {
+        let seed = self.seed.unwrap_or_else(|| {
+            // wasm 里没有 SystemTime，chrono 在 wasm 下用 js 的 Date 取得时间。
+            // timestamp_nanos 在新版本的 chrono 里废弃了，自己拼出纳秒数
+            let now = chrono::Utc::now();
+            (now.timestamp() as u64)
+                .wrapping_mul(1_000_000_000)
+                .wrapping_add(now.timestamp_subsec_nanos() as u64)
+        });
+        let mut rng = Rng::new(seed);
+        let height = df.height();
+
+        let mask: Vec<bool> = match self.size {
+            SampleSize::Rows(n) if n >= height => return Ok(df),
+            SampleSize::Rows(n) => {
+                // 蓄水池抽样选出 n 行，结果保持原来的顺序
+                let mut chosen: Vec<usize> = (0..n).collect();
+                for i in n..height {
+                    let j = rng.below(i as u64 + 1) as usize;
+                    if j < n {
+                        chosen[j] = i;
+                    }
+                }
+                let mut mask = vec![false; height];
+                for i in chosen {
+                    mask[i] = true;
+                }
+                mask
+            }
+            SampleSize::Percent(p) => {
+                let p = p / 100.0;
+                match self.method {
+                    SampleMethod::Bernoulli => (0..height).map(|_| rng.next_f64() < p).collect(),
+                    SampleMethod::System => {
+                        let blocks: Vec<bool> = (0..height.div_ceil(BLOCK_ROWS))
+                            .map(|_| rng.next_f64() < p)
+                            .collect();
+                        (0..height).map(|i| blocks[i / BLOCK_ROWS]).collect()
+                    }
+                }
+            }
+        };
+
+        let mask: BooleanChunked = mask.into_iter().map(Some).collect();
+        Ok(df.filter(&mask)?)
+    }
+}
+
+/// 可以指定种子的伪随机数生成器（xorshift64*），只用于抽样，不适合用于安全相关的场景
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        // 用 splitmix64 打散种子，避免 0 和相近的种子得到相近的序列
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Self((z ^ (z >> 31)).max(1))
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// [0, 1) 之间的浮点数
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// [0, n) 之间的整数
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 正好 100 块
+    const ROWS: i64 = 100 * BLOCK_ROWS as i64;
+
+    fn sample(method: SampleMethod, size: SampleSize, seed: u64) -> DataFrame {
+        let sample = Sample {
+            method,
+            size,
+            seed: Some(seed),
+        };
+        sample
+            .apply(df!("v" => (0..ROWS).collect::<Vec<_>>()).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn sample_is_reproducible() {
+        let a = sample(SampleMethod::Bernoulli, SampleSize::Percent(1.0), 42);
+        let b = sample(SampleMethod::Bernoulli, SampleSize::Percent(1.0), 42);
+        let c = sample(SampleMethod::Bernoulli, SampleSize::Percent(1.0), 7);
+        assert!(a.frame_equal(&b));
+        assert!(!a.frame_equal(&c));
+        // 期望 1024 行，标准差约 32 行
+        assert!((a.height() as i64 - 1024).abs() < 200);
+    }
+
+    #[test]
+    fn sample_rows_and_blocks_work() {
+        let df = sample(SampleMethod::System, SampleSize::Rows(100), 1);
+        assert_eq!(df.height(), 100);
+        // 保持原来的顺序
+        let v: Vec<_> = df
+            .column("v")
+            .unwrap()
+            .i64()
+            .unwrap()
+            .into_no_null_iter()
+            .collect();
+        assert!(v.windows(2).all(|w| w[0] < w[1]));
+
+        let df = sample(SampleMethod::System, SampleSize::Percent(10.0), 1);
+        assert_eq!(df.height() % BLOCK_ROWS, 0);
+        assert_eq!(
+            sample(SampleMethod::System, SampleSize::Rows(1_000_000), 1).height(),
+            ROWS as usize
+        );
+    }
+}
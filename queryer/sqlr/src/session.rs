@@ -0,0 +1,1869 @@
+use crate::cache::{CacheStats, ResultCache, ResultCacheConfig};
+use crate::convert::{calls_volatile, Context, Sql, Udf, Udfs};
+use crate::error::{Error, Result};
+use crate::excel::{spreadsheet_source, ExcelLoader};
+use crate::fetcher::{
+    open_body, read_header, retrieve_data, spill_data, validator, write_file, Body, Spilled,
+};
+use crate::html::is_html;
+use crate::json::{read_json, JsonOptions};
+use crate::limits::{guard, QueryOptions};
+use crate::loader::{detect_content, Load, INFER_SCHEMA_ROWS};
+use crate::params::{Params, Prepared};
+use crate::parser::{parse, CopyTo, Stmt};
+use crate::sqlite::{self, is_sqlite_source, SqliteLoader};
+use crate::stats::{traced_fetch, QueryStats};
+use crate::stream::{
+    batches_of, csv_batches, is_row_wise, read_record, record_batches, RecordBatchStream, RowPlan,
+};
+use crate::temporal;
+#[cfg(feature = "native")]
+use crate::watch::Watch;
+use crate::DataSet;
+use futures::TryStreamExt;
+use instant::Instant;
+use polars::prelude::*;
+use serde::de::DeserializeOwned;
+use sqlparser::ast::{Expr as SqlExpr, ObjectType, Query, Statement};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tracing::{field, info_span, Instrument, Span};
+use url::Url;
+
+/// 提前结束的 LIMIT 查询每次至少读取这么多行，第一块数据用来推断 csv 每一列的类型
+const HEAD_BATCH_ROWS: usize = 1024;
+
+/// 每次调用结果都可能不同的内置函数，用到它们的查询不缓存结果
+const VOLATILE_FUNCTIONS: [&str; 4] = ["now", "current_timestamp", "current_date", "current_time"];
+
+/// 查找查询用到的数据源时最多展开这么多层视图
+const MAX_VIEW_DEPTH: usize = 16;
+
+/// catalog 的版本号，每次修改 catalog 都取一个新的，clone 出来的 session 各自修改也不会重复
+static CATALOG_VERSION: AtomicU64 = AtomicU64::new(1);
+
+/// Session 的配置
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// 是否缓存已经读取过的数据源，同一个 session 里再次查询时不用重新下载
+    pub cache_sources: bool,
+    /// 是否把数据源先落地到磁盘再用 LazyCsvReader 扫描，
+    /// 这样 filter 和 select 会下推到读取 csv 的过程中，适合比内存还大的数据源
+    pub spill_to_disk: bool,
+    /// 允许查询的数据源，可以是地址的前缀（比如 `https://example.com/data/`）或者主机名，
+    /// None 表示不做限制，空列表表示只能查询注册过的表。
+    /// 只检查直接写在 SQL 里的地址，注册过的表总是可以查询
+    pub allowed_sources: Option<Vec<String>>,
+    /// 允许 COPY ... TO 写入的地址前缀，格式和 allowed_sources 一样。默认是空的，不能写入任何文件
+    pub allowed_targets: Vec<String>,
+    /// 默认的查询选项，[`Session::query_with`] 可以为单次查询指定别的选项
+    pub query_options: QueryOptions,
+    /// 查询结果缓存，None 表示不缓存。同样的查询（规范化之后的 SQL 和参数相同），
+    /// 在数据源和 catalog 都没有变化、也没有过期时直接返回缓存的结果。
+    /// 缓存在 clone 出来的 session 之间共享
+    pub result_cache: Option<ResultCacheConfig>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            cache_sources: true,
+            spill_to_disk: false,
+            allowed_sources: None,
+            allowed_targets: Vec::new(),
+            query_options: QueryOptions::default(),
+            result_cache: None,
+        }
+    }
+}
+
+/// catalog 里的一张表
+#[derive(Debug, Clone)]
+enum Table {
+    /// 数据源地址，查询时才去读取
+    Source(String),
+    /// 已经在内存里的数据
+    Data(DataFrame),
+    /// CREATE VIEW 定义的视图，查询时展开
+    View(Box<Query>),
+}
+
+/// 查询的上下文：维护表的 catalog、配置、Udf 以及数据源缓存
+#[derive(Clone, Default)]
+pub struct Session {
+    config: SessionConfig,
+    tables: HashMap<String, Table>,
+    udfs: Arc<Udfs>,
+    /// 用 register_volatile_udf 注册的函数名
+    volatile_udfs: HashSet<String>,
+    cache: HashMap<String, DataFrame>,
+    spills: HashMap<String, Arc<Spilled>>,
+    results: Arc<Mutex<ResultCache>>,
+    /// catalog 的版本号，是结果缓存的键的一部分
+    catalog: u64,
+    /// 正在执行的查询的选项，每次查询开始时设置
+    options: QueryOptions,
+    /// 最近一次查询的统计，每次查询开始时清空
+    stats: QueryStats,
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// 待执行的数据，如果数据落地在磁盘上，还需要持有落地的文件直到执行结束
+type Scan = (LazyFrame, Option<Arc<Spilled>>);
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: SessionConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    pub fn config_mut(&mut self) -> &mut SessionConfig {
+        &mut self.config
+    }
+
+    /// 把数据源注册成表，之后可以用 `SELECT ... FROM name` 查询
+    pub fn register(&mut self, name: impl Into<String>, source: impl Into<String>) {
+        self.tables
+            .insert(name.into(), Table::Source(source.into()));
+        self.touch();
+    }
+
+    /// 把已有的 DataSet 注册成表
+    pub fn register_dataset(&mut self, name: impl Into<String>, ds: DataSet) {
+        self.tables.insert(name.into(), Table::Data(ds.0));
+        self.touch();
+    }
+
+    /// 注册用户自定义函数，函数名不区分大小写
+    ///
+    /// 结果缓存假定函数对同样的输入总是返回同样的结果，不是这样的函数用 [`Session::register_volatile_udf`]
+    pub fn register_udf<F>(&mut self, name: impl AsRef<str>, f: F)
+    where
+        F: Fn(Vec<Expr>) -> Result<Expr> + Send + Sync + 'static,
+    {
+        let name = name.as_ref().to_lowercase();
+        self.volatile_udfs.remove(&name);
+        let udf: Udf = Arc::new(f);
+        Arc::make_mut(&mut self.udfs).insert(name, udf);
+        self.touch();
+    }
+
+    /// 注册每次调用结果都可能不同的函数（比如随机数），用到它的查询不会被结果缓存
+    pub fn register_volatile_udf<F>(&mut self, name: impl AsRef<str>, f: F)
+    where
+        F: Fn(Vec<Expr>) -> Result<Expr> + Send + Sync + 'static,
+    {
+        self.register_udf(&name, f);
+        self.volatile_udfs.insert(name.as_ref().to_lowercase());
+    }
+
+    /// 删除注册的表或者视图，返回它是否存在
+    pub fn deregister(&mut self, name: &str) -> bool {
+        self.touch();
+        self.tables.remove(name).is_some()
+    }
+
+    /// 已注册的表名，按字母排序
+    pub fn table_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.tables.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// 清空数据源缓存和结果缓存
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+        self.spills.clear();
+        self.results().clear();
+    }
+
+    /// 结果缓存的命中次数、占用的内存等统计
+    pub fn cache_stats(&self) -> CacheStats {
+        self.results().stats()
+    }
+
+    fn results(&self) -> MutexGuard<'_, ResultCache> {
+        self.results.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// catalog 变化之后，之前缓存的结果都不会再命中
+    fn touch(&mut self) {
+        self.catalog = CATALOG_VERSION.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 执行一条 SQL，支持 SELECT、CREATE VIEW、CREATE TABLE ... AS、DROP 和 COPY ... TO
+    pub async fn query<T: AsRef<str>>(&mut self, sql: T) -> Result<DataSet> {
+        let options = self.config.query_options.clone();
+        self.query_with(sql, options).await
+    }
+
+    /// 和 query 一样，但使用指定的超时、资源限制和取消句柄
+    pub async fn query_with<T: AsRef<str>>(
+        &mut self,
+        sql: T,
+        options: QueryOptions,
+    ) -> Result<DataSet> {
+        let sql = sql.as_ref();
+        let start = Instant::now();
+        self.stats = QueryStats::default();
+        let span = info_span!("query", sql, rows = field::Empty);
+        let stmts = span.in_scope(|| parse(sql))?;
+        self.stats.parse = start.elapsed();
+
+        if stmts.len() != 1 {
+            return Err(Error::unsupported(
+                "Only support single sql in query, use execute_script for multiple statements",
+                sql,
+            ));
+        }
+
+        self.options = options.clone();
+        let result = guard(&options, self.execute(&stmts[0], &Params::new()))
+            .instrument(span.clone())
+            .await
+            .map_err(|e| e.locate(sql));
+        self.finish(start, &span, &result);
+        result
+    }
+
+    /// 和 query 一样，同时返回各个阶段的用时和数据量
+    pub async fn query_with_stats<T: AsRef<str>>(
+        &mut self,
+        sql: T,
+    ) -> Result<(DataSet, QueryStats)> {
+        let ds = self.query(sql).await?;
+        Ok((ds, self.stats.clone()))
+    }
+
+    /// 最近一次查询的统计，查询失败时只有失败之前的阶段
+    pub fn last_stats(&self) -> &QueryStats {
+        &self.stats
+    }
+
+    /// 执行查询，并按列名把每一行反序列化成 T
+    pub async fn query_as<T, S>(&mut self, sql: S) -> Result<Vec<T>>
+    where
+        T: DeserializeOwned,
+        S: AsRef<str>,
+    {
+        self.query(sql).await?.to_vec()
+    }
+
+    /// 以流的形式返回 SELECT 的结果，每一批最多 batch_size 行
+    ///
+    /// 如果查询没有 ORDER BY、投影都是逐行计算的表达式，并且数据源是 csv，
+    /// 会把数据源落地到磁盘后分块读取、过滤和投影，内存里同时只有一块数据；
+    /// 否则先执行整个查询，再把结果分批返回。
+    pub async fn query_stream<T: AsRef<str>>(
+        &mut self,
+        sql: T,
+        batch_size: usize,
+    ) -> Result<RecordBatchStream> {
+        let sql = sql.as_ref();
+        let stmts = parse(sql)?;
+        let stmt = match stmts.as_slice() {
+            [Stmt::Sql(stmt @ Statement::Query(_))] => stmt,
+            _ => {
+                return Err(Error::unsupported(
+                    "Only support single SELECT in query_stream",
+                    sql,
+                ))
+            }
+        };
+
+        // 选项只作用于准备数据的阶段，返回的流由调用者决定什么时候停止
+        let options = self.begin();
+        guard(&options, self.stream(stmt, sql, batch_size)).await
+    }
+
+    async fn stream(
+        &mut self,
+        stmt: &Statement,
+        sql: &str,
+        batch_size: usize,
+    ) -> Result<RecordBatchStream> {
+        let params = Params::new();
+        let udfs = self.udfs.clone();
+        let ctx = Context {
+            udfs: Some(&udfs),
+            params: Some(&params),
+        };
+        let sql = Sql::with_context(stmt, ctx).map_err(|e| e.locate(sql))?;
+
+        let url = match self.csv_source(&sql)? {
+            Some(url) if is_plain_csv(&url).await => Some(url),
+            _ => None,
+        };
+        match url {
+            Some(url) if is_chunked(&sql) => {
+                let plan = RowPlan {
+                    condition: sql.condition,
+                    selection: sql.selection,
+                    offset: sql.offset.unwrap_or(0) as usize,
+                    limit: sql.limit.unwrap_or(usize::MAX),
+                };
+                let spilled = self.spill(url).await?;
+                csv_batches(spilled, plan, batch_size).await
+            }
+            _ => Ok(batches_of(self.select(stmt, &params).await?, batch_size)),
+        }
+    }
+
+    /// 可能按行分块读取的 csv 数据源：不是页面里的表格、数据库的表或者 JSON API，
+    /// 也没有抽样和 PIVOT 这样需要看到全部数据的操作。还要用 is_plain_csv 检查文件头
+    fn csv_source(&self, sql: &Sql) -> Result<Option<String>> {
+        if sql.table.is_some()
+            || sql.json.is_some()
+            || sql.reshape.is_some()
+            || sql.sample.is_some()
+        {
+            return Ok(None);
+        }
+        let url = match self.tables.get(sql.source) {
+            Some(Table::Source(url)) => url.clone(),
+            None => {
+                self.check_source(sql.source)?;
+                sql.source.to_owned()
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(url).filter(|url| !url.contains('#')))
+    }
+
+    /// 分块读取 csv 数据源的开头，读够了 plan.limit 行就停止，不用下载和解析整个数据源
+    ///
+    /// 数据源其实是 HTML 页面、已经缓存在内存里，或者没有读到任何一行时返回 None，
+    /// 交给普通的方式执行，这样结果的列名总是正确的
+    async fn read_head(&mut self, url: &str, plan: RowPlan) -> Result<Option<DataFrame>> {
+        if self.cache.contains_key(url) {
+            return Ok(None);
+        }
+        let (mut reader, spilled) = match self.spills.get(url) {
+            Some(spilled) => (
+                Body::open_file(spilled.path()).await?,
+                Some(spilled.clone()),
+            ),
+            None => {
+                let check = |url: &str| self.check_source(url);
+                let open = open_body(url, self.options.max_bytes, &check);
+                let (body, elapsed, _) = traced_fetch(url, open, |_| 0).await;
+                self.fetched(elapsed, 0);
+                (body?, None)
+            }
+        };
+        let mut header = String::new();
+        read_record(&mut reader, &mut header).await?;
+        if header.trim().is_empty() || is_html(&header) {
+            return Ok(None);
+        }
+
+        Span::current().record("limit", &(plan.limit as u64));
+        let batch_size = plan.offset.saturating_add(plan.limit).max(HEAD_BATCH_ROWS);
+        let batches: Vec<DataSet> = record_batches(reader, header, spilled, plan, batch_size)
+            .try_collect()
+            .await?;
+        let mut batches = batches.into_iter();
+        let mut df = match batches.next() {
+            Some(ds) => ds.0,
+            None => return Ok(None),
+        };
+        for ds in batches {
+            df.vstack_mut(&ds.0)?;
+        }
+        self.loaded(&df)?;
+        Ok(Some(df))
+    }
+
+    /// 解析一条语句，之后可以用 [`Session::execute_prepared`] 绑定不同的参数多次执行
+    pub fn prepare<T: AsRef<str>>(&self, sql: T) -> Result<Prepared> {
+        let sql = sql.as_ref();
+        let mut stmts = parse(sql)?;
+
+        if stmts.len() != 1 {
+            return Err(Error::unsupported(
+                "Only support single sql in prepare",
+                sql,
+            ));
+        }
+
+        Ok(Prepared {
+            stmt: stmts.remove(0),
+            sql: sql.to_owned(),
+        })
+    }
+
+    /// 绑定参数执行预先解析好的语句，不需要再次解析 SQL
+    pub async fn execute_prepared(
+        &mut self,
+        prepared: &Prepared,
+        params: &Params,
+    ) -> Result<DataSet> {
+        let start = Instant::now();
+        let options = self.begin();
+        let span = info_span!("query", sql = prepared.sql.as_str(), rows = field::Empty);
+        let result = guard(&options, self.execute(&prepared.stmt, params))
+            .instrument(span.clone())
+            .await
+            .map_err(|e| e.locate(&prepared.sql));
+        self.finish(start, &span, &result);
+        result
+    }
+
+    /// 监视一条 SELECT，每隔 interval 检查一次用到的数据源，变化时重新执行，见 [`Watch`]
+    ///
+    /// Watch 使用 session 的一个副本，之后对 session 的修改不会影响它
+    #[cfg(feature = "native")]
+    pub fn watch<T: AsRef<str>>(&self, sql: T, interval: Duration) -> Result<Watch> {
+        let prepared = self.prepare(sql)?;
+        let urls = match &prepared.stmt {
+            Stmt::Sql(stmt @ Statement::Query(_)) => {
+                self.sources(stmt, &Params::new())
+                    .map_err(|e| e.locate(&prepared.sql))?
+                    .urls
+            }
+            _ => {
+                return Err(Error::unsupported(
+                    "Only support SELECT in watch",
+                    &prepared.sql,
+                ))
+            }
+        };
+        Ok(Watch::new(self.clone(), prepared, interval, urls))
+    }
+
+    /// 按顺序执行用 `;` 分隔的多条语句，返回每条语句的结果
+    ///
+    /// 任何一条语句失败都会停止执行，返回 [`Error::Statement`] 说明是哪一条语句
+    pub async fn execute_script<T: AsRef<str>>(&mut self, sql: T) -> Result<Vec<DataSet>> {
+        let sql = sql.as_ref();
+        let stmts = parse(sql)?;
+        let params = Params::new();
+
+        // 超时作用于整个脚本
+        let options = self.begin();
+        guard(&options, async {
+            let mut results = Vec::with_capacity(stmts.len());
+            for (i, stmt) in stmts.iter().enumerate() {
+                let ds = self.execute(stmt, &params).await.map_err(|e| {
+                    Error::Statement {
+                        index: i + 1,
+                        statement: stmt.to_string(),
+                        source: Box::new(e),
+                    }
+                    .locate(sql)
+                })?;
+                results.push(ds);
+            }
+            Ok(results)
+        })
+        .await
+    }
+
+    /// 开始一次查询，使用 session 配置里的默认选项
+    fn begin(&mut self) -> QueryOptions {
+        self.stats = QueryStats::default();
+        self.options = self.config.query_options.clone();
+        self.options.clone()
+    }
+
+    /// 记录查询的总用时和结果的行数
+    fn finish(&mut self, start: Instant, span: &Span, result: &Result<DataSet>) {
+        self.stats.total = start.elapsed();
+        if let Ok(ds) = result {
+            self.stats.rows = ds.height();
+            span.record("rows", &(ds.height() as u64));
+        }
+    }
+
+    pub(crate) async fn execute(&mut self, stmt: &Stmt, params: &Params) -> Result<DataSet> {
+        match stmt {
+            Stmt::Sql(stmt) => self.execute_sql(stmt, params).await,
+            Stmt::CopyTo(copy) => self.copy_to(copy, params).await,
+        }
+    }
+
+    async fn execute_sql(&mut self, stmt: &Statement, params: &Params) -> Result<DataSet> {
+        match stmt {
+            Statement::CreateView {
+                name,
+                query,
+                or_replace,
+                ..
+            } => {
+                let name = name.to_string();
+                if !or_replace && self.tables.contains_key(&name) {
+                    return Err(Error::execution(format!("Table {} already exists", name)));
+                }
+                self.tables.insert(name, Table::View(query.clone()));
+                self.touch();
+                Ok(DataSet::empty())
+            }
+            // CREATE TABLE t AS SELECT ... 把结果物化在内存里，之后的查询不用重新读取数据源
+            Statement::CreateTable {
+                name,
+                query: Some(query),
+                or_replace,
+                if_not_exists,
+                ..
+            } => {
+                let name = name.to_string();
+                if self.tables.contains_key(&name) {
+                    if *if_not_exists {
+                        return Ok(DataSet::empty());
+                    }
+                    if !or_replace {
+                        return Err(Error::execution(format!("Table {} already exists", name)));
+                    }
+                }
+                let df = self
+                    .select(&Statement::Query(query.clone()), params)
+                    .await?;
+                self.tables.insert(name, Table::Data(df));
+                self.touch();
+                Ok(DataSet::empty())
+            }
+            Statement::CreateTable { .. } => Err(Error::unsupported(
+                "We only support CREATE TABLE ... AS SELECT at the moment",
+                stmt,
+            )),
+            Statement::Drop {
+                object_type: ObjectType::Table | ObjectType::View,
+                if_exists,
+                names,
+                ..
+            } => {
+                for name in names {
+                    let name = name.to_string();
+                    if !self.deregister(&name) && !if_exists {
+                        return Err(Error::execution(format!("Table {} does not exist", name)));
+                    }
+                }
+                Ok(DataSet::empty())
+            }
+            Statement::Query(_) => Ok(DataSet(self.select_cached(stmt, params).await?)),
+            _ => Err(Error::unsupported(
+                "We only support Query, CREATE VIEW, CREATE TABLE and DROP at the moment",
+                stmt,
+            )),
+        }
+    }
+
+    /// 把查询结果写入文件，返回写入的行数
+    async fn copy_to(&mut self, copy: &CopyTo, params: &Params) -> Result<DataSet> {
+        self.check_target(&copy.target)?;
+        let path = copy.target.strip_prefix("file://").ok_or_else(|| {
+            Error::unsupported(
+                "We only support COPY TO file:// at the moment",
+                &copy.target,
+            )
+        })?;
+
+        let stmt = Statement::Query(copy.query.clone());
+        let ds = DataSet(self.select(&stmt, params).await?);
+        write_file(path, ds.to_bytes(copy.format)?).await?;
+
+        Ok(DataSet(df!("rows" => &[ds.height() as u64])?))
+    }
+
+    /// 开启了结果缓存时先查缓存，没有命中再执行查询
+    async fn select_cached(&mut self, stmt: &Statement, params: &Params) -> Result<DataFrame> {
+        let config = match self.config.result_cache.clone() {
+            Some(config) => config,
+            None => return self.select(stmt, params).await,
+        };
+        let key = match self.cache_key(stmt, params).await {
+            Some(key) => key,
+            None => return self.select(stmt, params).await,
+        };
+
+        let cached = self.results().get(&key, &config);
+        if let Some(df) = cached {
+            self.options.check_rows(df.height())?;
+            self.stats.cached = true;
+            return Ok(df);
+        }
+        let df = self.select(stmt, params).await?;
+        self.results().insert(key, df.clone(), &config);
+        Ok(df)
+    }
+
+    /// 结果缓存的键：catalog 的版本、规范化的 SQL、绑定的参数，以及用到的每个数据源的校验值
+    ///
+    /// 结果每次都可能不同的查询返回 None 表示不缓存；解析失败时也返回 None，错误交给 select 报告
+    async fn cache_key(&self, stmt: &Statement, params: &Params) -> Option<String> {
+        let sources = self.sources(stmt, params).ok()?;
+        if sources.volatile {
+            return None;
+        }
+        let mut key = format!("{}\n{}\n{}", self.catalog, stmt, params.fingerprint());
+        for url in &sources.urls {
+            key.push('\n');
+            key.push_str(url);
+            key.push(' ');
+            key.push_str(&validator(url, self.options.timeout).await);
+        }
+        Some(key)
+    }
+
+    /// 展开视图，找出查询用到的所有数据源的地址
+    pub(crate) fn sources(&self, stmt: &Statement, params: &Params) -> Result<Sources> {
+        let empty = Params::new();
+        let mut pending = vec![(stmt.clone(), params)];
+        let mut sources = Sources::default();
+        let mut expanded = 0;
+
+        while let Some((stmt, params)) = pending.pop() {
+            expanded += 1;
+            if expanded > MAX_VIEW_DEPTH {
+                return Err(Error::unsupported(
+                    format!("Views can be nested at most {} levels", MAX_VIEW_DEPTH),
+                    stmt,
+                ));
+            }
+            let is_volatile = |name: &str| {
+                VOLATILE_FUNCTIONS.contains(&name) || self.volatile_udfs.contains(name)
+            };
+            sources.volatile |= calls_volatile(&stmt, &is_volatile);
+            let ctx = Context {
+                udfs: Some(&self.udfs),
+                params: Some(params),
+            };
+            let sql = Sql::with_context(&stmt, ctx)?;
+            sources.volatile |= matches!(&sql.sample, Some(sample) if sample.seed.is_none());
+            let source = sql.source.to_owned();
+            drop(sql);
+
+            match self.tables.get(&source) {
+                Some(Table::Data(_)) => {}
+                Some(Table::View(query)) => pending.push((Statement::Query(query.clone()), &empty)),
+                Some(Table::Source(url)) => sources.urls.push(url.clone()),
+                None => {
+                    self.check_source(&source)?;
+                    sources.urls.push(source);
+                }
+            }
+        }
+        Ok(sources)
+    }
+
+    /// 丢掉这些数据源缓存的数据，下次查询时重新读取
+    #[cfg(feature = "native")]
+    pub(crate) fn forget(&mut self, urls: &[String]) {
+        let stale = |key: &String| urls.iter().any(|url| key.starts_with(url.as_str()));
+        self.cache.retain(|key, _| !stale(key));
+        self.spills.retain(|key, _| !stale(key));
+    }
+
+    /// 从 from 中获取数据，从 where 中过滤，最后选取需要返回的列
+    fn select<'s>(
+        &'s mut self,
+        stmt: &'s Statement,
+        params: &'s Params,
+    ) -> BoxFuture<'s, Result<DataFrame>> {
+        Box::pin(async move {
+            let udfs = self.udfs.clone();
+            let ctx = Context {
+                udfs: Some(&udfs),
+                params: Some(params),
+            };
+
+            // 整个 SQL AST 转换成我们定义的 Sql 结构的细节都埋藏在 with_context() 中
+            let start = Instant::now();
+            let sql = info_span!("convert").in_scope(|| Sql::with_context(stmt, ctx))?;
+            self.stats.convert += start.elapsed();
+
+            // 没有排序和聚合的 LIMIT 查询读够了行数就停止，不用读取整个数据源
+            if let (Some(limit), true) = (sql.limit, is_chunked(&sql)) {
+                let url = match self.csv_source(&sql)? {
+                    Some(url) if is_plain_csv(&url).await => Some(url),
+                    _ => None,
+                };
+                if let Some(url) = url {
+                    let plan = RowPlan {
+                        condition: sql.condition.clone(),
+                        selection: sql.selection.clone(),
+                        offset: sql.offset.unwrap_or(0) as usize,
+                        limit,
+                    };
+                    let span = load_span(&url);
+                    let (start, busy) = (Instant::now(), self.stats.busy());
+                    let head = self.read_head(&url, plan).instrument(span).await;
+                    self.count_load(start, busy);
+                    if let Some(df) = head? {
+                        return Ok(df);
+                    }
+                }
+            }
+
+            let Sql {
+                source,
+                table,
+                json,
+                reshape,
+                sample,
+                predicate,
+                condition,
+                selection,
+                offset,
+                limit,
+                order_by,
+                group_by,
+                gap_fill,
+            } = sql;
+
+            // WHERE 作用在抽样和 PIVOT / UNPIVOT 之后，这时不能下推到数据源
+            let predicate = predicate.filter(|_| reshape.is_none() && sample.is_none());
+            // 落地的文件要等 collect 之后才能释放
+            let (start, busy) = (Instant::now(), self.stats.busy());
+            let loaded = self
+                .load(source, table, json.as_ref(), predicate)
+                .instrument(load_span(source))
+                .await;
+            self.count_load(start, busy);
+            let (lf, _spilled) = loaded?;
+
+            let start = Instant::now();
+            let span = info_span!("execute", rows = field::Empty);
+            let options = &self.options;
+            let result = span.in_scope(|| -> Result<DataFrame> {
+                let lf = match &sample {
+                    Some(sample) => sample.apply(lf.collect()?)?.lazy(),
+                    None => lf,
+                };
+                let lf = match &reshape {
+                    Some(reshape) => reshape.apply(lf.collect()?)?.lazy(),
+                    None => lf,
+                };
+
+                let filtered = match condition {
+                    Some(expr) => lf.filter(expr),
+                    None => lf,
+                };
+
+                let order_and_slice = |lf: LazyFrame| {
+                    let lf = order_by
+                        .into_iter()
+                        .fold(lf, |acc, (col, desc)| acc.sort(&col, desc));
+                    match (offset, limit) {
+                        (None, None) => lf,
+                        _ => lf.slice(offset.unwrap_or(0), limit.unwrap_or(usize::MAX)),
+                    }
+                };
+
+                if group_by.is_empty() {
+                    let df = order_and_slice(filtered).select(selection).collect()?;
+                    options.check_rows(df.height())?;
+                    return Ok(df);
+                }
+
+                // 分组聚合之后再排序和分页，ORDER BY 可以引用聚合结果的列
+                let mut df = filtered.groupby(group_by).agg(selection).collect()?;
+                if let Some((column, interval)) = gap_fill {
+                    df = temporal::gap_fill(df, &column, interval)?;
+                }
+                let df = order_and_slice(df.lazy()).collect()?;
+                options.check_rows(df.height())?;
+                Ok(df)
+            });
+            self.stats.execute += start.elapsed();
+            if let Ok(df) = &result {
+                span.record("rows", &(df.height() as u64));
+            }
+            result
+        })
+    }
+
+    /// 记录 load 阶段的用时，扣除其中嵌套的 fetch 以及视图里的查询的用时
+    fn count_load(&mut self, start: Instant, busy: Duration) {
+        let nested = self.stats.busy().saturating_sub(busy);
+        self.stats.load += start.elapsed().saturating_sub(nested);
+    }
+
+    /// 加载了数据源之后检查行数，并记录在统计和当前的 load span 上
+    fn loaded(&mut self, df: &DataFrame) -> Result<()> {
+        self.options.check_rows(df.height())?;
+        self.stats.rows_loaded += df.height();
+        Span::current()
+            .record("rows", &(df.height() as u64))
+            .record("columns", &(df.width() as u64));
+        Ok(())
+    }
+
+    /// 记录读取数据源的用时和字节数
+    fn fetched(&mut self, elapsed: Duration, bytes: u64) {
+        self.stats.fetch += elapsed;
+        self.stats.bytes_fetched += bytes;
+    }
+
+    /// 按名字在 catalog 里查找表，找不到就把名字当作数据源地址
+    ///
+    /// 数据源是 SQLite 数据库时读取其中的 table，predicate 里简单的条件会下推到 SQLite；
+    /// 有 json 参数时按 `read_json` 的方式读取
+    fn load<'s>(
+        &'s mut self,
+        source: &'s str,
+        table: Option<&'s str>,
+        json: Option<&'s JsonOptions>,
+        predicate: Option<&'s SqlExpr>,
+    ) -> BoxFuture<'s, Result<Scan>> {
+        Box::pin(async move {
+            let url = match self.tables.get(source) {
+                Some(Table::Data(_) | Table::View(_)) if table.is_some() || json.is_some() => {
+                    return Err(Error::unsupported(
+                        format!("{} is not a data source", source),
+                        source,
+                    ))
+                }
+                Some(Table::Data(df)) => {
+                    let df = df.clone();
+                    self.loaded(&df)?;
+                    return Ok((df.lazy(), None));
+                }
+                Some(Table::View(query)) => {
+                    let stmt = Statement::Query(query.clone());
+                    let df = self.select(&stmt, &Params::new()).await?;
+                    return Ok((df.lazy(), None));
+                }
+                Some(Table::Source(url)) => url.clone(),
+                None => {
+                    self.check_source(source)?;
+                    source.to_owned()
+                }
+            };
+
+            if let Some(options) = json {
+                let key = format!("{}#{:?}", url, options);
+                if let Some(df) = self.cache.get(&key).cloned() {
+                    self.loaded(&df)?;
+                    return Ok((df.lazy(), None));
+                }
+                // 分页的链接也要在允许的范围内
+                let read = read_json(&url, options, self.options.max_bytes, |next| {
+                    self.check_source(next)
+                });
+                let (df, elapsed, _) = traced_fetch(&url, read, |_| 0).await;
+                self.fetched(elapsed, 0);
+                let df = df?;
+                self.loaded(&df)?;
+                if self.config.cache_sources {
+                    self.cache.insert(key, df.clone());
+                }
+                return Ok((df.lazy(), None));
+            }
+
+            // 本地文件只读取一次文件头，用来判断是不是数据库或者表格
+            let header = read_header(url.split('#').next().unwrap_or(&url)).await;
+            let header = header.as_deref();
+
+            // SQLite 需要一个文件才能打开，所以总是先落地，读出来的结果因为有下推的条件不做缓存
+            if table.is_some() || is_sqlite_source(&url, header) {
+                let spilled = self.spill(url).await?;
+                let loader = SqliteLoader {
+                    path: spilled.path().to_owned(),
+                    table: table.map(String::from),
+                    filter: predicate.and_then(sqlite::pushdown),
+                };
+                let df = loader.load()?.0;
+                self.loaded(&df)?;
+                return Ok((df.lazy(), Some(spilled)));
+            }
+
+            // 表格也需要先落地，地址里的 fragment 只用来选择 sheet 和范围
+            if let Some((location, workbook, sheet, range)) = spreadsheet_source(&url, header)? {
+                if let Some(df) = self.cache.get(&url).cloned() {
+                    self.loaded(&df)?;
+                    return Ok((df.lazy(), None));
+                }
+                let spilled = self.spill(location.to_owned()).await?;
+                let loader = ExcelLoader {
+                    path: spilled.path().to_owned(),
+                    workbook,
+                    sheet,
+                    range,
+                };
+                let df = loader.load()?.0;
+                self.loaded(&df)?;
+                if self.config.cache_sources {
+                    self.cache.insert(url, df.clone());
+                }
+                return Ok((df.lazy(), None));
+            }
+
+            if self.config.spill_to_disk {
+                let spilled = self.spill(url).await?;
+                let lf = LazyCsvReader::new(spilled.path().to_string_lossy().into_owned())
+                    .has_header(true)
+                    .with_infer_schema_length(Some(INFER_SCHEMA_ROWS))
+                    .finish();
+                return Ok((lf, Some(spilled)));
+            }
+
+            if let Some(df) = self.cache.get(&url).cloned() {
+                self.loaded(&df)?;
+                return Ok((df.lazy(), None));
+            }
+
+            // detect_content，怎么 detect 不重要，重要的是它能根据内容返回 DataSet。
+            // fragment 不是地址的一部分，用来选择页面里的表格
+            let (location, fragment) = match url.split_once('#') {
+                Some((location, fragment)) => (location, Some(fragment)),
+                None => (url.as_str(), None),
+            };
+            let check = |url: &str| self.check_source(url);
+            let retrieve = retrieve_data(location, self.options.max_bytes, &check);
+            let (data, elapsed, bytes) =
+                traced_fetch(location, retrieve, |data: &String| data.len() as u64).await;
+            self.fetched(elapsed, bytes);
+            let df = detect_content(data?, fragment).load()?.0;
+            self.loaded(&df)?;
+            if self.config.cache_sources {
+                self.cache.insert(url, df.clone());
+            }
+            Ok((df.lazy(), None))
+        })
+    }
+
+    /// 检查地址是否在 allowed_sources 里
+    fn check_source(&self, url: &str) -> Result<()> {
+        match &self.config.allowed_sources {
+            Some(allowed) => check_allowed(url, allowed),
+            None => Ok(()),
+        }
+    }
+
+    /// 检查 COPY 的目标是否在 allowed_targets 里
+    fn check_target(&self, url: &str) -> Result<()> {
+        check_allowed(url, &self.config.allowed_targets)
+    }
+
+    /// 把数据源落地到磁盘，开启缓存时同一个数据源只下载一次
+    async fn spill(&mut self, url: String) -> Result<Arc<Spilled>> {
+        if let Some(spilled) = self.spills.get(&url) {
+            return Ok(spilled.clone());
+        }
+
+        let check = |url: &str| self.check_source(url);
+        let spill = spill_data(&url, self.options.max_bytes, &check);
+        let (spilled, elapsed, bytes) = traced_fetch(&url, spill, |spilled: &Spilled| {
+            std::fs::metadata(spilled.path())
+                .map(|meta| meta.len())
+                .unwrap_or_default()
+        })
+        .await;
+        self.fetched(elapsed, bytes);
+        let spilled = Arc::new(spilled?);
+        if self.config.cache_sources {
+            self.spills.insert(url, spilled.clone());
+        }
+        Ok(spilled)
+    }
+}
+
+/// 查询用到的数据源
+#[derive(Debug, Default)]
+pub(crate) struct Sources {
+    pub(crate) urls: Vec<String>,
+    /// 查询里调用了 now() 这样的易变函数或者没有种子的抽样，每次执行的结果都可能不同
+    pub(crate) volatile: bool,
+}
+
+/// 加载数据源的 span，rows 和 columns 在加载之后记录
+fn load_span(source: &str) -> Span {
+    info_span!(
+        "load",
+        source,
+        rows = field::Empty,
+        columns = field::Empty,
+        limit = field::Empty
+    )
+}
+
+/// 检查地址是否匹配 allowed 里的某一项，匹配的规则见 [`source_matches`]
+fn check_allowed(url: &str, allowed: &[String]) -> Result<()> {
+    let not_allowed = || Error::NotAllowed(url.to_owned());
+    // 不允许用 `..` 跳出允许的目录，解析 url 时 `\` 也会被当作 `/`
+    if url.contains('\\') || url.split('/').any(|segment| segment == "..") {
+        return Err(not_allowed());
+    }
+    let parsed = Url::parse(url).map_err(|_| not_allowed())?;
+    // `https://allowed.com@evil.com/` 实际访问的是 evil.com
+    if !parsed.username().is_empty() || parsed.password().is_some() {
+        return Err(not_allowed());
+    }
+    if allowed.iter().any(|a| source_matches(&parsed, a)) {
+        Ok(())
+    } else {
+        Err(not_allowed())
+    }
+}
+
+/// allowed 是主机名时只比较主机；是地址前缀时 scheme、主机和端口都要相同，
+/// 路径要在 `/` 处对齐，`file:///data` 不能匹配 `file:///data-private/`
+fn source_matches(url: &Url, allowed: &str) -> bool {
+    if !allowed.contains("://") {
+        return url
+            .host_str()
+            .map_or(false, |host| host.eq_ignore_ascii_case(allowed));
+    }
+    let allowed = match Url::parse(allowed) {
+        Ok(allowed) => allowed,
+        Err(_) => return false,
+    };
+    if url.scheme() != allowed.scheme()
+        || url.host_str() != allowed.host_str()
+        || url.port_or_known_default() != allowed.port_or_known_default()
+    {
+        return false;
+    }
+    let (path, prefix) = (url.path(), allowed.path());
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// 不是数据库也不是表格的数据源，可以当作 csv 按行读取
+async fn is_plain_csv(url: &str) -> bool {
+    let header = read_header(url).await;
+    !is_sqlite_source(url, header.as_deref())
+        && matches!(spreadsheet_source(url, header.as_deref()), Ok(None))
+}
+
+/// 没有排序和聚合、投影都是逐行计算的查询可以分块执行
+fn is_chunked(sql: &Sql) -> bool {
+    sql.order_by.is_empty() && sql.group_by.is_empty() && sql.selection.iter().all(is_row_wise)
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+
+    fn sample() -> DataSet {
+        DataSet(df!("name" => &["a", "b", "c"], "value" => &[1i64, 2, 3]).unwrap())
+    }
+
+    #[tokio::test]
+    async fn registered_dataset_can_be_queried_by_name() {
+        let mut session = Session::new();
+        session.register_dataset("sample", sample());
+
+        let ds = session
+            .query("SELECT name FROM sample WHERE value >= 2")
+            .await
+            .unwrap();
+        assert_eq!(ds.height(), 2);
+        assert_eq!(ds.get_column_names(), vec!["name"]);
+    }
+
+    #[tokio::test]
+    async fn query_as_works() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Row {
+            name: String,
+            value: i64,
+        }
+
+        let mut session = Session::new();
+        session.register_dataset("sample", sample());
+
+        let rows: Vec<Row> = session
+            .query_as("SELECT name, value FROM sample WHERE value > 2")
+            .await
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![Row {
+                name: "c".into(),
+                value: 3
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn create_view_works() {
+        let mut session = Session::new();
+        session.register_dataset("sample", sample());
+
+        session
+            .query("CREATE VIEW big AS SELECT name, value FROM sample WHERE value > 1")
+            .await
+            .unwrap();
+        assert_eq!(session.table_names(), vec!["big", "sample"]);
+
+        let ds = session
+            .query("SELECT name FROM big WHERE value < 3")
+            .await
+            .unwrap();
+        assert_eq!(ds.height(), 1);
+    }
+
+    #[tokio::test]
+    async fn create_and_drop_table_works() {
+        let mut session = Session::new();
+        session.register_dataset("sample", sample());
+
+        session
+            .query("CREATE TABLE small AS SELECT name, value FROM sample WHERE value < 3")
+            .await
+            .unwrap();
+        // 原始数据删掉后，物化的表依然可以查询
+        session.query("DROP TABLE sample").await.unwrap();
+
+        let ds = session.query("SELECT * FROM small").await.unwrap();
+        assert_eq!(ds.height(), 2);
+
+        session.query("DROP TABLE small").await.unwrap();
+        assert!(session.table_names().is_empty());
+        assert!(session.query("DROP TABLE small").await.is_err());
+        assert!(session.query("DROP TABLE IF EXISTS small").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn copy_to_works() {
+        let mut session = Session::new();
+        session.register_dataset("sample", sample());
+
+        let path = std::env::temp_dir().join("sqlr_copy_to_works.csv");
+        let sql = format!(
+            "COPY (SELECT name, value FROM sample WHERE value > 1) TO 'file://{}'",
+            path.display()
+        );
+        // 默认不能写入任何文件
+        let err = session.query(&sql).await.unwrap_err();
+        assert!(matches!(err, Error::NotAllowed(_)));
+
+        session.config_mut().allowed_targets =
+            vec![format!("file://{}", std::env::temp_dir().display())];
+        let ds = session.query(sql).await.unwrap();
+        assert_eq!(ds.column("rows").unwrap().sum::<u64>(), Some(2));
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content, "name,value\nb,2\nc,3\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn execute_script_works() {
+        let mut session = Session::new();
+        session.register_dataset("sample", sample());
+
+        let results = session
+            .execute_script(
+                "CREATE VIEW big AS SELECT name, value FROM sample WHERE value > 1;
+                 CREATE TABLE t AS SELECT name FROM big;
+                 SELECT * FROM t;",
+            )
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[2].height(), 2);
+    }
+
+    #[tokio::test]
+    async fn execute_script_reports_failed_statement() {
+        let mut session = Session::new();
+        session.register_dataset("sample", sample());
+
+        let err = session
+            .execute_script("SELECT * FROM sample; DROP TABLE missing; SELECT 1")
+            .await
+            .unwrap_err();
+        match err {
+            Error::Statement {
+                index, statement, ..
+            } => {
+                assert_eq!(index, 2);
+                assert_eq!(statement, "DROP TABLE missing");
+            }
+            e => panic!("expect statement error, got {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn prepared_statement_works() {
+        let mut session = Session::new();
+        session.register_dataset("sample", sample());
+
+        let stmt = session
+            .prepare("SELECT name FROM sample WHERE value >= $1 AND name != :skip LIMIT $2")
+            .unwrap();
+
+        let params = Params::positional(vec![1, 5]).bind_named("skip", "b");
+        let ds = session.execute_prepared(&stmt, &params).await.unwrap();
+        assert_eq!(ds.height(), 2);
+
+        let params = Params::positional(vec![1, 1]).bind_named("skip", "b");
+        let ds = session.execute_prepared(&stmt, &params).await.unwrap();
+        assert_eq!(ds.height(), 1);
+
+        // 缺少参数时报错，而不是把占位符当成列名
+        let params = Params::positional(vec![1]);
+        assert!(session.execute_prepared(&stmt, &params).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn query_stream_works() {
+        use futures::TryStreamExt;
+
+        let path = std::env::temp_dir().join("sqlr_query_stream_works.csv");
+        let rows: String = (0..10).map(|i| format!("{},\"n\n{}\"\n", i, i)).collect();
+        std::fs::write(&path, format!("value,name\n{}", rows)).unwrap();
+        let url = format!("file://{}", path.display());
+
+        let mut session = Session::new();
+        session.register("numbers", &url);
+
+        // 逐块执行：过滤、投影、offset 和 limit 跨越多个块
+        let sql = "SELECT name, value FROM numbers WHERE value >= 2 LIMIT 5 OFFSET 1";
+        let batches: Vec<_> = session
+            .query_stream(sql, 3)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let heights: Vec<_> = batches.iter().map(|ds| ds.height()).collect();
+        assert_eq!(heights.iter().sum::<usize>(), 5);
+        assert_eq!(
+            batches[0].column("value").unwrap().sum::<i64>(),
+            Some(3 + 4 + 5)
+        );
+
+        // 有 ORDER BY 时执行完整个查询再分批
+        let sql = "SELECT value FROM numbers ORDER BY value DESC";
+        let batches: Vec<_> = session
+            .query_stream(sql, 4)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        let heights: Vec<_> = batches.iter().map(|ds| ds.height()).collect();
+        assert_eq!(heights, vec![4, 4, 2]);
+
+        // 落地到磁盘后用 LazyCsvReader 扫描
+        session.config_mut().spill_to_disk = true;
+        let ds = session
+            .query("SELECT value FROM numbers WHERE value < 3")
+            .await
+            .unwrap();
+        assert_eq!(ds.height(), 3);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unsupported_error_has_location() {
+        let mut session = Session::new();
+        session.register_dataset("sample", sample());
+
+        let sql = "SELECT name,\n  median(value) FROM sample";
+        let err = session.query(sql).await.unwrap_err();
+        assert!(matches!(err, Error::Unsupported { .. }));
+        let location = err.location().unwrap();
+        assert_eq!((location.line, location.column), (2, 3));
+    }
+
+    #[tokio::test]
+    async fn quoted_and_unicode_identifiers_work() {
+        let df = df!("new cases" => &[1i64, 2, 3], "国家" => &["a", "b", "c"]).unwrap();
+        let mut session = Session::new();
+        session.register_dataset("my data", DataSet(df));
+
+        let ds = session
+            .query("SELECT 国家, `new cases` AS cases FROM \"my data\" WHERE \"new cases\" > 1")
+            .await
+            .unwrap();
+        assert_eq!(ds.height(), 2);
+        assert_eq!(ds.get_column_names(), vec!["国家", "cases"]);
+    }
+
+    #[tokio::test]
+    async fn time_bucket_group_by_works() {
+        let df = df!(
+            "last_updated_date" => &["2021-01-04", "2021-01-05", "2021-01-20", "2021-01-21"],
+            "cases" => &[1i64, 2, 3, 4]
+        )
+        .unwrap();
+        let mut session = Session::new();
+        session.register_dataset("covid", DataSet(df));
+
+        // 2021-01-04 是星期一，01-11 那一周没有数据
+        let ds = session
+            .query(
+                "SELECT time_bucket('1 week', last_updated_date) AS week, sum(cases) AS total \
+                 FROM covid GROUP BY week ORDER BY week",
+            )
+            .await
+            .unwrap();
+        let totals: Vec<_> = ds
+            .column("total")
+            .unwrap()
+            .i64()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(totals, vec![Some(3), Some(7)]);
+
+        let ds = session
+            .query(
+                "SELECT time_bucket_gapfill('1 week', last_updated_date), sum(cases) AS total \
+                 FROM covid GROUP BY 1",
+            )
+            .await
+            .unwrap();
+        assert_eq!(ds.get_column_names(), vec!["time_bucket", "total"]);
+        let totals: Vec<_> = ds
+            .column("total")
+            .unwrap()
+            .i64()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(totals, vec![Some(3), None, Some(7)]);
+
+        let ds = session
+            .query(
+                "SELECT extract(year FROM last_updated_date), \
+                 date_diff('day', last_updated_date, '2021-02-01') AS days FROM covid \
+                 WHERE date_trunc('month', last_updated_date) = date_trunc('month', '2021-01-31') \
+                 LIMIT 1",
+            )
+            .await
+            .unwrap();
+        assert_eq!(ds.get_column_names(), vec!["year", "days"]);
+        assert_eq!(ds.column("days").unwrap().i64().unwrap().get(0), Some(28));
+    }
+
+    #[tokio::test]
+    async fn query_limits_work() {
+        let path = std::env::temp_dir().join("sqlr_query_limits_work.csv");
+        let rows: String = (0..100).map(|i| format!("{}\n", i)).collect();
+        std::fs::write(&path, format!("value\n{}", rows)).unwrap();
+        let sql = format!("SELECT value FROM file://{}", path.display());
+
+        let mut session = Session::new();
+        let options = QueryOptions {
+            max_bytes: Some(16),
+            ..Default::default()
+        };
+        let err = session.query_with(&sql, options).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::LimitExceeded {
+                resource: "bytes",
+                ..
+            }
+        ));
+
+        session.config_mut().query_options.max_rows = Some(10);
+        let err = session.query(&sql).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::LimitExceeded {
+                resource: "rows",
+                ..
+            }
+        ));
+
+        // 单次查询的选项覆盖 session 的默认选项
+        let ds = session
+            .query_with(&sql, QueryOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(ds.height(), 100);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// 按路径返回固定响应的 http 服务器，routes 里是路径和完整的响应
+    async fn http_server(routes: Vec<(&'static str, String)>) -> std::net::SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0; 4096];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]);
+                let path = request.split_whitespace().nth(1).unwrap_or("/");
+                let response = routes.iter().find(|(p, _)| *p == path).map_or(
+                    "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n",
+                    |(_, r)| r.as_str(),
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn redirects_are_checked() {
+        let redirect = |location: &str| {
+            format!(
+                "HTTP/1.1 302 Found\r\nlocation: {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                location
+            )
+        };
+        let csv = "HTTP/1.1 200 OK\r\ncontent-length: 6\r\nconnection: close\r\n\r\na\n1\n2\n";
+        let addr = http_server(vec![
+            ("/data/moved.csv", redirect("/data/a.csv")),
+            ("/data/a.csv", csv.to_owned()),
+            ("/secret.csv", csv.to_owned()),
+        ])
+        .await;
+        let mut session = Session::new();
+        session.config_mut().allowed_sources = Some(vec![format!("http://{}/data/", addr)]);
+
+        // 允许的范围内的重定向照常跟随
+        let sql = format!("SELECT sum(a) AS a FROM 'http://{}/data/moved.csv'", addr);
+        let ds = session.query(sql).await.unwrap();
+        assert_eq!(ds.column("a").unwrap().sum::<i64>(), Some(3));
+
+        // 重定向到不允许的地址
+        for location in [
+            format!("http://{}/secret.csv", addr),
+            format!("http://localhost:{}/data/a.csv", addr.port()),
+        ] {
+            let addr = http_server(vec![("/data/evil.csv", redirect(&location))]).await;
+            session.config_mut().allowed_sources = Some(vec![format!("http://{}/data/", addr)]);
+            let sql = format!("SELECT * FROM 'http://{}/data/evil.csv'", addr);
+            let err = session.query(sql).await.unwrap_err();
+            assert!(matches!(err, Error::NotAllowed(_)), "{:?}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn validator_times_out() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // 接受连接但是从不响应
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+        let url = format!("http://{}/data.csv", addr);
+        let start = std::time::Instant::now();
+        let timeout = Some(std::time::Duration::from_millis(100));
+        assert_eq!(validator(&url, timeout).await, "");
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn allowed_sources_work() {
+        let mut session = Session::new();
+        session.register("trusted", "https://example.com/data.csv");
+        session.config_mut().allowed_sources = Some(vec![
+            "raw.githubusercontent.com".into(),
+            "file:///data/".into(),
+        ]);
+
+        assert!(session
+            .check_source("https://raw.githubusercontent.com/a.csv")
+            .is_ok());
+        assert!(session.check_source("file:///data/a.csv").is_ok());
+        assert!(session.check_source("file:///etc/passwd").is_err());
+        assert!(session.check_source("file:///data/../etc/passwd").is_err());
+        assert!(session
+            .check_source("https://raw.githubusercontent.com.evil.io/a.csv")
+            .is_err());
+
+        session.config_mut().allowed_sources = Some(vec![
+            "https://data.example.com".into(),
+            "file:///data".into(),
+        ]);
+        assert!(session
+            .check_source("https://data.example.com/x.csv")
+            .is_ok());
+        assert!(session.check_source("file:///data/x.csv").is_ok());
+        // userinfo 后面才是真正的主机
+        assert!(session
+            .check_source("https://data.example.com@evil.com/x.csv")
+            .is_err());
+        assert!(session
+            .check_source("https://data.example.com.evil.net/x.csv")
+            .is_err());
+        assert!(session
+            .check_source("https://data.example.com:8443/x.csv")
+            .is_err());
+        assert!(session
+            .check_source("http://data.example.com/x.csv")
+            .is_err());
+        assert!(session.check_source("file:///data-private/x.csv").is_err());
+        assert!(session
+            .check_source("file:///data\\..\\etc/passwd")
+            .is_err());
+
+        let err = session
+            .query("SELECT * FROM file:///etc/passwd")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotAllowed(_)));
+        // 允许读取的地址不代表允许写入
+        let err = session
+            .query("COPY (SELECT * FROM trusted) TO 'file:///data/out.csv'")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotAllowed(_)));
+        session.config_mut().allowed_targets = vec!["file:///out/".into()];
+        let err = session
+            .query("COPY (SELECT * FROM trusted) TO 'file:///out/../data/x.csv'")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NotAllowed(_)));
+    }
+
+    #[tokio::test]
+    async fn html_fragment_selects_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("page.html");
+        std::fs::write(
+            &path,
+            "<html><body>\
+             <table><tr><th>menu</th></tr><tr><td>home</td></tr></table>\
+             <table id=\"people\"><tr><th>name</th><th>age</th></tr>\
+             <tr><td>a</td><td>1</td></tr><tr><td>b</td><td>2</td></tr></table>\
+             </body></html>",
+        )
+        .unwrap();
+
+        let mut session = Session::new();
+        for fragment in ["table=2", "people"] {
+            let sql = format!(
+                "SELECT name FROM 'file://{}#{}' WHERE age > 1",
+                path.display(),
+                fragment
+            );
+            let ds = session.query(&sql).await.unwrap();
+            assert_eq!(ds.get_column_names(), vec!["name"]);
+            assert_eq!(ds.height(), 1);
+        }
+        let sql = format!("SELECT * FROM 'file://{}'", path.display());
+        let ds = session.query(&sql).await.unwrap();
+        assert_eq!(ds.get_column_names(), vec!["menu"]);
+    }
+
+    #[tokio::test]
+    async fn sqlite_source_works() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ref.db");
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE country (code TEXT, population INTEGER);
+             INSERT INTO country VALUES ('CN', 1400), ('FR', 67), ('IS', NULL);",
+        )
+        .unwrap();
+        drop(conn);
+        let url = format!("file://{}", path.display());
+
+        let mut session = Session::new();
+        let sql = format!(
+            "SELECT code FROM '{}'.country WHERE population > 50 AND code <> 'CN'",
+            url
+        );
+        let ds = session.query(sql).await.unwrap();
+        assert_eq!(ds.height(), 1);
+
+        let sql = format!("SELECT * FROM sqlite_scan('{}', 'country')", url);
+        assert_eq!(session.query(sql).await.unwrap().height(), 3);
+
+        // 只有一张表时可以不写表名
+        let sql = format!("SELECT * FROM '{}' WHERE population IS NULL", url);
+        assert_eq!(session.query(sql).await.unwrap().height(), 1);
+    }
+
+    #[tokio::test]
+    async fn read_json_works() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.json");
+        let doc = r#"{"data": {"items": [
+            {"name": "a", "address": {"city": "x"}, "roles": ["admin", "dev"]},
+            {"name": "b", "address": {"city": "y"}, "roles": ["dev"]}
+        ]}}"#;
+        std::fs::write(&path, doc).unwrap();
+
+        let mut session = Session::new();
+        let sql = format!(
+            "SELECT name, \"address.city\" city FROM read_json('file://{}', path => '$.data.items', explode => 'roles') WHERE roles = 'dev'",
+            path.display()
+        );
+        let ds = session.query(sql).await.unwrap();
+        assert_eq!(ds.height(), 2);
+        assert_eq!(ds.get_column_names(), vec!["name", "city"]);
+
+        let sql = format!(
+            "SELECT * FROM read_json('file://{}', path => '$.missing')",
+            path.display()
+        );
+        assert!(matches!(session.query(sql).await, Err(Error::Load(_))));
+    }
+
+    #[tokio::test]
+    async fn pivot_and_unpivot_work() {
+        let mut session = Session::new();
+        let long = df!(
+            "country" => &["a", "a", "b", "b"],
+            "metric" => &["cases", "deaths", "cases", "deaths"],
+            "value" => &[10i64, 1, 20, 2]
+        )
+        .unwrap();
+        session.register_dataset("long", DataSet(long));
+
+        let sql = "SELECT country, cases FROM long PIVOT (sum(value) FOR metric IN ('cases', 'deaths')) WHERE deaths > 1";
+        let ds = session.query(sql).await.unwrap();
+        assert_eq!(ds.height(), 1);
+        assert_eq!(ds.column("cases").unwrap().sum::<i64>(), Some(20));
+
+        let wide = session
+            .query("SELECT * FROM long PIVOT (sum(value) FOR metric IN ('cases', 'deaths'))")
+            .await
+            .unwrap();
+        session.register_dataset("wide", wide);
+        let sql = "SELECT * FROM wide UNPIVOT (value FOR metric IN (cases, deaths)) WHERE metric = 'deaths'";
+        let ds = session.query(sql).await.unwrap();
+        assert_eq!(ds.get_column_names(), vec!["country", "metric", "value"]);
+        assert_eq!(ds.column("value").unwrap().sum::<i64>(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn approx_aggregates_work() {
+        let n = 50_000i64;
+        let df = df!(
+            "g" => (0..n).map(|i| if i % 2 == 0 { "even" } else { "odd" }).collect::<Vec<_>>(),
+            "v" => (0..n).collect::<Vec<i64>>()
+        )
+        .unwrap();
+        let mut session = Session::new();
+        session.register_dataset("t", DataSet(df));
+
+        let ds = session
+            .query("SELECT approx_count_distinct(v), percentile_cont(v, 0.95), approx_median(v) FROM t")
+            .await
+            .unwrap();
+        assert_eq!(
+            ds.get_column_names(),
+            vec!["approx_count_distinct", "percentile_cont", "approx_median"]
+        );
+        let distinct = ds
+            .column("approx_count_distinct")
+            .unwrap()
+            .u64()
+            .unwrap()
+            .get(0);
+        assert!((distinct.unwrap() as f64 - n as f64).abs() / (n as f64) < 0.03);
+        // 精确值是 0.95 * (n - 1)
+        let p95 = ds.column("percentile_cont").unwrap().f64().unwrap().get(0);
+        assert!((p95.unwrap() - 47_499.05).abs() < 1e-6);
+        let median = ds.column("approx_median").unwrap().f64().unwrap().get(0);
+        assert!((median.unwrap() - 24_999.5).abs() / (n as f64) < 0.02);
+
+        let ds = session
+            .query("SELECT g, approx_count_distinct(v) d, quantile(v, 0.5) m FROM t GROUP BY g ORDER BY g")
+            .await
+            .unwrap();
+        assert_eq!(ds.height(), 2);
+        let medians: Vec<_> = ds.column("m").unwrap().f64().unwrap().into_iter().collect();
+        assert_eq!(medians, vec![Some(24_999.0), Some(25_000.0)]);
+
+        let ds = session
+            .query("SELECT width_bucket(v, 0, 50000, 5) b, count(v) c FROM t GROUP BY b ORDER BY b")
+            .await
+            .unwrap();
+        let counts = ds.column("c").unwrap().cast::<Int64Type>().unwrap();
+        let counts: Vec<_> = counts.i64().unwrap().into_iter().collect();
+        assert_eq!(counts, vec![Some(10_000); 5]);
+    }
+
+    #[tokio::test]
+    async fn tablesample_works() {
+        let mut session = Session::new();
+        let df = df!("v" => (0..10_000i64).collect::<Vec<_>>()).unwrap();
+        session.register_dataset("t", DataSet(df));
+
+        // WHERE 在抽样之后执行
+        let sql = "SELECT v FROM t TABLESAMPLE BERNOULLI (10) REPEATABLE (42) WHERE v < 5000";
+        let a = session.query(sql).await.unwrap();
+        let b = session.query(sql).await.unwrap();
+        assert!(a.frame_equal(&b));
+        assert!(a.height() > 300 && a.height() < 700);
+
+        let sql = "SELECT v FROM t TABLESAMPLE SYSTEM (100 ROWS) LIMIT 10";
+        assert_eq!(session.query(sql).await.unwrap().height(), 10);
+        let sql = "SELECT v FROM t TABLESAMPLE SYSTEM (100 ROWS)";
+        assert_eq!(session.query(sql).await.unwrap().height(), 100);
+    }
+
+    #[tokio::test]
+    async fn limit_stops_reading_early() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.csv");
+        let mut data = b"value,name\n".to_vec();
+        for i in 0..2000 {
+            data.extend(format!("{},n{}\n", i, i).as_bytes());
+        }
+        // 后面的内容不是合法的 UTF-8，读取整个文件会失败
+        data.extend(b"\xff\xfe,broken\n");
+        std::fs::write(&path, data).unwrap();
+
+        let mut session = Session::new();
+        let sql = format!(
+            "SELECT name FROM file://{} WHERE value % 2 = 1 LIMIT 3 OFFSET 1",
+            path.display()
+        );
+        let ds = session.query(&sql).await.unwrap();
+        let names: Vec<_> = ds
+            .column("name")
+            .unwrap()
+            .utf8()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(names, vec![Some("n3"), Some("n5"), Some("n7")]);
+
+        let sql = format!("SELECT name FROM file://{}", path.display());
+        assert!(session.query(&sql).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn limit_infers_same_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.csv");
+        // 前面的行里 b 都是空的，推断出来是字符串
+        let mut data = "a,b\n".to_owned();
+        for i in 0..40 {
+            let b = if i < 20 { String::new() } else { i.to_string() };
+            data.push_str(&format!("{},{}\n", i, b));
+        }
+        std::fs::write(&path, data).unwrap();
+
+        let sql = format!("SELECT * FROM file://{}", path.display());
+        let all = Session::new().query(&sql).await.unwrap();
+        let head = Session::new()
+            .query(format!("{} LIMIT 30", sql))
+            .await
+            .unwrap();
+        assert_eq!(head.height(), 30);
+        assert_eq!(head.schema(), all.schema());
+    }
+
+    #[tokio::test]
+    async fn result_cache_works() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.csv");
+        std::fs::write(&path, "value\n1\n2\n").unwrap();
+
+        let mut session = Session::with_config(SessionConfig {
+            cache_sources: false,
+            result_cache: Some(ResultCacheConfig::default()),
+            ..Default::default()
+        });
+        let sql = format!("SELECT sum(value) AS total FROM file://{}", path.display());
+        let total = |ds: DataSet| ds.column("total").unwrap().sum::<i64>();
+        assert_eq!(total(session.query(&sql).await.unwrap()), Some(3));
+        // 只是空白和关键字大小写不同的查询也会命中，clone 出来的 session 共享缓存
+        let same = sql.replace("SELECT sum(value)", "select   sum(value)");
+        assert_eq!(total(session.clone().query(&same).await.unwrap()), Some(3));
+        let (_, stats) = session.query_with_stats(&sql).await.unwrap();
+        assert!(stats.cached);
+        assert_eq!(
+            (session.cache_stats().hits, session.cache_stats().misses),
+            (1, 1)
+        );
+
+        // 数据源变化之后重新执行
+        std::fs::write(&path, "value\n1\n2\n3\n").unwrap();
+        assert_eq!(total(session.query(&sql).await.unwrap()), Some(6));
+        assert_eq!(session.cache_stats().misses, 2);
+
+        // 含有 now() 的查询不缓存
+        let sql = "SELECT now() AS t FROM sample";
+        session.register_dataset("sample", sample());
+        session.query(sql).await.unwrap();
+        session.query(sql).await.unwrap();
+        assert_eq!(session.cache_stats().misses, 2);
+
+        // 普通的 UDF 可以缓存，名字里带 now 也不影响；register_volatile_udf 注册的不缓存
+        let identity = |mut args: Vec<Expr>| {
+            args.pop()
+                .ok_or_else(|| Error::execution("requires an argument"))
+        };
+        session.register_udf("snow", identity);
+        session.register_volatile_udf("jitter", identity);
+        for _ in 0..2 {
+            session
+                .query("SELECT snow(value) v FROM sample")
+                .await
+                .unwrap();
+            session
+                .query("SELECT jitter(value) v FROM sample")
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            (session.cache_stats().hits, session.cache_stats().misses),
+            (2, 3)
+        );
+
+        session.clear_cache();
+        assert_eq!(session.cache_stats().entries, 0);
+    }
+
+    #[tokio::test]
+    async fn query_stats_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.csv");
+        std::fs::write(&path, "value,name\n1,a\n2,b\n3,c\n").unwrap();
+
+        let mut session = Session::new();
+        let sql = format!("SELECT name FROM file://{} WHERE value > 1", path.display());
+        let (ds, stats) = session.query_with_stats(&sql).await.unwrap();
+        assert_eq!(ds.height(), 2);
+        assert_eq!(
+            (stats.rows_loaded, stats.rows, stats.bytes_fetched),
+            (3, 2, 23)
+        );
+        assert!(!stats.cached);
+        assert!(stats.total >= stats.parse + stats.busy());
+
+        // 第二次查询用缓存的数据源，不再读取
+        session.query(&sql).await.unwrap();
+        assert_eq!(session.last_stats().bytes_fetched, 0);
+        assert_eq!(session.last_stats().rows_loaded, 3);
+    }
+
+    #[tokio::test]
+    async fn udf_works() {
+        let mut session = Session::new();
+        session.register_dataset("sample", sample());
+        session.register_udf("double", |mut args: Vec<Expr>| {
+            let arg = args
+                .pop()
+                .ok_or_else(|| Error::execution("double requires an argument"))?;
+            Ok(arg * lit(2))
+        });
+
+        let ds = session
+            .query("SELECT double(value) v FROM sample")
+            .await
+            .unwrap();
+        assert_eq!(ds.column("v").unwrap().sum::<i64>(), Some(12));
+    }
+}
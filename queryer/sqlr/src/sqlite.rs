@@ -0,0 +1,315 @@
+use crate::error::{Error, Result};
+use crate::loader::Load;
+use crate::DataSet;
+use polars::prelude::*;
+#[cfg(feature = "native")]
+use rusqlite::{types::Value as SqliteValue, Connection, OpenFlags};
+use sqlparser::ast::{BinaryOperator, Expr as SqlExpr, Ident, Value as SqlValue};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// SQLite 数据库文件开头的 16 个字节
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// 读取 SQLite 数据库里的一张表，filter 是下推到 SQLite 的 WHERE 条件
+#[derive(Debug)]
+pub struct SqliteLoader {
+    pub(crate) path: PathBuf,
+    pub(crate) table: Option<String>,
+    pub(crate) filter: Option<String>,
+}
+
+/// 数据的开头是不是 SQLite 的文件头
+pub fn is_sqlite(header: &[u8]) -> bool {
+    header.starts_with(SQLITE_HEADER)
+}
+
+/// 读取文件头判断是不是 SQLite 数据库
+pub(crate) fn is_sqlite_file(path: impl AsRef<Path>) -> bool {
+    let mut header = [0u8; 16];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .map(|_| is_sqlite(&header))
+        .unwrap_or(false)
+}
+
+/// 不读取整个数据源就能判断的 SQLite 数据源：本地文件看 header（用 read_header 读到的文件头），
+/// 远程的看扩展名
+pub(crate) fn is_sqlite_source(url: &str, header: Option<&[u8]>) -> bool {
+    match url.strip_prefix("file://") {
+        Some(_) => header.map_or(false, is_sqlite),
+        None => {
+            let path = url.split(|c| c == '?' || c == '#').next().unwrap_or(url);
+            [".db", ".sqlite", ".sqlite3"]
+                .iter()
+                .any(|ext| path.ends_with(ext))
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+impl Load for SqliteLoader {
+    type Error = Error;
+
+    fn load(self) -> Result<DataSet, Self::Error> {
+        if !is_sqlite_file(&self.path) {
+            return Err(Error::load(format!(
+                "{} is not a SQLite database",
+                self.path.display()
+            )));
+        }
+        let conn = Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(Error::load)?;
+        let table = match self.table {
+            Some(table) => table,
+            None => only_table(&conn)?,
+        };
+
+        let mut sql = format!("SELECT * FROM {}", quote(&table));
+        if let Some(filter) = &self.filter {
+            sql.push_str(" WHERE ");
+            sql.push_str(filter);
+        }
+        let mut stmt = conn.prepare(&sql).map_err(Error::load)?;
+        let names: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+
+        let mut columns = vec![Vec::new(); names.len()];
+        let mut rows = stmt.query([]).map_err(Error::load)?;
+        while let Some(row) = rows.next().map_err(Error::load)? {
+            for (i, column) in columns.iter_mut().enumerate() {
+                column.push(row.get::<_, SqliteValue>(i).map_err(Error::load)?);
+            }
+        }
+
+        let columns = names
+            .iter()
+            .zip(columns)
+            .map(|(name, values)| to_series(name, values))
+            .collect();
+        Ok(DataSet(DataFrame::new(columns)?))
+    }
+}
+
+/// wasm 下没有 SQLite
+#[cfg(not(feature = "native"))]
+impl Load for SqliteLoader {
+    type Error = Error;
+
+    fn load(self) -> Result<DataSet, Self::Error> {
+        Err(Error::unsupported(
+            "SQLite is not supported in wasm",
+            self.path.display(),
+        ))
+    }
+}
+
+/// 没有指定表名时，数据库里只能有一张表
+#[cfg(feature = "native")]
+fn only_table(conn: &Connection) -> Result<String> {
+    let mut stmt = conn
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'")
+        .map_err(Error::load)?;
+    let tables = stmt
+        .query_map([], |row| row.get::<_, String>(0))
+        .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+        .map_err(Error::load)?;
+    match tables.as_slice() {
+        [table] => Ok(table.clone()),
+        _ => Err(Error::load(format!(
+            "Please specify one of the tables: {}",
+            tables.join(", ")
+        ))),
+    }
+}
+
+/// SQLite 的列没有固定类型，按实际的值决定：
+/// 全是整数用 Int64，有小数用 Float64，其它情况用 Utf8
+#[cfg(feature = "native")]
+fn to_series(name: &str, values: Vec<SqliteValue>) -> Series {
+    let is_int = values
+        .iter()
+        .all(|v| matches!(v, SqliteValue::Integer(_) | SqliteValue::Null));
+    let is_number = values.iter().all(|v| {
+        matches!(
+            v,
+            SqliteValue::Integer(_) | SqliteValue::Real(_) | SqliteValue::Null
+        )
+    });
+    let has_value = values.iter().any(|v| !matches!(v, SqliteValue::Null));
+
+    if has_value && is_int {
+        let values: Vec<_> = values
+            .into_iter()
+            .map(|v| match v {
+                SqliteValue::Integer(v) => Some(v),
+                _ => None,
+            })
+            .collect();
+        return Series::new(name, values);
+    }
+    if has_value && is_number {
+        let values: Vec<_> = values
+            .into_iter()
+            .map(|v| match v {
+                SqliteValue::Integer(v) => Some(v as f64),
+                SqliteValue::Real(v) => Some(v),
+                _ => None,
+            })
+            .collect();
+        return Series::new(name, values);
+    }
+
+    let values: Vec<_> = values
+        .into_iter()
+        .map(|v| match v {
+            SqliteValue::Null => None,
+            SqliteValue::Integer(v) => Some(v.to_string()),
+            SqliteValue::Real(v) => Some(v.to_string()),
+            SqliteValue::Text(v) => Some(v),
+            SqliteValue::Blob(v) => Some(String::from_utf8_lossy(&v).into_owned()),
+        })
+        .collect();
+    let values: Vec<_> = values.iter().map(|v| v.as_deref()).collect();
+    Series::new(name, values)
+}
+
+/// 把 WHERE 里能交给 SQLite 的部分转换成 SQL：用 AND 连接的 `列 比较 常量`、
+/// `列 IS [NOT] NULL`。不能下推的部分会被忽略，整个条件之后还会在 DataFrame 上再过滤一次
+pub(crate) fn pushdown(expr: &SqlExpr) -> Option<String> {
+    let mut parts = Vec::new();
+    collect_conjuncts(expr, &mut parts);
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(" AND "))
+    }
+}
+
+fn collect_conjuncts(expr: &SqlExpr, parts: &mut Vec<String>) {
+    match expr {
+        SqlExpr::Nested(expr) => collect_conjuncts(expr, parts),
+        SqlExpr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            collect_conjuncts(left, parts);
+            collect_conjuncts(right, parts);
+        }
+        SqlExpr::BinaryOp { left, op, right } => {
+            let op = match op {
+                BinaryOperator::Eq => "=",
+                BinaryOperator::NotEq => "<>",
+                BinaryOperator::Lt => "<",
+                BinaryOperator::LtEq => "<=",
+                BinaryOperator::Gt => ">",
+                BinaryOperator::GtEq => ">=",
+                _ => return,
+            };
+            match (column(left), literal(right), literal(left), column(right)) {
+                (Some(c), Some(v), _, _) => parts.push(format!("{} {} {}", c, op, v)),
+                (_, _, Some(v), Some(c)) => parts.push(format!("{} {} {}", v, op, c)),
+                _ => {}
+            }
+        }
+        SqlExpr::IsNull(expr) => {
+            if let Some(c) = column(expr) {
+                parts.push(format!("{} IS NULL", c));
+            }
+        }
+        SqlExpr::IsNotNull(expr) => {
+            if let Some(c) = column(expr) {
+                parts.push(format!("{} IS NOT NULL", c));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn column(expr: &SqlExpr) -> Option<String> {
+    match expr {
+        SqlExpr::Identifier(Ident { value, .. }) => Some(quote(value)),
+        _ => None,
+    }
+}
+
+fn literal(expr: &SqlExpr) -> Option<String> {
+    match expr {
+        SqlExpr::Value(SqlValue::Number(n, _)) => Some(n.clone()),
+        SqlExpr::Value(SqlValue::SingleQuotedString(s)) => {
+            Some(format!("'{}'", s.replace('\'', "''")))
+        }
+        _ => None,
+    }
+}
+
+/// 按 SQLite 的规则给标识符加上双引号
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+    use sqlparser::parser::Parser;
+
+    fn where_clause(condition: &str) -> SqlExpr {
+        let sql = format!("SELECT * FROM t WHERE {}", condition);
+        let stmt = Parser::parse_sql(&crate::TryDialect, &sql)
+            .unwrap()
+            .remove(0);
+        match stmt {
+            sqlparser::ast::Statement::Query(q) => match q.body {
+                sqlparser::ast::SetExpr::Select(s) => s.selection.unwrap(),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn pushdown_works() {
+        let expr = where_clause("a > 1 AND (b = 'x''y' AND c IS NOT NULL) AND a + 1 < 3");
+        assert_eq!(
+            pushdown(&expr).as_deref(),
+            Some(r#""a" > 1 AND "b" = 'x''y' AND "c" IS NOT NULL"#)
+        );
+        let expr = where_clause("10 <= a");
+        assert_eq!(pushdown(&expr).as_deref(), Some(r#"10 <= "a""#));
+        // OR 不能拆开下推
+        assert_eq!(pushdown(&where_clause("a = 1 OR b = 2")), None);
+    }
+
+    #[test]
+    fn sqlite_loader_works() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ref.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE country (code TEXT, name TEXT, population INTEGER, area REAL);
+             INSERT INTO country VALUES ('CN', 'China', 1400, 9.6), ('FR', 'France', 67, NULL),
+                                        ('IS', 'Iceland', NULL, 0.1);",
+        )
+        .unwrap();
+        drop(conn);
+        assert!(is_sqlite_file(&path));
+
+        let loader = SqliteLoader {
+            path: path.clone(),
+            table: None,
+            filter: Some(r#""population" > 100"#.into()),
+        };
+        let ds = loader.load().unwrap();
+        assert_eq!(ds.height(), 1);
+        assert_eq!(ds.column("population").unwrap().dtype(), &DataType::Int64);
+        assert_eq!(ds.column("area").unwrap().dtype(), &DataType::Float64);
+
+        let loader = SqliteLoader {
+            path,
+            table: Some("missing".into()),
+            filter: None,
+        };
+        assert!(matches!(loader.load(), Err(Error::Load(_))));
+    }
+}
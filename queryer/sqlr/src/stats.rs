@@ -0,0 +1,67 @@
+use crate::error::Result;
+use instant::Instant;
+use std::future::Future;
+use std::time::Duration;
+use tracing::{field, info_span, Instrument};
+
+/// 一次查询各个阶段的用时和数据量，用 [`crate::Session::query_with_stats`] 或者
+/// [`crate::Session::last_stats`] 获取
+///
+/// 查询视图时，视图里的查询的各个阶段也计算在内
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryStats {
+    /// 解析 SQL，执行预先解析好的语句时为 0
+    pub parse: Duration,
+    /// 把 SQL AST 转换成 polars 的表达式
+    pub convert: Duration,
+    /// 下载或者读取数据源
+    pub fetch: Duration,
+    /// 把读到的数据解析成 DataFrame，不包括 fetch 的时间
+    pub load: Duration,
+    /// 过滤、聚合、排序等计算
+    pub execute: Duration,
+    /// 整个查询的用时
+    pub total: Duration,
+    /// 从数据源读取的字节数，JSON API 和边读边处理的数据源不计算在内
+    pub bytes_fetched: u64,
+    /// 从数据源加载的行数
+    pub rows_loaded: usize,
+    /// 结果的行数
+    pub rows: usize,
+    /// 结果是否来自结果缓存
+    pub cached: bool,
+}
+
+impl QueryStats {
+    /// 各个阶段的用时之和，用来从外层阶段的用时里扣除嵌套的阶段
+    pub(crate) fn busy(&self) -> Duration {
+        self.convert + self.fetch + self.load + self.execute
+    }
+}
+
+/// 在 fetch span 里读取数据源，返回读取的结果、用时和字节数
+///
+/// bytes 从读取的结果里算出字节数，http 的状态码由 fetcher 记录在当前的 span 上
+pub(crate) async fn traced_fetch<T, F>(
+    source: &str,
+    fetch: F,
+    bytes: impl FnOnce(&T) -> u64,
+) -> (Result<T>, Duration, u64)
+where
+    F: Future<Output = Result<T>>,
+{
+    let span = info_span!(
+        "fetch",
+        source,
+        bytes = field::Empty,
+        status = field::Empty,
+        duration_ms = field::Empty
+    );
+    let start = Instant::now();
+    let result = fetch.instrument(span.clone()).await;
+    let elapsed = start.elapsed();
+    let n = result.as_ref().map(bytes).unwrap_or_default();
+    span.record("bytes", &n);
+    span.record("duration_ms", &(elapsed.as_millis() as u64));
+    (result, elapsed, n)
+}
@@ -0,0 +1,189 @@
+use crate::error::{Error, Result};
+use crate::fetcher::{Body, Spilled};
+use crate::loader::INFER_SCHEMA_ROWS;
+use crate::DataSet;
+use futures::stream::{self, Stream};
+use polars::prelude::*;
+use std::io::Cursor;
+use std::pin::Pin;
+
+/// 查询结果流，每一项是一批数据
+pub type RecordBatchStream = Pin<Box<dyn Stream<Item = Result<DataSet>> + Send>>;
+
+/// 可以逐块执行的查询：只有过滤、投影和 offset / limit
+pub(crate) struct RowPlan {
+    pub(crate) condition: Option<Expr>,
+    pub(crate) selection: Vec<Expr>,
+    pub(crate) offset: usize,
+    pub(crate) limit: usize,
+}
+
+/// 表达式是否逐行计算，只有这样的投影才能分块执行，聚合需要看到全部数据
+pub(crate) fn is_row_wise(expr: &Expr) -> bool {
+    match expr {
+        Expr::Column(_) | Expr::Literal(_) | Expr::Wildcard => true,
+        Expr::Alias(expr, _) | Expr::Not(expr) | Expr::IsNull(expr) | Expr::IsNotNull(expr) => {
+            is_row_wise(expr)
+        }
+        Expr::BinaryExpr { left, right, .. } => is_row_wise(left) && is_row_wise(right),
+        _ => false,
+    }
+}
+
+/// 把已经算好的结果按 batch_size 切分成流
+pub(crate) fn batches_of(df: DataFrame, batch_size: usize) -> RecordBatchStream {
+    let batch_size = batch_size.max(1);
+    let batches: Vec<_> = (0..df.height())
+        .step_by(batch_size)
+        .map(|offset| Ok(DataSet(df.slice(offset as i64, batch_size))))
+        .collect();
+    Box::pin(stream::iter(batches))
+}
+
+struct CsvChunks {
+    reader: Body,
+    header: String,
+    schema: Option<SchemaRef>,
+    plan: RowPlan,
+    batch_size: usize,
+    // 临时文件要活得和流一样久
+    _spilled: Option<Arc<Spilled>>,
+}
+
+/// 分块读取落地的 csv，每块单独过滤和投影，内存里同时只有一块数据
+pub(crate) async fn csv_batches(
+    spilled: Arc<Spilled>,
+    plan: RowPlan,
+    batch_size: usize,
+) -> Result<RecordBatchStream> {
+    let mut reader = Body::open_file(spilled.path()).await?;
+    let mut header = String::new();
+    read_record(&mut reader, &mut header).await?;
+    Ok(record_batches(
+        reader,
+        header,
+        Some(spilled),
+        plan,
+        batch_size,
+    ))
+}
+
+/// 从已经读过表头的 reader 里分块读取 csv，读够了 limit 行就停止，剩下的数据不会被读取
+pub(crate) fn record_batches(
+    reader: Body,
+    header: String,
+    spilled: Option<Arc<Spilled>>,
+    plan: RowPlan,
+    batch_size: usize,
+) -> RecordBatchStream {
+    let chunks = CsvChunks {
+        reader,
+        header,
+        schema: None,
+        plan,
+        batch_size: batch_size.max(1),
+        _spilled: spilled,
+    };
+    Box::pin(stream::try_unfold(chunks, next_batch))
+}
+
+async fn next_batch(mut chunks: CsvChunks) -> Result<Option<(DataSet, CsvChunks)>> {
+    loop {
+        if chunks.plan.limit == 0 {
+            return Ok(None);
+        }
+
+        let mut buf = chunks.header.clone();
+        let mut rows = 0;
+        while rows < chunks.batch_size && read_record(&mut chunks.reader, &mut buf).await? > 0 {
+            rows += 1;
+        }
+        if rows == 0 {
+            return Ok(None);
+        }
+
+        // 第一块推断 schema，和一次读取整个数据源时看同样多的行，之后的块沿用它，保证每一批的类型一致
+        let reader = CsvReader::new(Cursor::new(buf)).has_header(true);
+        let df = match &chunks.schema {
+            Some(schema) => reader
+                .with_schema(schema.clone())
+                .finish()
+                .map_err(Error::load)?,
+            None => {
+                let df = reader
+                    .infer_schema(Some(INFER_SCHEMA_ROWS))
+                    .finish()
+                    .map_err(Error::load)?;
+                chunks.schema = Some(Arc::new(df.schema()));
+                df
+            }
+        };
+
+        let plan = &mut chunks.plan;
+        let mut filtered = df.lazy();
+        if let Some(expr) = &plan.condition {
+            filtered = filtered.filter(expr.clone());
+        }
+        let mut df = filtered.select(plan.selection.clone()).collect()?;
+
+        if plan.offset > 0 {
+            let skip = plan.offset.min(df.height());
+            df = df.slice(skip as i64, df.height() - skip);
+            plan.offset -= skip;
+        }
+        if df.height() > plan.limit {
+            df = df.head(Some(plan.limit));
+        }
+        plan.limit -= df.height();
+
+        if df.height() > 0 {
+            return Ok(Some((DataSet(df), chunks)));
+        }
+    }
+}
+
+/// 读取一条完整的 csv 记录追加到 buf，返回读取的字节数，引号里的换行不会把记录截断
+pub(crate) async fn read_record(reader: &mut Body, buf: &mut String) -> Result<usize> {
+    let mut total = 0;
+    let mut quotes = 0;
+    loop {
+        let start = buf.len();
+        let n = reader.read_line(buf).await?;
+        total += n;
+        quotes += buf[start..].matches('"').count();
+        if n == 0 || quotes % 2 == 0 {
+            return Ok(total);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_record_keeps_quoted_newlines() {
+        let data = "a,b\n1,\"x\ny\"\n2,z\n";
+        let mut reader = Body::Reader(Box::new(tokio::io::BufReader::new(data.as_bytes())));
+        let mut buf = String::new();
+
+        read_record(&mut reader, &mut buf).await.unwrap();
+        assert_eq!(buf, "a,b\n");
+
+        buf.clear();
+        read_record(&mut reader, &mut buf).await.unwrap();
+        assert_eq!(buf, "1,\"x\ny\"\n");
+
+        buf.clear();
+        read_record(&mut reader, &mut buf).await.unwrap();
+        assert_eq!(buf, "2,z\n");
+        assert_eq!(read_record(&mut reader, &mut buf).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn is_row_wise_works() {
+        assert!(is_row_wise(&col("a")));
+        assert!(is_row_wise(&(col("a") + lit(1)).alias("b")));
+        assert!(!is_row_wise(&col("a").sum()));
+    }
+}
@@ -0,0 +1,495 @@
+use crate::error::Error;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike, Utc};
+use polars::prelude::*;
+use std::str::FromStr;
+
+const SECOND: i64 = 1000;
+const MINUTE: i64 = 60 * SECOND;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
+
+/// 按周分桶时以 2000-01-03（星期一）为起点，这样每个桶都从星期一开始
+const ORIGIN: i64 = 946_857_600_000;
+
+/// time_bucket_gapfill 最多生成这么多个桶，避免间隔太小时耗尽内存
+const MAX_GAP_FILL_BUCKETS: usize = 100_000;
+
+/// 需要给出默认列名的日期函数，否则输出的列名和参数列同名，容易冲突
+pub(crate) const FUNCTIONS: [&str; 7] = [
+    "now",
+    "to_timestamp",
+    "date_trunc",
+    "date_add",
+    "date_diff",
+    "time_bucket",
+    "time_bucket_gapfill",
+];
+
+/// 时间单位，用于 date_trunc、date_diff 和 extract
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Year,
+    Quarter,
+    Month,
+    Week,
+    Day,
+    Hour,
+    Minute,
+    Second,
+}
+
+/// 时间间隔，比如 `'1 week'`、`'3 months'`，月份和毫秒分开记录，因为每个月的长度不一样
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub months: i64,
+    pub millis: i64,
+}
+
+impl Unit {
+    /// 固定长度的单位对应的毫秒数，年、季度和月没有固定长度
+    fn millis(self) -> Option<i64> {
+        match self {
+            Unit::Week => Some(WEEK),
+            Unit::Day => Some(DAY),
+            Unit::Hour => Some(HOUR),
+            Unit::Minute => Some(MINUTE),
+            Unit::Second => Some(SECOND),
+            _ => None,
+        }
+    }
+
+    fn months(self) -> i64 {
+        match self {
+            Unit::Year => 12,
+            Unit::Quarter => 3,
+            _ => 1,
+        }
+    }
+}
+
+impl FromStr for Unit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unit = s.trim().to_lowercase();
+        match unit.trim_end_matches('s') {
+            "year" => Ok(Unit::Year),
+            "quarter" => Ok(Unit::Quarter),
+            "month" => Ok(Unit::Month),
+            "week" => Ok(Unit::Week),
+            "day" => Ok(Unit::Day),
+            "hour" => Ok(Unit::Hour),
+            "minute" => Ok(Unit::Minute),
+            "second" => Ok(Unit::Second),
+            _ => Err(Error::unsupported(
+                format!("Time unit {} is not supported", s),
+                s,
+            )),
+        }
+    }
+}
+
+impl FromStr for Interval {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::unsupported(format!("Invalid interval {}", s), s);
+        let mut parts = s.split_whitespace();
+        let (n, unit) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(n), Some(unit), None) => (n, unit),
+            (Some(unit), None, None) => ("1", unit),
+            _ => return Err(invalid()),
+        };
+        let n: i64 = n.parse().map_err(|_| invalid())?;
+        let unit: Unit = unit.parse()?;
+
+        Ok(match unit.millis() {
+            Some(ms) => Interval {
+                months: 0,
+                millis: n.checked_mul(ms).ok_or_else(invalid)?,
+            },
+            None => Interval {
+                months: n.checked_mul(unit.months()).ok_or_else(invalid)?,
+                millis: 0,
+            },
+        })
+    }
+}
+
+impl Interval {
+    fn is_empty(&self) -> bool {
+        self.months == 0 && self.millis == 0
+    }
+
+    /// 把时间戳加上这个间隔，月末的日期会被截断到目标月份的最后一天。
+    /// 超出能表示的范围时返回 None
+    pub(crate) fn add_to(&self, ms: i64) -> Option<i64> {
+        let ms = if self.months == 0 {
+            ms
+        } else {
+            let t = to_naive(ms)?;
+            let (year, month) = year_month(months_of(&t).checked_add(self.months)?)?;
+            let day = t.day().min(days_in_month(year, month)?);
+            NaiveDate::from_ymd_opt(year, month, day)?
+                .and_time(t.time())
+                .timestamp_millis()
+        };
+        ms.checked_add(self.millis)
+    }
+
+    /// 时间戳所在的桶的起点
+    pub(crate) fn bucket(&self, ms: i64) -> Option<i64> {
+        if self.months > 0 {
+            let months = months_of(&to_naive(ms)?);
+            month_start(months - months.rem_euclid(self.months))
+        } else {
+            let n = ms.checked_sub(ORIGIN)?.div_euclid(self.millis);
+            n.checked_mul(self.millis)?.checked_add(ORIGIN)
+        }
+    }
+}
+
+fn to_naive(ms: i64) -> Option<NaiveDateTime> {
+    NaiveDateTime::from_timestamp_opt(
+        ms.div_euclid(SECOND),
+        (ms.rem_euclid(SECOND) * 1_000_000) as u32,
+    )
+}
+
+/// 从公元 0 年开始数的月份
+fn months_of(t: &NaiveDateTime) -> i64 {
+    t.year() as i64 * 12 + t.month0() as i64
+}
+
+fn year_month(months: i64) -> Option<(i32, u32)> {
+    let year = i32::try_from(months.div_euclid(12)).ok()?;
+    Some((year, months.rem_euclid(12) as u32 + 1))
+}
+
+/// 从公元 0 年开始数的第 months 个月的第一天
+fn month_start(months: i64) -> Option<i64> {
+    let (year, month) = year_month(months)?;
+    Some(
+        NaiveDate::from_ymd_opt(year, month, 1)?
+            .and_hms(0, 0, 0)
+            .timestamp_millis(),
+    )
+}
+
+fn days_in_month(year: i32, month: u32) -> Option<u32> {
+    let next = if month == 12 {
+        NaiveDate::from_ymd_opt(year.checked_add(1)?, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1)
+    };
+    Some(next?.pred_opt()?.day())
+}
+
+fn truncate(ms: i64, unit: Unit) -> Option<i64> {
+    match unit {
+        Unit::Week => Interval {
+            months: 0,
+            millis: WEEK,
+        }
+        .bucket(ms),
+        Unit::Year | Unit::Quarter | Unit::Month => Interval {
+            months: unit.months(),
+            millis: 0,
+        }
+        .bucket(ms),
+        unit => {
+            let step = unit.millis().unwrap_or(DAY);
+            ms.checked_sub(ms.rem_euclid(step))
+        }
+    }
+}
+
+fn diff(start: i64, end: i64, unit: Unit) -> Option<i64> {
+    match unit.millis() {
+        Some(step) => Some(end.checked_sub(start)? / step),
+        None => {
+            let months = |ms| to_naive(ms).map(|t| months_of(&t));
+            Some((months(end)? - months(start)?) / unit.months())
+        }
+    }
+}
+
+fn field(ms: i64, unit: Unit) -> Option<i64> {
+    let t = to_naive(ms)?;
+    Some(match unit {
+        Unit::Year => t.year() as i64,
+        Unit::Quarter => t.month0() as i64 / 3 + 1,
+        Unit::Month => t.month() as i64,
+        Unit::Week => t.iso_week().week() as i64,
+        Unit::Day => t.day() as i64,
+        Unit::Hour => t.hour() as i64,
+        Unit::Minute => t.minute() as i64,
+        Unit::Second => t.second() as i64,
+    })
+}
+
+/// 把字符串、日期或者毫秒数转换成时间戳，字符串的格式会自动识别
+pub(crate) fn to_datetime(s: &Series) -> polars::prelude::Result<Date64Chunked> {
+    match s.dtype() {
+        DataType::Date64 => Ok(s.date64()?.clone()),
+        DataType::Utf8 => {
+            let mut ca: Date64Chunked = s.utf8()?.into_iter().map(|v| v.and_then(parse)).collect();
+            ca.rename(s.name());
+            Ok(ca)
+        }
+        DataType::Date32 | DataType::Int64 => Ok(s.cast::<Date64Type>()?.date64()?.clone()),
+        dt => Err(PolarsError::ComputeError(
+            format!("Cannot convert {:?} to timestamp", dt).into(),
+        )),
+    }
+}
+
+/// 解析常见格式的日期和时间，解析不了的是 null
+fn parse(s: &str) -> Option<i64> {
+    const DATETIME: [&str; 4] = [
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M",
+        "%Y/%m/%d %H:%M:%S",
+    ];
+    const DATE: [&str; 3] = ["%Y-%m-%d", "%Y/%m/%d", "%Y%m%d"];
+
+    let s = s.trim();
+    if let Ok(t) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Some(t.timestamp_millis());
+    }
+    DATETIME
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+        .or_else(|| {
+            DATE.iter()
+                .find_map(|fmt| NaiveDate::parse_from_str(s, fmt).ok())
+                .map(|d| d.and_hms(0, 0, 0))
+        })
+        .map(|t| t.timestamp_millis())
+}
+
+/// 对每个时间戳调用 f，f 返回 None（比如结果超出了能表示的范围）时是 null
+fn map_datetime<F>(expr: Expr, f: F) -> Expr
+where
+    F: Fn(i64) -> Option<i64> + Send + Sync + 'static,
+{
+    expr.map(
+        move |s: Series| {
+            let ca = to_datetime(&s)?;
+            let mut out: Date64Chunked = ca.into_iter().map(|v| v.and_then(&f)).collect();
+            out.rename(ca.name());
+            Ok(out.into_series())
+        },
+        Some(DataType::Date64),
+    )
+}
+
+/// 当前时间
+pub(crate) fn now() -> Expr {
+    lit(Utc::now().timestamp_millis()).cast(DataType::Date64)
+}
+
+/// 把字符串列解析成时间戳
+pub(crate) fn to_timestamp(expr: Expr) -> Expr {
+    map_datetime(expr, Some)
+}
+
+pub(crate) fn date_trunc(unit: Unit, expr: Expr) -> Expr {
+    map_datetime(expr, move |ms| truncate(ms, unit))
+}
+
+pub(crate) fn date_add(expr: Expr, interval: Interval) -> Expr {
+    map_datetime(expr, move |ms| interval.add_to(ms))
+}
+
+/// 按固定的间隔分桶，比如 `time_bucket('1 week', ts)` 返回每个时间所在那一周的星期一
+pub(crate) fn time_bucket(interval: Interval, expr: Expr) -> Result<Expr, Error> {
+    if interval.is_empty() || interval.months < 0 || interval.millis < 0 {
+        return Err(Error::unsupported(
+            "Interval of time_bucket must be positive",
+            format!("{:?}", interval),
+        ));
+    }
+    if interval.months != 0 && interval.millis != 0 {
+        return Err(Error::unsupported(
+            "Interval of time_bucket can not mix months with days",
+            format!("{:?}", interval),
+        ));
+    }
+    Ok(map_datetime(expr, move |ms| interval.bucket(ms)).alias("time_bucket"))
+}
+
+/// `extract(year from ts)`
+pub(crate) fn extract(unit: Unit, expr: Expr) -> Expr {
+    expr.map(
+        move |s: Series| {
+            let ca = to_datetime(&s)?;
+            let out: Int64Chunked = ca
+                .into_iter()
+                .map(|v| v.and_then(|ms| field(ms, unit)))
+                .collect();
+            let mut s = out.into_series();
+            s.rename(ca.name());
+            Ok(s)
+        },
+        Some(DataType::Int64),
+    )
+}
+
+/// `date_diff('day', start, end)`，按单位计算 end - start
+pub(crate) fn date_diff(unit: Unit, start: Expr, end: Expr) -> Expr {
+    map_binary(
+        start,
+        end,
+        move |a: Series, b: Series| {
+            let (a, b) = (to_datetime(&a)?, to_datetime(&b)?);
+            // 其中一边可能是 now() 这样的常量，需要广播
+            let len = a.len().max(b.len());
+            let get = |ca: &Date64Chunked, i: usize| ca.get(if ca.len() == 1 { 0 } else { i });
+            let out: Int64Chunked = (0..len)
+                .map(|i| match (get(&a, i), get(&b, i)) {
+                    (Some(a), Some(b)) => diff(a, b, unit),
+                    _ => None,
+                })
+                .collect();
+            let mut s = out.into_series();
+            s.rename(a.name());
+            Ok(s)
+        },
+        None,
+    )
+}
+
+/// 在分桶聚合的结果里补齐缺失的桶，缺失的桶里其它列是 null
+pub(crate) fn gap_fill(
+    df: DataFrame,
+    column: &str,
+    interval: Interval,
+) -> polars::prelude::Result<DataFrame> {
+    let buckets = to_datetime(df.column(column)?)?;
+    let (min, max) = match (buckets.min(), buckets.max()) {
+        (Some(min), Some(max)) => (min, max),
+        _ => return Ok(df),
+    };
+
+    let mut values = Vec::new();
+    let mut ms = Some(min);
+    while let Some(t) = ms.filter(|t| *t <= max) {
+        if values.len() >= MAX_GAP_FILL_BUCKETS {
+            return Err(PolarsError::ComputeError(
+                format!(
+                    "time_bucket_gapfill would generate more than {} buckets",
+                    MAX_GAP_FILL_BUCKETS
+                )
+                .into(),
+            ));
+        }
+        values.push(t);
+        ms = interval.add_to(t).and_then(|t| interval.bucket(t));
+    }
+    let all = Date64Chunked::new_from_slice(column, &values).into_series();
+    let filled = DataFrame::new(vec![all])?.left_join(&df, column, column)?;
+    filled.sort(column, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(s: &str) -> i64 {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+            .unwrap()
+            .timestamp_millis()
+    }
+
+    #[test]
+    fn parse_works() {
+        assert_eq!(parse("2021-08-19"), Some(ms("2021-08-19 00:00:00")));
+        assert_eq!(
+            parse("2021-08-19 13:45:10"),
+            Some(ms("2021-08-19 13:45:10"))
+        );
+        assert_eq!(
+            parse("2021-08-19T13:45:10+01:00"),
+            Some(ms("2021-08-19 12:45:10"))
+        );
+        assert_eq!(parse("yesterday"), None);
+    }
+
+    #[test]
+    fn interval_parse_works() {
+        assert_eq!(
+            "1 week".parse::<Interval>().unwrap(),
+            Interval {
+                months: 0,
+                millis: WEEK
+            }
+        );
+        assert_eq!(
+            "2 Quarters".parse::<Interval>().unwrap(),
+            Interval {
+                months: 6,
+                millis: 0
+            }
+        );
+        assert!("one week".parse::<Interval>().is_err());
+        assert!("1 fortnight".parse::<Interval>().is_err());
+    }
+
+    #[test]
+    fn truncate_and_bucket_works() {
+        let t = ms("2021-08-19 13:45:10");
+        assert_eq!(truncate(t, Unit::Month), Some(ms("2021-08-01 00:00:00")));
+        assert_eq!(truncate(t, Unit::Quarter), Some(ms("2021-07-01 00:00:00")));
+        assert_eq!(truncate(t, Unit::Hour), Some(ms("2021-08-19 13:00:00")));
+        // 2021-08-19 是星期四，所在的周从 2021-08-16 星期一开始
+        assert_eq!(truncate(t, Unit::Week), Some(ms("2021-08-16 00:00:00")));
+
+        let interval: Interval = "2 months".parse().unwrap();
+        assert_eq!(interval.bucket(t), Some(ms("2021-07-01 00:00:00")));
+    }
+
+    #[test]
+    fn add_and_diff_works() {
+        let month: Interval = "1 month".parse().unwrap();
+        assert_eq!(
+            month.add_to(ms("2021-01-31 08:00:00")),
+            Some(ms("2021-02-28 08:00:00"))
+        );
+        let back: Interval = "-3 days".parse().unwrap();
+        assert_eq!(
+            back.add_to(ms("2021-03-01 00:00:00")),
+            Some(ms("2021-02-26 00:00:00"))
+        );
+
+        let (a, b) = (ms("2021-01-31 00:00:00"), ms("2021-03-01 12:00:00"));
+        assert_eq!(diff(a, b, Unit::Day), Some(29));
+        assert_eq!(diff(a, b, Unit::Month), Some(2));
+        assert_eq!(field(b, Unit::Quarter), Some(1));
+        assert_eq!(field(b, Unit::Hour), Some(12));
+    }
+
+    #[test]
+    fn out_of_range_is_none() {
+        // 纳秒时间戳当作毫秒，超出了 chrono 能表示的范围
+        let ns = 1_629_380_710_000_000_000;
+        assert_eq!(field(ns, Unit::Year), None);
+        assert_eq!(truncate(ns, Unit::Month), None);
+        assert_eq!(truncate(i64::MIN, Unit::Hour), None);
+        assert_eq!(truncate(i64::MIN, Unit::Week), None);
+
+        let years: Interval = "300000 years".parse().unwrap();
+        assert_eq!(years.add_to(ms("2021-01-31 08:00:00")), None);
+        assert_eq!(diff(i64::MIN, i64::MAX, Unit::Day), None);
+        assert!(format!("{} weeks", i64::MAX).parse::<Interval>().is_err());
+    }
+
+    #[test]
+    fn gap_fill_limits_buckets() {
+        let df = df!("t" => &[0i64, 1_000_000_000_000]).unwrap();
+        let second: Interval = "1 second".parse().unwrap();
+        assert!(gap_fill(df, "t", second).is_err());
+    }
+}
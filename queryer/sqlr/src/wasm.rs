@@ -0,0 +1,100 @@
+//! 给 JavaScript 用的接口，编译命令见 README.md
+//!
+//! wasm 里不能读取文件和下载数据，数据由 JavaScript 读取之后注册成表
+
+use crate::json::to_dataframe;
+use crate::loader::detect_content;
+use crate::{DataSet, Format, Session};
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+/// 一个内存里的数据库，对应一个 [`Session`]
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Database {
+    session: Session,
+}
+
+#[wasm_bindgen]
+impl Database {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 把 csv 内容注册成表，data 是 UTF-8 编码的 csv，比如 `fetch` 得到的 ArrayBuffer
+    #[wasm_bindgen(js_name = registerCsv)]
+    pub fn register_csv(&mut self, name: &str, data: &[u8]) -> Result<(), JsValue> {
+        let data = String::from_utf8(data.to_vec()).map_err(to_js_err)?;
+        let ds = detect_content(data, None).load().map_err(to_js_err)?;
+        self.session.register_dataset(name, ds);
+        Ok(())
+    }
+
+    /// 把 JSON 注册成表：数组里的每个元素是一行，嵌套的对象展开成 `a.b` 这样的列
+    #[wasm_bindgen(js_name = registerJson)]
+    pub fn register_json(&mut self, name: &str, data: &[u8]) -> Result<(), JsValue> {
+        let records = match serde_json::from_slice(data).map_err(to_js_err)? {
+            Value::Array(records) => records,
+            record => vec![record],
+        };
+        let df = to_dataframe(records, &[]).map_err(to_js_err)?;
+        self.session.register_dataset(name, DataSet(df));
+        Ok(())
+    }
+
+    /// 执行一条 SQL，结果是 JSON 数组，每个元素是一行；出错时抛出带有出错位置的字符串
+    ///
+    /// wasm-bindgen 导出的 async 函数不能借用 self，好在注册的数据都在内存里，
+    /// 查询不会真正等待，直接在当前线程上执行完
+    pub fn query(&mut self, sql: &str) -> Result<String, JsValue> {
+        let ds = futures::executor::block_on(self.session.query(sql))
+            .map_err(|e| JsValue::from_str(&e.render(sql)))?;
+        let data = ds.to_bytes(Format::Json).map_err(to_js_err)?;
+        String::from_utf8(data).map_err(to_js_err)
+    }
+
+    /// 注册的表名
+    #[wasm_bindgen(js_name = tableNames)]
+    pub fn table_names(&self) -> Vec<JsValue> {
+        self.session
+            .table_names()
+            .into_iter()
+            .map(JsValue::from)
+            .collect()
+    }
+}
+
+fn to_js_err(e: impl ToString) -> JsValue {
+    JsValue::from_str(&e.to_string())
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    #[wasm_bindgen_test]
+    fn database_works() {
+        let mut db = Database::new();
+        db.register_csv("fruits", b"id,name\n1,apple\n2,banana\n3,cherry\n")
+            .unwrap();
+        db.register_json("prices", br#"[{"id": 2, "price": {"usd": 3.5}}]"#)
+            .unwrap();
+
+        let result = db
+            .query("SELECT name FROM fruits WHERE id > 1 ORDER BY id")
+            .unwrap();
+        assert!(result.contains("banana") && result.contains("cherry"));
+        assert!(!result.contains("apple"));
+
+        let result = db
+            .query("SELECT id FROM prices WHERE \"price.usd\" > 3")
+            .unwrap();
+        assert!(result.contains('2'));
+
+        // 本地文件在 wasm 里读不到
+        let err = db.query("SELECT * FROM file:///tmp/data.csv").unwrap_err();
+        assert!(err.as_string().unwrap().starts_with("error: "));
+    }
+}
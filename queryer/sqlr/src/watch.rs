@@ -0,0 +1,216 @@
+use crate::error::Result;
+use crate::fetcher::validator;
+use crate::params::{Params, Prepared};
+use crate::session::Session;
+use crate::DataSet;
+use polars::prelude::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// 监视查询用到的数据源，数据源变化时重新执行查询，用 [`Session::watch`] 创建
+///
+/// 本地文件按大小和修改时间判断是否变化，http 数据源按 ETag 或者 Last-Modified 判断；
+/// 拿不到校验值的数据源每个周期都重新执行，只有结果变化时才返回
+pub struct Watch {
+    session: Session,
+    prepared: Prepared,
+    interval: Duration,
+    urls: Vec<String>,
+    validators: Vec<String>,
+    last: Option<DataFrame>,
+}
+
+/// 一次执行的结果
+#[derive(Debug)]
+pub struct Update {
+    pub result: DataSet,
+    /// 和上一次结果相比的变化，第一次执行时为 None
+    pub diff: Option<Diff>,
+}
+
+/// 两次结果之间的变化，行的顺序不影响比较，重复的行按出现的次数比较
+#[derive(Debug)]
+pub struct Diff {
+    /// 新结果里多出来的行
+    pub added: DataSet,
+    /// 旧结果里不再出现的行
+    pub removed: DataSet,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.height() == 0 && self.removed.height() == 0
+    }
+
+    /// 比较两次结果，列不同时整个旧结果都算作删除，整个新结果都算作新增
+    pub fn between(old: &DataFrame, new: &DataFrame) -> Result<Self> {
+        if old.get_column_names() != new.get_column_names() {
+            return Ok(Self {
+                added: DataSet(new.clone()),
+                removed: DataSet(old.clone()),
+            });
+        }
+
+        let mut counts: HashMap<String, i64> = HashMap::new();
+        for key in row_keys(old) {
+            *counts.entry(key).or_default() += 1;
+        }
+        let added: Vec<bool> = row_keys(new)
+            .map(|key| match counts.get_mut(&key) {
+                Some(n) if *n > 0 => {
+                    *n -= 1;
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        // 剩下的计数就是旧结果里没有被匹配的行
+        let removed: Vec<bool> = row_keys(old)
+            .map(|key| match counts.get_mut(&key) {
+                Some(n) if *n > 0 => {
+                    *n -= 1;
+                    true
+                }
+                _ => false,
+            })
+            .collect();
+
+        let mask = |mask: Vec<bool>| mask.into_iter().map(Some).collect::<BooleanChunked>();
+        Ok(Self {
+            added: DataSet(new.filter(&mask(added))?),
+            removed: DataSet(old.filter(&mask(removed))?),
+        })
+    }
+}
+
+/// 每一行的值拼成的字符串，用来比较两行是否相同
+fn row_keys(df: &DataFrame) -> impl Iterator<Item = String> + '_ {
+    let columns = df.get_columns();
+    (0..df.height()).map(move |i| {
+        let values: Vec<_> = columns.iter().map(|s| format!("{}", s.get(i))).collect();
+        values.join("\u{1f}")
+    })
+}
+
+impl Watch {
+    pub(crate) fn new(
+        session: Session,
+        prepared: Prepared,
+        interval: Duration,
+        urls: Vec<String>,
+    ) -> Self {
+        Self {
+            session,
+            prepared,
+            interval,
+            urls,
+            validators: Vec::new(),
+            last: None,
+        }
+    }
+
+    /// 监视的数据源
+    pub fn sources(&self) -> &[String] {
+        &self.urls
+    }
+
+    /// 第一次调用时立即执行查询，之后等到数据源变化并且结果也变化时才返回
+    ///
+    /// 执行失败（比如文件只写了一半）时返回错误，可以继续调用 next 等待下一次变化
+    pub async fn next(&mut self) -> Result<Update> {
+        // 出错时保留上一次的结果，下一次仍然和它比较
+        let last = match &self.last {
+            Some(last) => last.clone(),
+            None => {
+                self.validators = self.validators().await;
+                let df = self.execute().await?;
+                self.last = Some(df.clone());
+                return Ok(Update {
+                    result: DataSet(df),
+                    diff: None,
+                });
+            }
+        };
+
+        loop {
+            tokio::time::sleep(self.interval).await;
+            let validators = self.validators().await;
+            let unknown = validators.iter().any(|v| v.is_empty());
+            if validators == self.validators && !unknown {
+                continue;
+            }
+            // 先记下校验值再执行，执行期间发生的变化下一次还能发现
+            self.validators = validators;
+            self.session.forget(&self.urls);
+
+            let df = self.execute().await?;
+            let diff = Diff::between(&last, &df)?;
+            if diff.is_empty() {
+                continue;
+            }
+            self.last = Some(df.clone());
+            return Ok(Update {
+                result: DataSet(df),
+                diff: Some(diff),
+            });
+        }
+    }
+
+    async fn execute(&mut self) -> Result<DataFrame> {
+        let ds = self
+            .session
+            .execute_prepared(&self.prepared, &Params::new())
+            .await?;
+        Ok(ds.0)
+    }
+
+    async fn validators(&self) -> Vec<String> {
+        let timeout = self.session.config().query_options.timeout;
+        let mut validators = Vec::with_capacity(self.urls.len());
+        for url in &self.urls {
+            validators.push(validator(url, timeout).await);
+        }
+        validators
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_works() {
+        let old = df!("id" => &[1, 2, 2, 3], "v" => &["a", "b", "b", "c"]).unwrap();
+        let new = df!("id" => &[3, 2, 4], "v" => &["c", "b", "d"]).unwrap();
+        let diff = Diff::between(&old, &new).unwrap();
+        assert!(diff
+            .added
+            .frame_equal(&df!("id" => &[4], "v" => &["d"]).unwrap()));
+        // 重复的行只匹配一次
+        assert!(diff
+            .removed
+            .frame_equal(&df!("id" => &[1, 2], "v" => &["a", "b"]).unwrap()));
+        assert!(Diff::between(&new, &new).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn watch_reruns_when_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.csv");
+        std::fs::write(&path, "id,v\n1,a\n2,b\n").unwrap();
+
+        let session = Session::new();
+        let sql = format!("SELECT id, v FROM file://{}", path.display());
+        let mut watch = session.watch(&sql, Duration::from_millis(10)).unwrap();
+        assert_eq!(watch.sources(), [format!("file://{}", path.display())]);
+        let update = watch.next().await.unwrap();
+        assert_eq!(update.result.height(), 2);
+        assert!(update.diff.is_none());
+
+        std::fs::write(&path, "id,v\n1,a\n3,c\n4,d\n").unwrap();
+        let update = watch.next().await.unwrap();
+        let diff = update.diff.unwrap();
+        assert_eq!(update.result.height(), 3);
+        assert_eq!((diff.added.height(), diff.removed.height()), (2, 1));
+    }
+}
@@ -0,0 +1,68 @@
+use crate::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// DataSet 可以输出的格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Json,
+    NdJson,
+    Parquet,
+    /// Arrow IPC 文件
+    Arrow,
+}
+
+impl Format {
+    /// 根据文件扩展名猜测输出格式
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?;
+        ext.parse().ok()
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Format::Csv => "csv",
+            Format::Json => "json",
+            Format::NdJson => "ndjson",
+            Format::Parquet => "parquet",
+            Format::Arrow => "arrow",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "ndjson" | "jsonl" => Ok(Self::NdJson),
+            "parquet" => Ok(Self::Parquet),
+            "arrow" | "ipc" | "feather" => Ok(Self::Arrow),
+            v => Err(Error::unsupported(
+                format!("Format {} is not supported", v),
+                v,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_from_path_works() {
+        assert_eq!(Format::from_path("out.parquet"), Some(Format::Parquet));
+        assert_eq!(Format::from_path("/tmp/out.JSONL"), Some(Format::NdJson));
+        assert_eq!(Format::from_path("out.csv"), Some(Format::Csv));
+        assert_eq!(Format::from_path("out.arrow"), Some(Format::Arrow));
+        assert_eq!(Format::from_path("out"), None);
+    }
+}